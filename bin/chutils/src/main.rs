@@ -55,7 +55,51 @@ async fn run() -> eyre::Result<()> {
 #[tokio::main]
 async fn main() {
     if let Err(err) = run().await {
-        tracing::error!(error=?err, "Execute failed");
-        std::process::exit(1);
+        if let Some(check) = err.downcast_ref::<migration::StatusCheckFailed>() {
+            tracing::error!(error=?err, "Execute failed");
+            std::process::exit(check.code);
+        }
+        let migration_err = err
+            .chain()
+            .find_map(|e| e.downcast_ref::<::migration::Error>());
+        match migration_err {
+            Some(e) => {
+                tracing::error!(error=?err, code = e.code(), "Execute failed");
+                std::process::exit(migration::exit_code(e));
+            }
+            None => {
+                tracing::error!(error=?err, "Execute failed");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        CLI::command().debug_assert();
+    }
+
+    #[test]
+    fn test_migrate_short_flags() {
+        let commands: [&[&str]; 4] = [
+            &["up", "-d"],
+            &["down", "-d"],
+            &["renumber", "-d"],
+            &["import-history", "--from=goose", "-d"],
+        ];
+        for args in commands {
+            let cli = CLI::try_parse_from(["chutils", "migrate", "-D", "app"].iter().chain(args))
+                .unwrap();
+            let Command::Migrate(cmd) = cli.command else {
+                panic!("expected migrate");
+            };
+            assert_eq!(cmd.database.as_deref(), Some("app"));
+        }
     }
 }
@@ -3,15 +3,17 @@ use migration::Migration;
 
 #[derive(clap::Parser)]
 pub struct Command {
-    /// ClickHouse server URL (e.g., http://localhost:8123)
+    /// ClickHouse server URL (e.g., http://localhost:8123), `up` and `info` accept several
+    /// (repeated or comma-separated)
     #[clap(
         long = "clickhouse-url",
         short = 'c',
         env = "CLICKHOUSE_URL",
         default_value = "",
-        global = true
+        global = true,
+        value_delimiter = ','
     )]
-    pub url: String,
+    pub urls: Vec<String>,
 
     /// ClickHouse username for authentication
     #[clap(
@@ -34,7 +36,7 @@ pub struct Command {
     /// ClickHouse database name to use
     #[clap(
         long = "clickhouse-db",
-        short = 'd',
+        short = 'D',
         env = "CLICKHOUSE_DB",
         global = true
     )]
@@ -53,6 +55,38 @@ pub struct Command {
     )]
     pub source: String,
 
+    /// Create --clickhouse-db (or each literal --tenants database) if it doesn't exist
+    #[clap(long, global = true)]
+    pub create_database: bool,
+
+    /// Engine of databases made by --create-database
+    #[clap(long, value_enum, default_value_t = migration::DatabaseEngine::Atomic, global = true)]
+    pub database_engine: migration::DatabaseEngine,
+
+    /// Create databases made by --create-database ON CLUSTER
+    #[clap(long, global = true)]
+    pub database_cluster: Option<String>,
+
+    /// Run `up`/`info` against each of these databases instead of --clickhouse-db
+    /// (comma-separated, `*` and `?` patterns are matched against the server's databases)
+    #[clap(long, env = "MIGRATION_TENANTS", value_delimiter = ',', global = true)]
+    pub tenants: Vec<String>,
+
+    /// Shell command to run around `up`/`down` (`<hook>=<command>`, hook is one of
+    /// before_all, after_all, before_each, after_each), can be repeated
+    #[clap(long = "hook", value_parser = parse_hook, global = true)]
+    pub hooks: Vec<(migration::HookPoint, String)>,
+
+    /// Keep the migration history in this local JSON file instead of the
+    /// `_ch_migrations` table (e.g. for read-only replicas)
+    #[clap(long, env = "MIGRATION_HISTORY_FILE", global = true)]
+    pub history_file: Option<String>,
+
+    /// Run each migration and its history row in a server transaction when the server
+    /// supports it (experimental, needs allow_experimental_transactions)
+    #[clap(long, global = true)]
+    pub transactional: bool,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -70,23 +104,58 @@ enum Commands {
         #[clap(long, short = 's')]
         simple: bool,
     },
+    /// Fill the down file of a reversible migration with the inverse of its up file
+    GenDown {
+        /// Migration version to generate the down file for
+        version: u32,
+        /// Overwrite a down file that already has statements
+        #[clap(long, short = 'f')]
+        force: bool,
+    },
     /// Display migration status information
     Info {
         /// Skip validation of missing local migration files
         #[clap(long, short = 'I')]
         ignore_missing: bool,
+        /// Also show duration, user, host and chutils version of applied migrations
+        #[clap(long, short = 'v')]
+        verbose: bool,
+        /// Output format, json and yaml follow the versioned report schema
+        #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
+    /// Compare the database with the local migrations, `--check` exits non-zero unless it
+    /// is at the latest one
+    Status {
+        /// Exit with 20 (pending), 21 (database ahead) or 22 (history corrupted) unless
+        /// the database is up to date
+        #[clap(long)]
+        check: bool,
     },
     /// Apply pending migrations
     Up {
         /// Preview migrations without applying them
         #[clap(long, short = 'd')]
         dry_run: bool,
+        /// Execute the pending migrations against a temporary copy of the database's
+        /// structure instead of applying them
+        #[clap(long, conflicts_with = "dry_run")]
+        shadow: bool,
         /// Skip validation of missing local migration files
         #[clap(long, short = 'I')]
         ignore_missing: bool,
         /// Migrate up to a specific version (inclusive)
         #[clap(long, short = 't')]
         target_version: Option<u32>,
+        /// Number of targets (servers or tenant databases) migrated concurrently
+        #[clap(long, short = 'j', default_value_t = 1)]
+        parallelism: usize,
+        /// What to do with the remaining targets when one fails
+        #[clap(long, value_enum, default_value_t = migration::FailurePolicy::Continue)]
+        on_failure: migration::FailurePolicy,
+        /// Output format, json and yaml follow the versioned report schema
+        #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
     },
     /// Revert applied migrations
     Down {
@@ -99,7 +168,126 @@ enum Commands {
         /// Migrate down to a specific version (exclusive)
         #[clap(long, short = 't')]
         target_version: Option<u32>,
+        /// Output format, json and yaml follow the versioned report schema
+        #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
+    /// Check that every reversible migration's down script restores the previous schema
+    VerifyRoundtrip,
+    /// Parse pending migrations on the server without executing them
+    Validate {
+        /// Skip validation of missing local migration files
+        #[clap(long, short = 'I')]
+        ignore_missing: bool,
     },
+    /// Compare a declarative schema directory with the current schema
+    Diff {
+        /// Directory containing the desired schema as CREATE statements
+        #[clap(long, default_value = "schema/")]
+        schema: String,
+        /// Compare against the live database instead of a scratch database built
+        /// from the existing migrations
+        #[clap(long)]
+        live: bool,
+        /// Write the changes as the next reversible migration with this name
+        #[clap(long, short = 'g')]
+        generate: Option<String>,
+    },
+    /// Move colliding, never applied migrations to the end of the sequence
+    Renumber {
+        /// Show the renames without performing them
+        #[clap(long, short = 'd')]
+        dry_run: bool,
+    },
+    /// Replace old migrations with a single baseline built from their resulting schema
+    Squash {
+        /// Last version to fold into the baseline
+        #[clap(long)]
+        up_to: u32,
+        /// Directory the squashed files are moved to (default: <source>/archive)
+        #[clap(long)]
+        archive: Option<String>,
+    },
+    /// Take over the history of another migration tool, renaming its files to this layout
+    ImportHistory {
+        /// Tool that applied the migrations so far
+        #[clap(long)]
+        from: migration::SourceTool,
+        /// Show the renames and history rows without performing them
+        #[clap(long, short = 'd')]
+        dry_run: bool,
+    },
+    /// Run a large INSERT ... SELECT chunk by chunk, resuming where a previous run stopped
+    Backfill {
+        /// Name the progress is recorded under, reuse it to resume
+        #[clap(long)]
+        name: String,
+        /// Table the chunks are computed from (`table` or `database.table`)
+        #[clap(long)]
+        table: String,
+        /// INSERT ... SELECT filtering on {partition:String}, or {from:UInt64} and
+        /// {to:UInt64} with --by-key
+        #[clap(long)]
+        query: String,
+        /// Split by ranges of this unsigned integer column instead of partitions
+        #[clap(long)]
+        by_key: Option<String>,
+        /// Values of --by-key per chunk
+        #[clap(long, default_value_t = 1_000_000, requires = "by_key")]
+        chunk_size: u64,
+        /// Chunks inserted at the same time
+        #[clap(long, short = 'j', default_value_t = 1)]
+        concurrency: usize,
+        /// `max_threads` setting of each chunk's query
+        #[clap(long)]
+        max_threads: Option<u64>,
+        /// `max_memory_usage` setting of each chunk's query, in bytes
+        #[clap(long)]
+        max_memory_usage: Option<u64>,
+    },
+    /// Show the system.query_log entries of the statements run for a migration version
+    Audit {
+        /// Migration version to look up
+        version: u32,
+    },
+    /// Check migration files for dangerous operations without connecting to ClickHouse
+    Lint {
+        /// Cluster name the migrations are expected to target with ON CLUSTER
+        #[clap(long, env = "MIGRATION_LINT_CLUSTER")]
+        cluster: Option<String>,
+        /// Comma-separated list of rules to disable
+        #[clap(long, value_delimiter = ',')]
+        disable: Vec<migration::LintRule>,
+        /// Comma-separated list of rules to report as warnings
+        #[clap(long, value_delimiter = ',')]
+        warn: Vec<migration::LintRule>,
+        /// Comma-separated list of rules to report as errors
+        #[clap(long, value_delimiter = ',')]
+        deny: Vec<migration::LintRule>,
+        /// Output format
+        #[clap(long, short = 'f', value_enum, default_value_t = LintFormat::Text)]
+        format: LintFormat,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// Human readable lines
+    Table,
+    /// The report as JSON
+    Json,
+    /// The report as YAML
+    Yaml,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum LintFormat {
+    /// Human readable `file:line` lines
+    Text,
+    /// A JSON array of findings
+    Json,
+    /// GitHub Actions workflow commands (annotations)
+    Github,
 }
 
 impl Command {
@@ -107,43 +295,170 @@ impl Command {
         let Command {
             username,
             password,
-            url,
+            urls,
             database,
             options,
             source,
+            create_database,
+            database_engine,
+            database_cluster,
+            tenants,
+            hooks,
+            history_file,
+            transactional,
             command,
         } = self;
 
-        if let Commands::Add {
-            name,
-            reversible,
-            simple,
-        } = command
-        {
-            return add(&source, &name, reversible, simple).await;
-        }
+        let command = match command {
+            Commands::Add {
+                name,
+                reversible,
+                simple,
+            } => return add(&source, &name, reversible, simple).await,
+            Commands::GenDown { version, force } => return gen_down(&source, version, force).await,
+            Commands::Lint {
+                cluster,
+                disable,
+                warn,
+                deny,
+                format,
+            } => {
+                let mut config = migration::LintConfig::new().cluster(cluster);
+                for rule in disable {
+                    config = config.disable(rule);
+                }
+                for rule in warn {
+                    config = config.severity(rule, migration::Severity::Warning);
+                }
+                for rule in deny {
+                    config = config.severity(rule, migration::Severity::Error);
+                }
+                return lint(&source, &config, format).await;
+            }
+            command => command,
+        };
 
-        if url.is_empty() {
+        if urls.iter().any(|url| url.is_empty()) {
             eyre::bail!("--clickhouse-url must be specified");
         }
 
-        let builder = ch::Builder::new(url)
-            .with_username(username)
-            .with_password(password)
-            .with_database(database)
-            .with_options(options);
+        let fan_out = urls.len() > 1 || !tenants.is_empty();
+        if fan_out && !matches!(command, Commands::Up { .. } | Commands::Info { .. }) {
+            eyre::bail!("Only up and info support several --clickhouse-url or --tenants");
+        }
+        if fan_out && history_file.is_some() {
+            eyre::bail!("--history-file can't be shared by several --clickhouse-url or --tenants");
+        }
+
+        if create_database && database.is_none() && tenants.is_empty() {
+            eyre::bail!("--create-database needs --clickhouse-db or --tenants");
+        }
+        let create_options = migration::CreateDatabaseOptions::new()
+            .engine(database_engine)
+            .cluster(database_cluster);
+
+        let mut targets = vec![];
+        for url in &urls {
+            let builder = ch::Builder::new(url.clone())
+                .with_username(username.clone())
+                .with_password(password.clone())
+                .with_database(database.clone())
+                .with_options(options.clone());
 
-        let ch_client = builder
-            .to_client()
-            .wrap_err_with(|| "Failed to build ClickHouse client")?;
+            let ch_client = builder
+                .to_client()
+                .wrap_err_with(|| format!("Failed to build ClickHouse client for {}", url))?;
 
-        let migrator = migration::Migrator::from_client(ch_client);
+            let mut migrator =
+                migration::Migrator::from_client(ch_client).with_transactions(transactional);
+            // Progress of concurrent targets would interleave, fan-out prints a summary instead
+            if !fan_out {
+                migrator = migrator.with_observer(print_progress);
+            }
+            for (point, command) in &hooks {
+                migrator = migrator.with_hook_command(*point, command.clone());
+            }
+            if let Some(path) = &history_file {
+                migrator = migrator.with_history(migration::JsonFileHistory::new(path));
+            }
+
+            // Before ping, which already fails when the default database is missing
+            if create_database && tenants.is_empty() {
+                if let Some(database) = &database {
+                    migrator
+                        .create_database(database, &create_options)
+                        .await
+                        .wrap_err_with(|| format!("Failed to create database on {}", url))?;
+                }
+            }
+
+            migrator
+                .ping()
+                .await
+                .wrap_err_with(|| format!("Failed to ping ClickHouse at {}", url))?;
+
+            if tenants.is_empty() {
+                targets.push((url.clone(), migrator));
+                continue;
+            }
+            let databases = migrator.discover_databases(&tenants).await?;
+            if databases.is_empty() {
+                eyre::bail!(
+                    "No database on {} matches --tenants {}",
+                    url,
+                    tenants.join(",")
+                );
+            }
+            for db in databases {
+                // Patterns only match existing databases, so this creates the literal ones
+                if create_database {
+                    migrator.create_database(&db, &create_options).await?;
+                }
+                let label = match urls.len() {
+                    1 => db.clone(),
+                    _ => format!("{}/{}", url, db),
+                };
+                targets.push((label, migrator.for_database(&db)));
+            }
+        }
 
-        migrator
-            .ping()
-            .await
-            .wrap_err_with(|| "Failed to ping ClickHouse")?;
+        if fan_out {
+            if matches!(
+                command,
+                Commands::Up { output, .. } | Commands::Info { output, .. }
+                    if output != OutputFormat::Table
+            ) {
+                eyre::bail!("--output is not supported with several --clickhouse-url or --tenants");
+            }
+            if matches!(command, Commands::Up { shadow: true, .. }) {
+                eyre::bail!("--shadow is not supported with several --clickhouse-url or --tenants");
+            }
+            return match command {
+                Commands::Up {
+                    dry_run,
+                    ignore_missing,
+                    target_version,
+                    parallelism,
+                    on_failure,
+                    ..
+                } => {
+                    let options = migration::RunOptions::new()
+                        .dry_run(dry_run)
+                        .ignore_missing(ignore_missing)
+                        .target_version(target_version);
+                    let fan_out = migration::FanOutOptions::new()
+                        .parallelism(parallelism)
+                        .on_failure(on_failure);
+                    up_targets(&targets, &source, options, fan_out).await
+                }
+                Commands::Info { ignore_missing, .. } => {
+                    info_matrix(&targets, &source, ignore_missing).await
+                }
+                _ => unreachable!(),
+            };
+        }
 
+        let (_, migrator) = targets.remove(0);
         migrator.ensure_migrations_table().await?;
 
         match command {
@@ -151,13 +466,77 @@ impl Command {
                 dry_run,
                 ignore_missing,
                 target_version,
-            } => up(&migrator, &source, dry_run, ignore_missing, target_version).await?,
+                output,
+                shadow,
+                ..
+            } => {
+                let options = migration::RunOptions::new()
+                    .dry_run(dry_run)
+                    .ignore_missing(ignore_missing)
+                    .target_version(target_version);
+                if shadow {
+                    if output != OutputFormat::Table {
+                        eyre::bail!("--output is not supported with --shadow");
+                    }
+                    up_shadow(&migrator, &source, options).await?
+                } else {
+                    up(&migrator, &source, options, output).await?
+                }
+            }
             Commands::Down {
                 dry_run,
                 ignore_missing,
                 target_version,
-            } => down(&migrator, &source, dry_run, ignore_missing, target_version).await?,
-            Commands::Info { ignore_missing } => info(&migrator, &source, ignore_missing).await?,
+                output,
+            } => {
+                let options = migration::RevertOptions::new()
+                    .dry_run(dry_run)
+                    .ignore_missing(ignore_missing)
+                    .target_version(target_version);
+                down(&migrator, &source, options, output).await?
+            }
+            Commands::Info {
+                ignore_missing,
+                verbose,
+                output,
+            } => info(&migrator, &source, ignore_missing, verbose, output).await?,
+            Commands::Status { check } => status(&migrator, &source, check).await?,
+            Commands::VerifyRoundtrip => verify_roundtrip(&migrator, &source).await?,
+            Commands::Validate { ignore_missing } => {
+                validate(&migrator, &source, ignore_missing).await?
+            }
+            Commands::Diff {
+                schema,
+                live,
+                generate,
+            } => diff(&migrator, &source, &schema, live, generate).await?,
+            Commands::Audit { version } => audit(&migrator, version).await?,
+            Commands::Renumber { dry_run } => renumber(&migrator, &source, dry_run).await?,
+            Commands::Squash { up_to, archive } => {
+                squash(&migrator, &source, up_to, archive.as_deref()).await?
+            }
+            Commands::Backfill {
+                name,
+                table,
+                query,
+                by_key,
+                chunk_size,
+                concurrency,
+                max_threads,
+                max_memory_usage,
+            } => {
+                let mut options = migration::BackfillOptions::new(name, table, query)
+                    .concurrency(concurrency)
+                    .max_threads(max_threads)
+                    .max_memory_usage(max_memory_usage);
+                if let Some(key) = by_key {
+                    options = options.by_key(key, chunk_size);
+                }
+                backfill(&migrator, &options).await?
+            }
+            Commands::ImportHistory { from, dry_run } => {
+                import_history(&migrator, &source, from, dry_run).await?
+            }
             _ => unreachable!(),
         }
 
@@ -182,16 +561,99 @@ async fn add(src: &str, name: &str, reversible: bool, simple: bool) -> eyre::Res
     Ok(())
 }
 
+async fn lint(src: &str, config: &migration::LintConfig, format: LintFormat) -> eyre::Result<()> {
+    let findings = migration::lint(src, config).await?;
+
+    match format {
+        LintFormat::Text => {
+            for f in &findings {
+                println!(
+                    "{}:{}: {} [{}] {}",
+                    f.file, f.line, f.severity, f.rule, f.message
+                );
+            }
+        }
+        LintFormat::Json => println!("{}", serde_json::to_string_pretty(&findings)?),
+        LintFormat::Github => {
+            for f in &findings {
+                println!(
+                    "::{} file={},line={},title={}::{}",
+                    f.severity, f.file, f.line, f.rule, f.message
+                );
+            }
+        }
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == migration::Severity::Error)
+        .count();
+    if errors > 0 {
+        eyre::bail!(
+            "Lint found {} error(s) and {} warning(s)",
+            errors,
+            findings.len() - errors
+        );
+    }
+    eprintln!("Lint found {} warning(s)", findings.len());
+    Ok(())
+}
+
+fn print_progress(event: &migration::MigrationEvent) {
+    use migration::MigrationEvent;
+
+    match event {
+        MigrationEvent::MigrationStarted {
+            version,
+            name,
+            direction,
+        } => eprintln!("Running {:04}_{} ({})...", version, name, direction),
+        MigrationEvent::MigrationApplied {
+            version,
+            name,
+            duration,
+        } => eprintln!("Applied {:04}_{} in {:.2?}", version, name, duration),
+        MigrationEvent::MigrationReverted {
+            version,
+            name,
+            duration,
+        } => eprintln!("Reverted {:04}_{} in {:.2?}", version, name, duration),
+        MigrationEvent::MigrationFailed { version, name, .. } => {
+            eprintln!("Failed {:04}_{}", version, name)
+        }
+        _ => {}
+    }
+}
+
+async fn gen_down(src: &str, version: u32, force: bool) -> eyre::Result<()> {
+    let generated = migration::gen_down(src, version, force).await?;
+    eprintln!(
+        "Wrote {} inverse statement(s) to {}",
+        generated.statements, generated.path
+    );
+    if generated.todos > 0 {
+        eprintln!(
+            "{} statement(s) could not be inverted, see the TODO comments",
+            generated.todos
+        );
+    }
+    println!("{}", generated.path);
+    Ok(())
+}
+
 async fn up(
     migrator: &impl migration::Migration,
     src: &str,
-    dry_run: bool,
-    ignore_missing: bool,
-    target_version: Option<u32>,
+    options: migration::RunOptions,
+    output: OutputFormat,
 ) -> eyre::Result<()> {
-    let installed = migrator
-        .run(src, dry_run, ignore_missing, target_version)
-        .await?;
+    let dry_run = options.dry_run;
+    let installed = migrator.run(src, options).await?;
+    if output != OutputFormat::Table {
+        let statements = dry_run.then_some(migration::Direction::Up);
+        let report = migration::Report::build("up", dry_run, &installed, statements).await?;
+        return print_report(&report, output);
+    }
 
     eprintln!(
         "{}Installed {} migration(s)!",
@@ -205,13 +667,16 @@ async fn up(
 async fn down(
     migrator: &impl migration::Migration,
     src: &str,
-    dry_run: bool,
-    ignore_missing: bool,
-    target_version: Option<u32>,
+    options: migration::RevertOptions,
+    output: OutputFormat,
 ) -> eyre::Result<()> {
-    let uninstalled = migrator
-        .revert(src, dry_run, ignore_missing, target_version)
-        .await?;
+    let dry_run = options.dry_run;
+    let uninstalled = migrator.revert(src, options).await?;
+    if output != OutputFormat::Table {
+        let statements = dry_run.then_some(migration::Direction::Down);
+        let report = migration::Report::build("down", dry_run, &uninstalled, statements).await?;
+        return print_report(&report, output);
+    }
     eprintln!(
         "{}Uninstalled {} migration(s)!",
         if dry_run { "(Prepare) " } else { "" },
@@ -225,10 +690,415 @@ async fn info(
     migrator: &impl migration::Migration,
     src: &str,
     ignore_missing: bool,
+    verbose: bool,
+    output: OutputFormat,
 ) -> eyre::Result<()> {
     let migrations = migrator.info(src, ignore_missing).await?;
+    if output != OutputFormat::Table {
+        let report = migration::Report::build("info", false, &migrations, None).await?;
+        return print_report(&report, output);
+    }
     eprintln!("Migration status");
-    print_migrations_info(&migrations);
+    if !verbose {
+        print_migrations_info(&migrations);
+        return Ok(());
+    }
+
+    for mig in &migrations {
+        if mig.status == migration::MigrationStatus::Pending {
+            println!("{} pending", mig.full_version());
+            continue;
+        }
+        println!(
+            "{} applied at {} in {} by {}@{} ({}){}",
+            mig.full_version(),
+            mig.applied_at.to_rfc3339(),
+            humantime::format_duration(std::time::Duration::from_millis(mig.duration_ms)),
+            or_unknown(&mig.applied_by),
+            or_unknown(&mig.host),
+            or_unknown(&mig.chutils_version),
+            if mig.transactional {
+                " in a transaction"
+            } else {
+                ""
+            },
+        );
+    }
+    Ok(())
+}
+
+fn print_report(report: &migration::Report, output: OutputFormat) -> eyre::Result<()> {
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+        OutputFormat::Yaml => print!("{}", serde_yaml_ng::to_string(report)?),
+        OutputFormat::Table => unreachable!(),
+    }
+    Ok(())
+}
+
+async fn status(migrator: &migration::Migrator, src: &str, check: bool) -> eyre::Result<()> {
+    let state = migrator.status(src).await?;
+    println!("{}", state);
+
+    let code = match state {
+        migration::SchemaState::UpToDate { .. } => return Ok(()),
+        migration::SchemaState::Pending { .. } => 20,
+        migration::SchemaState::Ahead { .. } => 21,
+        migration::SchemaState::Corrupted(_) => 22,
+    };
+    if check {
+        return Err(StatusCheckFailed { code, state }.into());
+    }
+    Ok(())
+}
+
+/// `migrate status --check` found the database anywhere but at the latest local migration.
+#[derive(Debug)]
+pub struct StatusCheckFailed {
+    pub code: i32,
+    state: migration::SchemaState,
+}
+
+impl std::fmt::Display for StatusCheckFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Status check failed: {}", self.state)
+    }
+}
+
+impl std::error::Error for StatusCheckFailed {}
+
+/// Rows recorded before metadata columns existed have empty values.
+fn or_unknown(value: &str) -> &str {
+    if value.is_empty() { "unknown" } else { value }
+}
+
+async fn validate(
+    migrator: &migration::Migrator,
+    src: &str,
+    ignore_missing: bool,
+) -> eyre::Result<()> {
+    let errors = migrator.validate(src, ignore_missing).await?;
+    for err in &errors {
+        println!("{}", err);
+    }
+
+    if !errors.is_empty() {
+        eyre::bail!(
+            "{} statement(s) in pending migrations failed validation",
+            errors.len()
+        );
+    }
+    eprintln!("All pending migrations are valid");
+    Ok(())
+}
+
+async fn verify_roundtrip(migrator: &migration::Migrator, src: &str) -> eyre::Result<()> {
+    let reports = migrator.verify_roundtrip(src).await?;
+    eprintln!("Verified {} reversible migration(s)", reports.len());
+
+    let mut failed = vec![];
+    for report in &reports {
+        println!("{:04}_{} {}", report.version, report.name, report.outcome);
+        if !report.is_ok() {
+            failed.push(report.down_file.as_str());
+        }
+    }
+
+    if !failed.is_empty() {
+        eyre::bail!(
+            "{} down migration(s) failed to restore the previous state: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+async fn diff(
+    migrator: &migration::Migrator,
+    src: &str,
+    schema: &str,
+    live: bool,
+    generate: Option<String>,
+) -> eyre::Result<()> {
+    let base = if live {
+        migration::DiffBase::Live
+    } else {
+        migration::DiffBase::Migrations
+    };
+
+    let changes = migrator.diff_schema(src, schema, base).await?;
+    for warning in &changes.warnings {
+        eprintln!("WARNING: {}", warning);
+    }
+
+    if changes.is_empty() {
+        if changes.warnings.is_empty() {
+            eprintln!("Schema is up to date, nothing to generate");
+        } else {
+            eprintln!("No statements to generate, the changes above must be made by hand");
+        }
+        return Ok(());
+    }
+
+    match generate {
+        Some(name) => {
+            for fp in changes.write_migration(src, &name).await? {
+                println!("Added migration file to {}", fp)
+            }
+        }
+        None => {
+            for stmt in &changes.up {
+                println!("{};", stmt);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn renumber(migrator: &migration::Migrator, src: &str, dry_run: bool) -> eyre::Result<()> {
+    let renumbered = migrator.renumber(src, dry_run).await?;
+    for moved in &renumbered {
+        for (from, to) in &moved.files {
+            println!("{} -> {}", from, to);
+        }
+    }
+    eprintln!(
+        "{}Renumbered {} migration(s)",
+        if dry_run { "(Prepare) " } else { "" },
+        renumbered.len()
+    );
+    Ok(())
+}
+
+async fn up_shadow(
+    migrator: &migration::Migrator,
+    src: &str,
+    options: migration::RunOptions,
+) -> eyre::Result<()> {
+    let report = migrator.shadow_run(src, options).await?;
+    eprintln!(
+        "Ran {} pending migration(s) of {} in shadow database {}",
+        report.migrations.len(),
+        report.source_database,
+        report.shadow_database
+    );
+
+    for stmt in &report.statements {
+        let position = format!("{}:{}", stmt.file, stmt.index + 1);
+        match &stmt.error {
+            None => println!("{} ok in {:.2?}", position, stmt.duration),
+            Some(err) => println!("{} failed: {}", position, err),
+        }
+    }
+
+    if !report.is_ok() {
+        eyre::bail!("Pending migrations failed in the shadow database");
+    }
+    eprintln!("All pending migrations succeeded in the shadow database");
+    Ok(())
+}
+
+async fn up_targets(
+    targets: &[(String, migration::Migrator)],
+    src: &str,
+    options: migration::RunOptions,
+    fan_out: migration::FanOutOptions,
+) -> eyre::Result<()> {
+    eprintln!(
+        "{}Migrating {} target(s), {} at a time",
+        if options.dry_run { "(Prepare) " } else { "" },
+        targets.len(),
+        fan_out.parallelism
+    );
+    let reports = migration::run_targets(targets, src, options, fan_out).await;
+
+    for report in &reports {
+        println!("{}: {}", report.target, report.outcome);
+    }
+
+    let failed = reports.iter().filter(|r| r.is_failed()).count();
+    if failed > 0 {
+        eyre::bail!("{} of {} target(s) failed", failed, reports.len());
+    }
+    Ok(())
+}
+
+/// One row per local migration, one column per target.
+async fn info_matrix(
+    targets: &[(String, migration::Migrator)],
+    src: &str,
+    ignore_missing: bool,
+) -> eyre::Result<()> {
+    let mut columns = vec![];
+    let mut rows = std::collections::BTreeMap::new();
+    let mut failed = 0;
+    for (target, migrator) in targets {
+        let result = match migrator.ensure_migrations_table().await {
+            Ok(()) => migrator.info(src, ignore_missing).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(migrations) => {
+                for mig in &migrations {
+                    rows.insert(mig.version, mig.full_version());
+                }
+                columns.push((target.as_str(), Some(migrations)));
+            }
+            Err(err) => {
+                eprintln!("{}: {}", target, err);
+                failed += 1;
+                columns.push((target.as_str(), None));
+            }
+        }
+    }
+
+    let status = |migrations: &Option<Vec<migration::MigrationInfo>>, version: u32| {
+        let Some(migrations) = migrations else {
+            return "error";
+        };
+        match migrations.iter().find(|m| m.version == version) {
+            Some(m) if m.status == migration::MigrationStatus::Applied => "applied",
+            Some(_) => "pending",
+            None => "-",
+        }
+    };
+    let latest = |migrations: &Option<Vec<migration::MigrationInfo>>| match migrations {
+        Some(migrations) => migrations
+            .iter()
+            .filter(|m| m.status == migration::MigrationStatus::Applied)
+            .map(|m| format!("{:04}", m.version))
+            .next_back()
+            .unwrap_or_else(|| "none".to_string()),
+        None => "error".to_string(),
+    };
+
+    let first_width = rows
+        .values()
+        .map(|v| v.len())
+        .chain(["MIGRATION".len()])
+        .max()
+        .unwrap_or_default();
+    let widths: Vec<usize> = columns
+        .iter()
+        .map(|(target, _)| target.len().max("applied".len()))
+        .collect();
+
+    let mut line = format!("{:first_width$}", "MIGRATION");
+    for ((target, _), width) in columns.iter().zip(&widths) {
+        line.push_str(&format!("  {:width$}", target));
+    }
+    println!("{}", line.trim_end());
+    for (version, full_version) in &rows {
+        let mut line = format!("{:first_width$}", full_version);
+        for ((_, migrations), width) in columns.iter().zip(&widths) {
+            line.push_str(&format!("  {:width$}", status(migrations, *version)));
+        }
+        println!("{}", line.trim_end());
+    }
+    let mut line = format!("{:first_width$}", "LATEST");
+    for ((_, migrations), width) in columns.iter().zip(&widths) {
+        line.push_str(&format!("  {:width$}", latest(migrations)));
+    }
+    println!("{}", line.trim_end());
+
+    if failed > 0 {
+        eyre::bail!("{} of {} target(s) failed", failed, targets.len());
+    }
+    Ok(())
+}
+
+async fn squash(
+    migrator: &migration::Migrator,
+    src: &str,
+    up_to: u32,
+    archive: Option<&str>,
+) -> eyre::Result<()> {
+    let report = migrator.squash(src, up_to, archive).await?;
+    for (from, to) in &report.archived {
+        println!("Archived {} to {}", from, to);
+    }
+    println!(
+        "Squashed versions {} to {} into {}",
+        report.from, report.to, report.baseline
+    );
+    Ok(())
+}
+
+async fn backfill(
+    migrator: &migration::Migrator,
+    options: &migration::BackfillOptions,
+) -> eyre::Result<()> {
+    let report = migrator.backfill(options).await?;
+    for chunk in &report.inserted {
+        println!("Inserted {}", chunk);
+    }
+    eprintln!(
+        "Backfill {}: {} chunk(s), {} inserted, {} already done",
+        report.name,
+        report.chunks.len(),
+        report.inserted.len(),
+        report.skipped.len()
+    );
+    Ok(())
+}
+
+async fn import_history(
+    migrator: &migration::Migrator,
+    src: &str,
+    from: migration::SourceTool,
+    dry_run: bool,
+) -> eyre::Result<()> {
+    let imported = migrator.import_history(src, from, dry_run).await?;
+    for mig in &imported {
+        println!(
+            "{:04}_{} ({}, source version {})",
+            mig.version,
+            mig.name,
+            if mig.applied { "applied" } else { "pending" },
+            mig.source_version
+        );
+        for path in &mig.from {
+            println!("  - {}", path);
+        }
+        for path in &mig.to {
+            println!("  + {}", path);
+        }
+    }
+    eprintln!(
+        "{}Imported {} migration(s), {} applied",
+        if dry_run { "(Prepare) " } else { "" },
+        imported.len(),
+        imported.iter().filter(|m| m.applied).count()
+    );
+    Ok(())
+}
+
+async fn audit(migrator: &migration::Migrator, version: u32) -> eyre::Result<()> {
+    let entries = migrator.audit(version).await?;
+    if entries.is_empty() {
+        eprintln!(
+            "No query_log entries found for version {} (the query log is flushed periodically)",
+            version
+        );
+        return Ok(());
+    }
+
+    for entry in &entries {
+        println!(
+            "{} {} at {} in {}ms read={} written={} memory={}",
+            entry.query_id,
+            entry.kind,
+            entry.event_time.to_rfc3339(),
+            entry.query_duration_ms,
+            human_bytes::human_bytes(entry.read_bytes as f64),
+            human_bytes::human_bytes(entry.written_bytes as f64),
+            human_bytes::human_bytes(entry.memory_usage as f64),
+        );
+        if !entry.exception.is_empty() {
+            println!("  exception: {}", entry.exception);
+        }
+    }
     Ok(())
 }
 
@@ -249,3 +1119,35 @@ fn print_migrations_info(migrations: &[migration::MigrationInfo]) {
         )
     }
 }
+
+/// Process exit code for a migration failure, so scripts can tell failures apart
+/// without matching on messages.
+pub fn exit_code(err: &migration::Error) -> i32 {
+    match err {
+        migration::Error::InvalidInput(_) => 2,
+        migration::Error::InvalidMigrationSet(_) => 3,
+        migration::Error::OutOfOrder { .. } => 4,
+        migration::Error::NameMismatch { .. } => 5,
+        migration::Error::MissingLocal { .. } => 6,
+        migration::Error::StatementFailed { .. } => 7,
+        migration::Error::ClickhouseError(_) => 8,
+        migration::Error::IoError(_) => 9,
+        migration::Error::HookFailed { .. } => 10,
+        migration::Error::PartialBaseline { .. } => 11,
+        migration::Error::Locked(_) => 12,
+    }
+}
+
+fn parse_hook(raw: &str) -> Result<(migration::HookPoint, String), String> {
+    let (point, command) = raw
+        .split_once('=')
+        .filter(|(_, command)| !command.is_empty())
+        .ok_or_else(|| {
+            format!(
+                "Invalid hook: must be in the format `hook=command`. Received `{}`",
+                raw
+            )
+        })?;
+    let point = point.parse().map_err(|e: migration::Error| e.to_string())?;
+    Ok((point, command.to_owned()))
+}
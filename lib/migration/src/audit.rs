@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ch::clickhouse;
+
+use crate::{Direction, Error, Migrator};
+
+/// Identifies the statements of one migration file within one run, used to tag
+/// queries so they can be found in `system.query_log`.
+pub(crate) struct StatementTag<'a> {
+    pub run_id: &'a str,
+    pub version: u32,
+    pub name: &'a str,
+    pub file: &'a str,
+    pub direction: Direction,
+}
+
+impl StatementTag<'_> {
+    /// `chutils-mig-0005-<run id>-<stmt>`, down statements get a `down-` infix.
+    pub fn query_id(&self, index: usize) -> String {
+        match self.direction {
+            Direction::Up => format!(
+                "{}-{}-{}",
+                query_id_prefix(self.version),
+                self.run_id,
+                index
+            ),
+            Direction::Down => format!(
+                "{}-{}-down-{}",
+                query_id_prefix(self.version),
+                self.run_id,
+                index
+            ),
+        }
+    }
+
+    pub fn log_comment(&self, index: usize) -> String {
+        serde_json::json!({
+            "tool": "chutils",
+            "run_id": self.run_id,
+            "version": self.version,
+            "name": self.name,
+            "file": self.file,
+            "direction": self.direction.to_string(),
+            "statement_index": index,
+        })
+        .to_string()
+    }
+}
+
+fn query_id_prefix(version: u32) -> String {
+    format!("chutils-mig-{:04}", version)
+}
+
+/// Prefix of the run ids of migrations replayed in scratch databases (diff, squash,
+/// verify-roundtrip), which `Migrator::audit` leaves out.
+const SCRATCH_RUN_PREFIX: &str = "scratch-";
+
+/// Unique id for one `run`/`revert` invocation. The counter keeps runs started in the
+/// same millisecond by one process (e.g. fan-out over tenants) apart.
+pub(crate) fn new_run_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{:x}-{:x}-{:x}",
+        chrono::Utc::now().timestamp_millis(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Run id for replaying migrations in a scratch database.
+pub(crate) fn new_scratch_run_id() -> String {
+    format!("{}{}", SCRATCH_RUN_PREFIX, new_run_id())
+}
+
+/// A finished (or failed) migration statement from `system.query_log`.
+#[derive(Debug, Clone, clickhouse::Row, serde::Deserialize, serde::Serialize)]
+pub struct QueryLogEntry {
+    pub query_id: String,
+    /// `QueryFinish`, `ExceptionBeforeStart` or `ExceptionWhileProcessing`
+    pub kind: String,
+    #[serde(with = "ch::clickhouse::serde::chrono::datetime")]
+    pub event_time: chrono::DateTime<chrono::Utc>,
+    pub query_duration_ms: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub memory_usage: u64,
+    pub exception: String,
+    pub query: String,
+    pub log_comment: String,
+}
+
+impl Migrator {
+    /// Fetch the `system.query_log` rows of every statement chutils ran for `version`
+    /// against the migrator's database.
+    ///
+    /// Statements run in scratch databases by `diff`, `squash` or `verify_roundtrip`
+    /// aren't included. The server flushes the query log periodically, so very recent statements may
+    /// not show up yet.
+    pub async fn audit(&self, version: u32) -> Result<Vec<QueryLogEntry>, Error> {
+        let entries = self
+            .inner
+            .query(
+                "
+            SELECT
+                query_id,
+                toString(type) AS kind,
+                event_time,
+                query_duration_ms,
+                read_bytes,
+                written_bytes,
+                memory_usage,
+                exception,
+                query,
+                log_comment
+            FROM system.query_log
+            WHERE query_id LIKE ? AND query_id NOT LIKE ?
+                AND current_database = currentDatabase()
+                AND type != 'QueryStart'
+            ORDER BY event_time_microseconds
+            ",
+            )
+            .bind(format!("{}-%", query_id_prefix(version)))
+            .bind(format!(
+                "{}-{}%",
+                query_id_prefix(version),
+                SCRATCH_RUN_PREFIX
+            ))
+            .fetch_all::<QueryLogEntry>()
+            .await?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(direction: Direction) -> StatementTag<'static> {
+        StatementTag {
+            run_id: "abc",
+            version: 5,
+            name: "add_email",
+            file: "migrations/0005_add_email.up.sql",
+            direction,
+        }
+    }
+
+    #[test]
+    fn test_query_id() {
+        assert_eq!(tag(Direction::Up).query_id(2), "chutils-mig-0005-abc-2");
+        assert_eq!(
+            tag(Direction::Down).query_id(0),
+            "chutils-mig-0005-abc-down-0"
+        );
+    }
+
+    #[test]
+    fn test_run_ids_are_unique() {
+        let ids: std::collections::HashSet<_> = (0..1000).map(|_| new_run_id()).collect();
+        assert_eq!(ids.len(), 1000);
+        assert!(new_scratch_run_id().starts_with(SCRATCH_RUN_PREFIX));
+    }
+
+    #[test]
+    fn test_log_comment() {
+        let comment: serde_json::Value =
+            serde_json::from_str(&tag(Direction::Up).log_comment(1)).unwrap();
+        assert_eq!(comment["version"], 5);
+        assert_eq!(comment["name"], "add_email");
+        assert_eq!(comment["file"], "migrations/0005_add_email.up.sql");
+        assert_eq!(comment["statement_index"], 1);
+        assert_eq!(comment["direction"], "up");
+    }
+}
@@ -0,0 +1,425 @@
+use ch::clickhouse;
+use clickhouse::sql::Identifier;
+use futures::{StreamExt, TryStreamExt};
+
+use crate::{Error, Migrator};
+
+/// How `Migrator::backfill` splits the work.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackfillChunks {
+    /// One chunk per active partition of the source table, bound as `{partition:String}`
+    Partitions,
+    /// Consecutive ranges of an unsigned integer column, bound as `{from:UInt64}`
+    /// (inclusive) and `{to:UInt64}` (exclusive)
+    KeyRange { key: String, chunk_size: u64 },
+}
+
+/// A chunked `INSERT ... SELECT`, see `Migrator::backfill`.
+#[derive(Debug, Clone)]
+pub struct BackfillOptions {
+    /// Identifies the backfill in `_ch_backfills`, reuse it to resume
+    pub name: String,
+    /// Table the chunks are computed from, `table` or `database.table`
+    pub source_table: String,
+    /// `INSERT ... SELECT` filtering on the chunk parameters
+    pub query: String,
+    pub chunks: BackfillChunks,
+    /// Chunks inserted at the same time
+    pub concurrency: usize,
+    pub max_threads: Option<u64>,
+    pub max_memory_usage: Option<u64>,
+}
+
+impl BackfillOptions {
+    /// Defaults split by partition and insert one chunk at a time with the server's
+    /// settings.
+    pub fn new(
+        name: impl Into<String>,
+        source_table: impl Into<String>,
+        query: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            source_table: source_table.into(),
+            query: query.into(),
+            chunks: BackfillChunks::Partitions,
+            concurrency: 1,
+            max_threads: None,
+            max_memory_usage: None,
+        }
+    }
+
+    /// Split by ranges of `chunk_size` values of the unsigned integer column `key`
+    /// instead of partitions
+    pub fn by_key(mut self, key: impl Into<String>, chunk_size: u64) -> Self {
+        self.chunks = BackfillChunks::KeyRange {
+            key: key.into(),
+            chunk_size: chunk_size.max(1),
+        };
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn max_threads(mut self, max_threads: Option<u64>) -> Self {
+        self.max_threads = max_threads;
+        self
+    }
+
+    pub fn max_memory_usage(mut self, max_memory_usage: Option<u64>) -> Self {
+        self.max_memory_usage = max_memory_usage;
+        self
+    }
+}
+
+/// Outcome of `Migrator::backfill`.
+#[derive(Debug, Clone, Default)]
+pub struct BackfillReport {
+    pub name: String,
+    /// Chunks of the source table, in order
+    pub chunks: Vec<String>,
+    /// Chunks inserted by this call
+    pub inserted: Vec<String>,
+    /// Chunks already recorded by a previous call
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, clickhouse::Row, serde::Serialize, serde::Deserialize)]
+struct BackfillProgress {
+    name: String,
+    chunk: String,
+    #[serde(with = "ch::clickhouse::serde::chrono::datetime")]
+    finished_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, clickhouse::Row, serde::Serialize, serde::Deserialize)]
+struct KeyBounds {
+    rows: u64,
+    key_type: String,
+    min: u64,
+    max: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Chunk {
+    Partition(String),
+    Range { from: u64, to: u64 },
+}
+
+impl Chunk {
+    fn id(&self) -> String {
+        match self {
+            Self::Partition(id) => id.clone(),
+            Self::Range { from, to } => format!("{}-{}", from, to),
+        }
+    }
+}
+
+impl Migrator {
+    /// Run a large `INSERT ... SELECT` chunk by chunk, e.g. from a data migration that
+    /// would time out or run out of memory as a single statement.
+    ///
+    /// `options.query` must filter on the chunk parameters, `{partition:String}` (compare
+    /// with `_partition_id`) or `{from:UInt64}` / `{to:UInt64}`. Each finished chunk is
+    /// recorded in `_ch_backfills` under `options.name`, so running the same backfill
+    /// again only inserts the chunks left. Every chunk carries an
+    /// `insert_deduplication_token`, a chunk interrupted halfway and retried is
+    /// deduplicated by tables with insert deduplication enabled.
+    pub async fn backfill(&self, options: &BackfillOptions) -> Result<BackfillReport, Error> {
+        let required: &[&str] = match options.chunks {
+            BackfillChunks::Partitions => &["{partition:"],
+            BackfillChunks::KeyRange { .. } => &["{from:", "{to:"],
+        };
+        if let Some(missing) = required.iter().find(|p| !options.query.contains(**p)) {
+            return Err(Error::InvalidInput(format!(
+                "backfill query must filter on {}...}} or it would insert everything for every chunk",
+                missing
+            )));
+        }
+
+        self.inner
+            .query(
+                "
+            CREATE TABLE IF NOT EXISTS _ch_backfills (
+                name String,
+                chunk String,
+                finished_at DateTime DEFAULT now()
+                ) ENGINE = MergeTree()
+            ORDER BY (name, chunk)
+            ",
+            )
+            .execute()
+            .await?;
+
+        let chunks = self.backfill_chunks(options).await?;
+        let done: Vec<String> = self
+            .inner
+            .query("SELECT chunk FROM _ch_backfills WHERE name = ?")
+            .bind(&options.name)
+            .fetch_all()
+            .await?;
+
+        let mut report = BackfillReport {
+            name: options.name.clone(),
+            chunks: chunks.iter().map(Chunk::id).collect(),
+            ..Default::default()
+        };
+        let (skipped, pending): (Vec<_>, Vec<_>) =
+            chunks.into_iter().partition(|c| done.contains(&c.id()));
+        report.skipped = skipped.iter().map(Chunk::id).collect();
+
+        report.inserted = futures::stream::iter(pending)
+            .map(|chunk| self.backfill_chunk(options, chunk))
+            .buffered(options.concurrency)
+            .try_collect()
+            .await?;
+        Ok(report)
+    }
+
+    async fn backfill_chunks(&self, options: &BackfillOptions) -> Result<Vec<Chunk>, Error> {
+        let (database, table) = match options.source_table.split_once('.') {
+            Some((database, table)) => (Some(database), table),
+            None => (None, options.source_table.as_str()),
+        };
+        match &options.chunks {
+            BackfillChunks::Partitions => {
+                let partitions: Vec<String> = self
+                    .inner
+                    .query(
+                        "SELECT DISTINCT partition_id FROM system.parts
+                        WHERE database = if(? = '', currentDatabase(), ?) AND table = ? AND active
+                        ORDER BY partition_id",
+                    )
+                    .bind(database.unwrap_or_default())
+                    .bind(database.unwrap_or_default())
+                    .bind(table)
+                    .fetch_all()
+                    .await?;
+                Ok(partitions.into_iter().map(Chunk::Partition).collect())
+            }
+            BackfillChunks::KeyRange { key, chunk_size } => {
+                let mut query = self
+                    .inner
+                    .query(match database {
+                        Some(_) => {
+                            "SELECT count(), toTypeName(min(?)), toUInt64(min(?)), toUInt64(max(?)) FROM ?.?"
+                        }
+                        None => {
+                            "SELECT count(), toTypeName(min(?)), toUInt64(min(?)), toUInt64(max(?)) FROM ?"
+                        }
+                    })
+                    .bind(Identifier(key))
+                    .bind(Identifier(key))
+                    .bind(Identifier(key));
+                if let Some(database) = database {
+                    query = query.bind(Identifier(database));
+                }
+                let bounds = query
+                    .bind(Identifier(table))
+                    .fetch_one::<KeyBounds>()
+                    .await?;
+                // Negative values would wrap around in the UInt64 bounds
+                if !bounds.key_type.starts_with("UInt") {
+                    return Err(Error::InvalidInput(format!(
+                        "backfill key {} is {}, only unsigned integer keys can be split by range",
+                        key, bounds.key_type
+                    )));
+                }
+                if bounds.rows == 0 {
+                    return Ok(vec![]);
+                }
+                if bounds.min > bounds.max {
+                    return Err(Error::InvalidInput(format!(
+                        "backfill key {} has min {} above max {}",
+                        key, bounds.min, bounds.max
+                    )));
+                }
+                Ok(key_ranges(bounds.min, bounds.max, *chunk_size))
+            }
+        }
+    }
+
+    async fn backfill_chunk(
+        &self,
+        options: &BackfillOptions,
+        chunk: Chunk,
+    ) -> Result<String, Error> {
+        let id = chunk.id();
+        let started = std::time::Instant::now();
+
+        let mut query = self.inner.query(&options.query).with_option(
+            "insert_deduplication_token",
+            format!("chutils-backfill-{}-{}", options.name, id),
+        );
+        if let Some(max_threads) = options.max_threads {
+            query = query.with_option("max_threads", max_threads.to_string());
+        }
+        if let Some(max_memory_usage) = options.max_memory_usage {
+            query = query.with_option("max_memory_usage", max_memory_usage.to_string());
+        }
+        query = match &chunk {
+            Chunk::Partition(partition) => query.param("partition", partition),
+            Chunk::Range { from, to } => query.param("from", from).param("to", to),
+        };
+        query.execute().await?;
+
+        let mut insert = self.inner.insert::<BackfillProgress>("_ch_backfills")?;
+        insert
+            .write(&BackfillProgress {
+                name: options.name.clone(),
+                chunk: id.clone(),
+                finished_at: chrono::Utc::now(),
+            })
+            .await?;
+        insert.end().await?;
+
+        tracing::info!(backfill = options.name, chunk = id, elapsed = ?started.elapsed(), "Backfilled chunk");
+        Ok(id)
+    }
+}
+
+/// `[from, to)` ranges of `size` values covering `min..=max`.
+fn key_ranges(min: u64, max: u64, size: u64) -> Vec<Chunk> {
+    let mut ranges = vec![];
+    let mut from = min;
+    loop {
+        let to = from.saturating_add(size).min(max.saturating_add(1));
+        ranges.push(Chunk::Range { from, to });
+        if to > max || to == u64::MAX {
+            return ranges;
+        }
+        from = to;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickhouse::test;
+
+    #[test]
+    fn test_key_ranges() {
+        assert_eq!(
+            key_ranges(1, 25, 10),
+            vec![
+                Chunk::Range { from: 1, to: 11 },
+                Chunk::Range { from: 11, to: 21 },
+                Chunk::Range { from: 21, to: 26 },
+            ]
+        );
+        assert_eq!(key_ranges(5, 5, 10), vec![Chunk::Range { from: 5, to: 6 }]);
+    }
+
+    #[tokio::test]
+    async fn test_backfill_resumes_by_partition() {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::provide(vec![
+            "202401".to_string(),
+            "202402".to_string(),
+        ]));
+        mock.add(test::handlers::provide(vec!["202401".to_string()]));
+        let insert = mock.add(test::handlers::record_ddl());
+        let progress = mock.add(test::handlers::record());
+
+        let options = BackfillOptions::new(
+            "events_v2",
+            "events",
+            "INSERT INTO events_v2 SELECT * FROM events WHERE _partition_id = {partition:String}",
+        )
+        .max_threads(Some(4));
+        let report = migrator.backfill(&options).await.unwrap();
+        assert_eq!(report.chunks, ["202401", "202402"]);
+        assert_eq!(report.skipped, ["202401"]);
+        assert_eq!(report.inserted, ["202402"]);
+
+        assert!(insert.query().await.starts_with("INSERT INTO events_v2"));
+        let rows: Vec<BackfillProgress> = progress.collect().await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(
+            (rows[0].name.as_str(), rows[0].chunk.as_str()),
+            ("events_v2", "202402")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backfill_requires_chunk_filter() {
+        let migrator = Migrator::from_client(clickhouse::Client::default());
+        let options = BackfillOptions::new("copy", "events", "INSERT INTO b SELECT * FROM events")
+            .by_key("id", 1000);
+        assert!(matches!(
+            migrator.backfill(&options).await,
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    fn key_bounds(rows: u64, key_type: &str, min: u64, max: u64) -> KeyBounds {
+        KeyBounds {
+            rows,
+            key_type: key_type.to_string(),
+            min,
+            max,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backfill_resumes_by_key_range() {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::provide(vec![key_bounds(
+            25, "UInt32", 1, 25,
+        )]));
+        mock.add(test::handlers::provide(vec!["1-11".to_string()]));
+        let first = mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record::<BackfillProgress>());
+        let second = mock.add(test::handlers::record_ddl());
+        let progress = mock.add(test::handlers::record());
+
+        let options = BackfillOptions::new(
+            "events_v2",
+            "analytics.events",
+            "INSERT INTO events_v2 SELECT * FROM events WHERE id >= {from:UInt64} AND id < {to:UInt64}",
+        )
+        .by_key("id", 10);
+        let report = migrator.backfill(&options).await.unwrap();
+        assert_eq!(report.chunks, ["1-11", "11-21", "21-26"]);
+        assert_eq!(report.skipped, ["1-11"]);
+        assert_eq!(report.inserted, ["11-21", "21-26"]);
+
+        assert!(first.query().await.starts_with("INSERT INTO events_v2"));
+        assert!(second.query().await.starts_with("INSERT INTO events_v2"));
+        let rows: Vec<BackfillProgress> = progress.collect().await;
+        assert_eq!(rows[0].chunk, "21-26");
+    }
+
+    #[tokio::test]
+    async fn test_backfill_rejects_unusable_key_bounds() {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let options = BackfillOptions::new(
+            "copy",
+            "events",
+            "INSERT INTO b SELECT * FROM events WHERE id >= {from:UInt64} AND id < {to:UInt64}",
+        )
+        .by_key("id", 1000);
+
+        for bounds in [
+            key_bounds(3, "Int64", u64::MAX - 4, 2),
+            key_bounds(3, "UInt64", 10, 2),
+        ] {
+            mock.add(test::handlers::record_ddl());
+            mock.add(test::handlers::provide(vec![bounds]));
+            assert!(matches!(
+                migrator.backfill(&options).await,
+                Err(Error::InvalidInput(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use ch::clickhouse;
+use clickhouse::sql::Identifier;
+
+use crate::{Error, Migrator};
+
+/// Engine of a database created by `Migrator::create_database`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum DatabaseEngine {
+    #[default]
+    Atomic,
+    /// `Replicated('/clickhouse/databases/<name>', '{shard}', '{replica}')`
+    Replicated,
+}
+
+/// Options for `Migrator::create_database`, defaults create an `Atomic` database on the
+/// connected server only.
+#[derive(Debug, Clone, Default)]
+pub struct CreateDatabaseOptions {
+    pub engine: DatabaseEngine,
+    pub cluster: Option<String>,
+}
+
+impl CreateDatabaseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn engine(mut self, engine: DatabaseEngine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Create the database `ON CLUSTER`
+    pub fn cluster(mut self, cluster: Option<String>) -> Self {
+        self.cluster = cluster;
+        self
+    }
+}
+
+impl Migrator {
+    /// Create `name` if it doesn't exist yet, so `ensure_migrations_table` can run on a
+    /// fresh server.
+    ///
+    /// The statement is sent with `system` as the session database: a client whose
+    /// default database is `name` can't run anything until it exists.
+    pub async fn create_database(
+        &self,
+        name: &str,
+        options: &CreateDatabaseOptions,
+    ) -> Result<(), Error> {
+        let mut sql = "CREATE DATABASE IF NOT EXISTS ?".to_string();
+        if options.cluster.is_some() {
+            sql.push_str(" ON CLUSTER ?");
+        }
+        sql.push_str(match options.engine {
+            DatabaseEngine::Atomic => " ENGINE = Atomic",
+            DatabaseEngine::Replicated => " ENGINE = Replicated(?, '{shard}', '{replica}')",
+        });
+
+        let client = (*self.inner).clone().with_database("system");
+        let mut query = client.query(&sql).bind(Identifier(name));
+        if let Some(cluster) = &options.cluster {
+            query = query.bind(Identifier(cluster));
+        }
+        if options.engine == DatabaseEngine::Replicated {
+            query = query.bind(format!("/clickhouse/databases/{}", name));
+        }
+        query.execute().await?;
+
+        tracing::debug!(database = name, "Ensured database exists");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_create_database() {
+        let mock = clickhouse::test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+
+        let recording = mock.add(clickhouse::test::handlers::record_ddl());
+        let options = CreateDatabaseOptions::new()
+            .engine(DatabaseEngine::Replicated)
+            .cluster(Some("main".to_string()));
+        migrator.create_database("app", &options).await.unwrap();
+
+        let query = recording.query().await;
+        assert!(query.contains("CREATE DATABASE IF NOT EXISTS `app` ON CLUSTER `main`"));
+        assert!(query.contains("Replicated('/clickhouse/databases/app', '{shard}', '{replica}')"));
+    }
+}
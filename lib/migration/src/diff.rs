@@ -0,0 +1,871 @@
+use std::collections::BTreeMap;
+
+use ch::clickhouse;
+
+use crate::audit::new_scratch_run_id;
+use crate::scratch::ScratchDatabase;
+use crate::{Error, MigrationFileMode, MigrationInfo, Migrator, fs, sql};
+
+/// What the desired schema is compared against.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum DiffBase {
+    /// A scratch database built by replaying every local migration
+    #[default]
+    Migrations,
+    /// The migrator's target database as it is right now
+    Live,
+}
+
+/// Statements that move the current schema to the desired one and back.
+#[derive(Debug, Clone, Default)]
+pub struct SchemaChanges {
+    pub up: Vec<String>,
+    pub down: Vec<String>,
+    /// Changes ClickHouse can't do in place; these are never turned into statements
+    pub warnings: Vec<String>,
+}
+
+impl SchemaChanges {
+    /// No statements to run. There may still be warnings.
+    pub fn is_empty(&self) -> bool {
+        self.up.is_empty()
+    }
+
+    pub fn up_sql(&self) -> String {
+        render(
+            "Generated by chutils migrate diff",
+            &self.warnings,
+            &self.up,
+        )
+    }
+
+    pub fn down_sql(&self) -> String {
+        render("Generated by chutils migrate diff", &[], &self.down)
+    }
+
+    /// Write the changes as the next reversible migration in `src`. Nothing is written
+    /// when there are no statements, a migration of warnings alone would do nothing.
+    pub async fn write_migration(&self, src: &str, name: &str) -> Result<Vec<String>, Error> {
+        if self.is_empty() {
+            return Ok(vec![]);
+        }
+        fs::gen_migration_file_with_content(
+            src,
+            name,
+            Some(MigrationFileMode::Reversible),
+            &self.up_sql(),
+            &self.down_sql(),
+        )
+        .await
+    }
+}
+
+fn render(header: &str, warnings: &[String], statements: &[String]) -> String {
+    let mut out = format!("-- {}\n", header);
+    for warning in warnings {
+        out.push_str(&format!("-- WARNING: {}\n", warning));
+    }
+    for stmt in statements {
+        out.push('\n');
+        out.push_str(stmt);
+        out.push_str(";\n");
+    }
+    out
+}
+
+impl Migrator {
+    /// Compare the `CREATE` statements in `schema_dir` with the current schema
+    /// and return the statements needed to reach it.
+    ///
+    /// The desired schema is materialised in a scratch database so ClickHouse
+    /// itself normalises types, defaults and keys before comparing.
+    pub async fn diff_schema(
+        &self,
+        src: &str,
+        schema_dir: &str,
+        base: DiffBase,
+    ) -> Result<SchemaChanges, Error> {
+        let schema_files = fs::list_sql_files(schema_dir).await?;
+        if schema_files.is_empty() {
+            return Err(Error::InvalidInput(format!(
+                "no schema files found in '{}'",
+                schema_dir
+            )));
+        }
+
+        let desired_db = ScratchDatabase::create(&self.inner, "desired").await?;
+        let desired = async {
+            let migrator = desired_db.migrator();
+            for file in &schema_files {
+                migrator.execute_file(file, None).await?;
+            }
+            load_schema(&migrator.inner).await
+        }
+        .await;
+        desired_db.drop().await?;
+        let desired = desired?;
+
+        let current = match base {
+            DiffBase::Live => load_schema(&self.inner).await?,
+            DiffBase::Migrations => {
+                let current_db = ScratchDatabase::create(&self.inner, "current").await?;
+                let current = async {
+                    let migrator = current_db.migrator();
+                    let run_id = new_scratch_run_id();
+                    for mf in fs::list_migrations_strict(src).await? {
+                        if mf.mode == MigrationFileMode::Reversible && !mf.is_up {
+                            continue;
+                        }
+                        let info: MigrationInfo = mf.into();
+                        migrator.execute_migration(&info, true, &run_id).await?;
+                    }
+                    load_schema(&migrator.inner).await
+                }
+                .await;
+                current_db.drop().await?;
+                current?
+            }
+        };
+
+        Ok(diff_schemas(&current, &desired))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TableSchema {
+    pub name: String,
+    pub engine: String,
+    /// `CREATE` statement without the database qualifier
+    pub create_query: String,
+    pub sorting_key: String,
+    pub partition_key: String,
+    pub primary_key: String,
+    pub ttl: Option<String>,
+    pub columns: Vec<ColumnSchema>,
+    pub indices: Vec<IndexSchema>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ColumnSchema {
+    pub name: String,
+    pub data_type: String,
+    pub default_kind: String,
+    pub default_expression: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct IndexSchema {
+    pub name: String,
+    pub expr: String,
+    pub type_full: String,
+    pub granularity: u64,
+}
+
+#[derive(clickhouse::Row, serde::Deserialize)]
+struct TableRow {
+    name: String,
+    engine: String,
+    engine_full: String,
+    create_table_query: String,
+    sorting_key: String,
+    partition_key: String,
+    primary_key: String,
+}
+
+#[derive(clickhouse::Row, serde::Deserialize)]
+struct ColumnRow {
+    table: String,
+    name: String,
+    r#type: String,
+    default_kind: String,
+    default_expression: String,
+    comment: String,
+}
+
+#[derive(clickhouse::Row, serde::Deserialize)]
+struct IndexRow {
+    table: String,
+    name: String,
+    expr: String,
+    type_full: String,
+    granularity: u64,
+}
+
+/// Load tables, columns and skipping indices of the client's current database.
+/// Internal tables (`_ch_*`) and materialized view inner tables are skipped.
+async fn load_schema(client: &clickhouse::Client) -> Result<BTreeMap<String, TableSchema>, Error> {
+    let db: String = client.query("SELECT currentDatabase()").fetch_one().await?;
+
+    let tables = client
+        .query(
+            "SELECT name, engine, engine_full, create_table_query, sorting_key, partition_key, primary_key
+            FROM system.tables
+            WHERE database = currentDatabase() AND NOT startsWith(name, '_ch_') AND NOT startsWith(name, '.inner')
+            ORDER BY name",
+        )
+        .fetch_all::<TableRow>()
+        .await?;
+
+    let columns = client
+        .query(
+            "SELECT table, name, type, default_kind, default_expression, comment
+            FROM system.columns
+            WHERE database = currentDatabase()
+            ORDER BY table, position",
+        )
+        .fetch_all::<ColumnRow>()
+        .await?;
+
+    let indices = client
+        .query(
+            "SELECT table, name, expr, type_full, granularity
+            FROM system.data_skipping_indices
+            WHERE database = currentDatabase()
+            ORDER BY table, name",
+        )
+        .fetch_all::<IndexRow>()
+        .await?;
+
+    let mut schema: BTreeMap<String, TableSchema> = tables
+        .into_iter()
+        .map(|t| {
+            let table = TableSchema {
+                ttl: extract_ttl(&t.engine_full),
+                create_query: strip_database(&t.create_table_query, &db),
+                name: t.name,
+                engine: t.engine,
+                sorting_key: t.sorting_key,
+                partition_key: t.partition_key,
+                primary_key: t.primary_key,
+                columns: vec![],
+                indices: vec![],
+            };
+            (table.name.clone(), table)
+        })
+        .collect();
+
+    for col in columns {
+        if let Some(table) = schema.get_mut(&col.table) {
+            table.columns.push(ColumnSchema {
+                name: col.name,
+                data_type: col.r#type,
+                default_kind: col.default_kind,
+                default_expression: col.default_expression,
+                comment: col.comment,
+            });
+        }
+    }
+
+    for idx in indices {
+        if let Some(table) = schema.get_mut(&idx.table) {
+            table.indices.push(IndexSchema {
+                name: idx.name,
+                expr: idx.expr,
+                type_full: idx.type_full,
+                granularity: idx.granularity,
+            });
+        }
+    }
+
+    Ok(schema)
+}
+
+/// Drop the `db.` / `` `db`. `` qualifier of identifiers. Other databases (`otherdb.t`),
+/// string literals and comments are left alone.
+pub(crate) fn strip_database(query: &str, db: &str) -> String {
+    map_qualifiers(query, |qualifier| qualifier == db)
+}
+
+/// Every `db` of a `db.name` identifier in `query`, outside string literals and comments.
+/// Table-qualified columns (`t.id`) are included, there's no telling them apart.
+pub(crate) fn database_qualifiers(query: &str) -> Vec<String> {
+    let mut qualifiers = vec![];
+    map_qualifiers(query, |qualifier| {
+        qualifiers.push(qualifier.to_string());
+        false
+    });
+    qualifiers
+}
+
+/// Copy `query`, dropping the qualifiers of `a.b` identifiers for which `strip` is true.
+fn map_qualifiers(query: &str, mut strip: impl FnMut(&str) -> bool) -> String {
+    let mut out = String::with_capacity(query.len());
+    let mut rest = query;
+    // Whether the previous token may be followed by a qualified name, i.e. isn't part of
+    // an identifier or a `a.b` path
+    let mut boundary = true;
+
+    while let Some(c) = rest.chars().next() {
+        let (token_len, name) = match c {
+            '\'' => (sql::quoted_len(rest, '\''), None),
+            '-' if rest.starts_with("--") => (rest.find('\n').unwrap_or(rest.len()), None),
+            '/' if rest.starts_with("/*") => (rest.find("*/").map_or(rest.len(), |i| i + 2), None),
+            '`' | '"' => {
+                let len = sql::quoted_len(rest, c);
+                let name = rest[..len].strip_prefix(c).and_then(|n| n.strip_suffix(c));
+                (len, name)
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (len, Some(&rest[..len]))
+            }
+            c => (c.len_utf8(), None),
+        };
+        if let Some(name) = name.filter(|_| boundary) {
+            if let Some(after) = rest[token_len..].strip_prefix('.') {
+                if strip(name) {
+                    rest = after;
+                    boundary = false;
+                    continue;
+                }
+            }
+        }
+        let (token, tail) = rest.split_at(token_len);
+        out.push_str(token);
+        boundary = !(c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '`' | '"'));
+        rest = tail;
+    }
+    out
+}
+
+/// `engine_full` looks like `MergeTree ORDER BY id TTL ts + toIntervalDay(1) SETTINGS ...`
+pub(crate) fn extract_ttl(engine_full: &str) -> Option<String> {
+    let (_, rest) = engine_full.split_once(" TTL ")?;
+    let ttl = match rest.split_once(" SETTINGS ") {
+        Some((ttl, _)) => ttl,
+        None => rest,
+    };
+    Some(ttl.trim().to_string())
+}
+
+fn is_view(engine: &str) -> bool {
+    matches!(
+        engine,
+        "View" | "MaterializedView" | "LiveView" | "WindowView"
+    )
+}
+
+pub(crate) fn diff_schemas(
+    current: &BTreeMap<String, TableSchema>,
+    desired: &BTreeMap<String, TableSchema>,
+) -> SchemaChanges {
+    let mut changes = SchemaChanges::default();
+
+    // Plain tables are created before views that may select from them
+    let mut created: Vec<&TableSchema> = desired
+        .values()
+        .filter(|t| !current.contains_key(&t.name))
+        .collect();
+    created.sort_by_key(|t| is_view(&t.engine));
+    for table in created {
+        changes.up.push(table.create_query.clone());
+        changes.down.push(drop_statement(table));
+    }
+
+    for (name, want) in desired {
+        let Some(have) = current.get(name) else {
+            continue;
+        };
+        diff_table(have, want, &mut changes);
+    }
+
+    let mut dropped: Vec<&TableSchema> = current
+        .values()
+        .filter(|t| !desired.contains_key(&t.name))
+        .collect();
+    dropped.sort_by_key(|t| !is_view(&t.engine));
+    for table in dropped {
+        changes.warnings.push(format!(
+            "`{}` is dropped together with its data",
+            table.name
+        ));
+        changes.up.push(drop_statement(table));
+        changes.down.push(table.create_query.clone());
+    }
+
+    // Down statements undo up statements in reverse order
+    changes.down.reverse();
+    changes
+}
+
+fn drop_statement(table: &TableSchema) -> String {
+    let kind = if is_view(&table.engine) {
+        "VIEW"
+    } else if table.engine == "Dictionary" {
+        "DICTIONARY"
+    } else {
+        "TABLE"
+    };
+    format!("DROP {} IF EXISTS {}", kind, ident(&table.name))
+}
+
+fn diff_table(have: &TableSchema, want: &TableSchema, changes: &mut SchemaChanges) {
+    let name = &want.name;
+    let table = ident(name);
+
+    if is_view(&have.engine) || is_view(&want.engine) {
+        if have.create_query != want.create_query {
+            changes.warnings.push(format!(
+                "definition of view `{}` changed; recreate it manually",
+                name
+            ));
+        }
+        return;
+    }
+
+    if have.engine != want.engine {
+        changes.warnings.push(format!(
+            "engine of `{}` changes from {} to {}; this requires recreating the table",
+            name, have.engine, want.engine
+        ));
+        return;
+    }
+
+    for (what, old, new) in [
+        ("ORDER BY", &have.sorting_key, &want.sorting_key),
+        ("PARTITION BY", &have.partition_key, &want.partition_key),
+        ("PRIMARY KEY", &have.primary_key, &want.primary_key),
+    ] {
+        if old != new {
+            changes.warnings.push(format!(
+                "{} of `{}` changes from '{}' to '{}'; this requires recreating the table",
+                what, name, old, new
+            ));
+        }
+    }
+
+    let have_cols: BTreeMap<_, _> = have.columns.iter().map(|c| (&c.name, c)).collect();
+    let want_cols: BTreeMap<_, _> = want.columns.iter().map(|c| (&c.name, c)).collect();
+
+    let mut prev: Option<&str> = None;
+    for col in &want.columns {
+        match have_cols.get(&col.name) {
+            None => {
+                let position = match prev {
+                    Some(prev) => format!(" AFTER {}", ident(prev)),
+                    None => " FIRST".to_string(),
+                };
+                changes.up.push(format!(
+                    "ALTER TABLE {} ADD COLUMN {}{}",
+                    table,
+                    column_definition(col),
+                    position
+                ));
+                changes.down.push(format!(
+                    "ALTER TABLE {} DROP COLUMN {}",
+                    table,
+                    ident(&col.name)
+                ));
+            }
+            Some(old) if *old != col => {
+                changes.up.push(format!(
+                    "ALTER TABLE {} MODIFY COLUMN {}",
+                    table,
+                    column_definition(col)
+                ));
+                changes.down.push(format!(
+                    "ALTER TABLE {} MODIFY COLUMN {}",
+                    table,
+                    column_definition(old)
+                ));
+            }
+            _ => {}
+        }
+        prev = Some(&col.name);
+    }
+
+    for col in &have.columns {
+        if !want_cols.contains_key(&col.name) {
+            changes.up.push(format!(
+                "ALTER TABLE {} DROP COLUMN {}",
+                table,
+                ident(&col.name)
+            ));
+            changes.down.push(format!(
+                "ALTER TABLE {} ADD COLUMN {}",
+                table,
+                column_definition(col)
+            ));
+        }
+    }
+
+    match (&have.ttl, &want.ttl) {
+        (old, Some(new)) if old.as_ref() != Some(new) => {
+            changes
+                .up
+                .push(format!("ALTER TABLE {} MODIFY TTL {}", table, new));
+            changes.down.push(match old {
+                Some(old) => format!("ALTER TABLE {} MODIFY TTL {}", table, old),
+                None => format!("ALTER TABLE {} REMOVE TTL", table),
+            });
+        }
+        (Some(old), None) => {
+            changes.up.push(format!("ALTER TABLE {} REMOVE TTL", table));
+            changes
+                .down
+                .push(format!("ALTER TABLE {} MODIFY TTL {}", table, old));
+        }
+        _ => {}
+    }
+
+    let have_idx: BTreeMap<_, _> = have.indices.iter().map(|i| (&i.name, i)).collect();
+    let want_idx: BTreeMap<_, _> = want.indices.iter().map(|i| (&i.name, i)).collect();
+
+    for (idx_name, idx) in &have_idx {
+        if want_idx.get(idx_name) != Some(idx) {
+            changes.up.push(format!(
+                "ALTER TABLE {} DROP INDEX {}",
+                table,
+                ident(idx_name)
+            ));
+            changes.down.push(add_index(&table, idx));
+        }
+    }
+
+    for (idx_name, idx) in &want_idx {
+        if have_idx.get(idx_name) != Some(idx) {
+            changes.up.push(add_index(&table, idx));
+            changes.down.push(format!(
+                "ALTER TABLE {} DROP INDEX {}",
+                table,
+                ident(idx_name)
+            ));
+            changes.warnings.push(format!(
+                "index `{}` on `{}` only covers new parts; run `ALTER TABLE {} MATERIALIZE INDEX {}` to build it for existing data",
+                idx_name,
+                name,
+                table,
+                ident(idx_name)
+            ));
+        }
+    }
+}
+
+fn add_index(table: &str, idx: &IndexSchema) -> String {
+    format!(
+        "ALTER TABLE {} ADD INDEX {} {} TYPE {} GRANULARITY {}",
+        table,
+        ident(&idx.name),
+        idx.expr,
+        idx.type_full,
+        idx.granularity
+    )
+}
+
+fn column_definition(col: &ColumnSchema) -> String {
+    let mut def = format!("{} {}", ident(&col.name), col.data_type);
+    if !col.default_kind.is_empty() {
+        def.push_str(&format!(" {} {}", col.default_kind, col.default_expression));
+    }
+    if !col.comment.is_empty() {
+        def.push_str(&format!(" COMMENT {}", string_literal(&col.comment)));
+    }
+    def
+}
+
+/// `name` quoted as an identifier.
+fn ident(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+/// `value` quoted as a string literal.
+fn string_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, data_type: &str) -> ColumnSchema {
+        ColumnSchema {
+            name: name.to_string(),
+            data_type: data_type.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn users(columns: Vec<ColumnSchema>) -> TableSchema {
+        TableSchema {
+            name: "users".to_string(),
+            engine: "MergeTree".to_string(),
+            create_query: "CREATE TABLE users (id UInt64) ENGINE = MergeTree ORDER BY id"
+                .to_string(),
+            sorting_key: "id".to_string(),
+            primary_key: "id".to_string(),
+            columns,
+            ..Default::default()
+        }
+    }
+
+    fn schema(tables: Vec<TableSchema>) -> BTreeMap<String, TableSchema> {
+        tables.into_iter().map(|t| (t.name.clone(), t)).collect()
+    }
+
+    #[test]
+    fn test_extract_ttl() {
+        assert_eq!(
+            extract_ttl(
+                "MergeTree ORDER BY id TTL ts + toIntervalDay(7) SETTINGS index_granularity = 8192"
+            ),
+            Some("ts + toIntervalDay(7)".to_string())
+        );
+        assert_eq!(
+            extract_ttl("MergeTree ORDER BY id TTL ts + toIntervalDay(7)"),
+            Some("ts + toIntervalDay(7)".to_string())
+        );
+        assert_eq!(
+            extract_ttl("MergeTree ORDER BY id SETTINGS index_granularity = 8192"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_strip_database() {
+        assert_eq!(
+            strip_database("CREATE TABLE scratch.users (id UInt64)", "scratch"),
+            "CREATE TABLE users (id UInt64)"
+        );
+        assert_eq!(
+            strip_database("CREATE TABLE `scratch`.users (id UInt64)", "scratch"),
+            "CREATE TABLE users (id UInt64)"
+        );
+        assert_eq!(
+            strip_database(
+                "CREATE VIEW db.v AS SELECT * FROM otherdb.x JOIN db.y USING id JOIN x.db.z USING id",
+                "db"
+            ),
+            "CREATE VIEW v AS SELECT * FROM otherdb.x JOIN y USING id JOIN x.db.z USING id"
+        );
+        assert_eq!(
+            strip_database(
+                "INSERT INTO db.log VALUES ('db.x', 'it''s db.y') -- copies db.x\n/* db.z */",
+                "db"
+            ),
+            "INSERT INTO log VALUES ('db.x', 'it''s db.y') -- copies db.x\n/* db.z */"
+        );
+        assert_eq!(
+            strip_database("SELECT `db_2`.t, \"db\".t, db_2.t", "db"),
+            "SELECT `db_2`.t, t, db_2.t"
+        );
+    }
+
+    #[test]
+    fn test_database_qualifiers() {
+        assert_eq!(
+            database_qualifiers(
+                "INSERT INTO `analytics`.events SELECT u.id FROM users AS u -- from other.x\nWHERE s = 'prod.y'"
+            ),
+            ["analytics", "u"]
+        );
+    }
+
+    #[test]
+    fn test_diff_no_changes() {
+        let current = schema(vec![users(vec![column("id", "UInt64")])]);
+        let changes = diff_schemas(&current, &current.clone());
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn test_diff_new_table() {
+        let current = schema(vec![]);
+        let desired = schema(vec![users(vec![column("id", "UInt64")])]);
+
+        let changes = diff_schemas(&current, &desired);
+        assert_eq!(changes.up.len(), 1);
+        assert!(changes.up[0].starts_with("CREATE TABLE users"));
+        assert_eq!(changes.down, vec!["DROP TABLE IF EXISTS `users`"]);
+    }
+
+    #[test]
+    fn test_diff_dropped_table_is_flagged() {
+        let current = schema(vec![users(vec![column("id", "UInt64")])]);
+        let desired = schema(vec![]);
+
+        let changes = diff_schemas(&current, &desired);
+        assert_eq!(changes.up, vec!["DROP TABLE IF EXISTS `users`"]);
+        assert!(changes.down[0].starts_with("CREATE TABLE users"));
+        assert_eq!(changes.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_diff_add_drop_modify_column() {
+        let current = schema(vec![users(vec![
+            column("id", "UInt64"),
+            column("name", "String"),
+            column("legacy", "String"),
+        ])]);
+        let mut email = column("email", "String");
+        email.default_kind = "DEFAULT".to_string();
+        email.default_expression = "''".to_string();
+        let desired = schema(vec![users(vec![
+            column("id", "UInt64"),
+            column("name", "LowCardinality(String)"),
+            email,
+        ])]);
+
+        let changes = diff_schemas(&current, &desired);
+        assert_eq!(
+            changes.up,
+            vec![
+                "ALTER TABLE `users` MODIFY COLUMN `name` LowCardinality(String)",
+                "ALTER TABLE `users` ADD COLUMN `email` String DEFAULT '' AFTER `name`",
+                "ALTER TABLE `users` DROP COLUMN `legacy`",
+            ]
+        );
+        assert_eq!(
+            changes.down,
+            vec![
+                "ALTER TABLE `users` ADD COLUMN `legacy` String",
+                "ALTER TABLE `users` DROP COLUMN `email`",
+                "ALTER TABLE `users` MODIFY COLUMN `name` String",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_ttl() {
+        let current = schema(vec![users(vec![column("id", "UInt64")])]);
+        let mut with_ttl = users(vec![column("id", "UInt64")]);
+        with_ttl.ttl = Some("ts + toIntervalDay(7)".to_string());
+        let desired = schema(vec![with_ttl]);
+
+        let changes = diff_schemas(&current, &desired);
+        assert_eq!(
+            changes.up,
+            vec!["ALTER TABLE `users` MODIFY TTL ts + toIntervalDay(7)"]
+        );
+        assert_eq!(changes.down, vec!["ALTER TABLE `users` REMOVE TTL"]);
+
+        let changes = diff_schemas(&desired, &current);
+        assert_eq!(changes.up, vec!["ALTER TABLE `users` REMOVE TTL"]);
+    }
+
+    #[test]
+    fn test_diff_add_index() {
+        let current = schema(vec![users(vec![column("id", "UInt64")])]);
+        let mut indexed = users(vec![column("id", "UInt64")]);
+        indexed.indices.push(IndexSchema {
+            name: "idx_id".to_string(),
+            expr: "id".to_string(),
+            type_full: "minmax".to_string(),
+            granularity: 4,
+        });
+        let desired = schema(vec![indexed]);
+
+        let changes = diff_schemas(&current, &desired);
+        assert_eq!(
+            changes.up,
+            vec!["ALTER TABLE `users` ADD INDEX `idx_id` id TYPE minmax GRANULARITY 4"]
+        );
+        assert_eq!(
+            changes.down,
+            vec!["ALTER TABLE `users` DROP INDEX `idx_id`"]
+        );
+        assert_eq!(changes.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_diff_sorting_key_change_is_flagged_not_generated() {
+        let current = schema(vec![users(vec![column("id", "UInt64")])]);
+        let mut resorted = users(vec![column("id", "UInt64")]);
+        resorted.sorting_key = "id, ts".to_string();
+        let desired = schema(vec![resorted]);
+
+        let changes = diff_schemas(&current, &desired);
+        assert!(changes.is_empty());
+        assert_eq!(changes.warnings.len(), 1);
+        assert!(changes.warnings[0].contains("ORDER BY"));
+    }
+
+    #[tokio::test]
+    async fn test_write_migration_skips_warnings_only() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let changes = SchemaChanges {
+            warnings: vec!["ORDER BY changed".to_string()],
+            ..Default::default()
+        };
+        let written = changes
+            .write_migration(temp_dir.path().to_str().unwrap(), "resort")
+            .await
+            .unwrap();
+        assert!(written.is_empty());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_diff_schema_keeps_qualified_statements_in_scratch() {
+        use ch::clickhouse::test;
+
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(
+            temp_dir.path().join("01_users.sql"),
+            "CREATE TABLE analytics.users (id UInt64) ENGINE = MergeTree ORDER BY id",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            temp_dir.path().join("02_invoices.sql"),
+            "DROP TABLE billing.invoices",
+        )
+        .await
+        .unwrap();
+
+        mock.add(test::handlers::provide(vec!["analytics".to_string()]));
+        mock.add(test::handlers::provide(vec![
+            "analytics".to_string(),
+            "billing".to_string(),
+        ]));
+        mock.add(test::handlers::record_ddl());
+        let create = mock.add(test::handlers::record_ddl());
+        let drop = mock.add(test::handlers::record_ddl());
+
+        let schema_dir = temp_dir.path().to_str().unwrap();
+        assert!(matches!(
+            migrator.diff_schema(schema_dir, schema_dir, DiffBase::Live).await,
+            Err(Error::InvalidInput(msg)) if msg.contains("billing")
+        ));
+        assert!(create.query().await.starts_with("CREATE TABLE users "));
+        assert!(drop.query().await.starts_with("DROP DATABASE"));
+    }
+
+    #[test]
+    fn test_diff_engine_change_is_flagged_not_generated() {
+        let current = schema(vec![users(vec![column("id", "UInt64")])]);
+        let mut replacing = users(vec![column("id", "UInt64"), column("v", "UInt64")]);
+        replacing.engine = "ReplacingMergeTree".to_string();
+        let desired = schema(vec![replacing]);
+
+        let changes = diff_schemas(&current, &desired);
+        assert!(changes.up.is_empty());
+        assert!(changes.warnings[0].contains("engine"));
+    }
+
+    #[test]
+    fn test_column_definition_quotes() {
+        let mut col = column("odd `name`", "String");
+        col.comment = r"it's C:\temp".to_string();
+        assert_eq!(
+            column_definition(&col),
+            r"`odd \`name\`` String COMMENT 'it\'s C:\\temp'"
+        );
+    }
+
+    #[test]
+    fn test_rendered_up_sql_contains_warnings() {
+        let changes = SchemaChanges {
+            up: vec!["ALTER TABLE `users` DROP COLUMN `legacy`".to_string()],
+            down: vec![],
+            warnings: vec!["something odd".to_string()],
+        };
+        let sql = changes.up_sql();
+        assert!(sql.contains("-- WARNING: something odd"));
+        assert!(sql.contains("ALTER TABLE `users` DROP COLUMN `legacy`;"));
+    }
+}
@@ -1,14 +1,130 @@
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("Migration Corrupted: {0}")]
-    MigrationCorrupted(String),
+    #[error(
+        "Migration out of order: pending version {pending} is older than latest applied version {latest_applied}"
+    )]
+    OutOfOrder { pending: u32, latest_applied: u32 },
+
+    #[error(
+        "Migration name mismatch for version {version}: '{db_name}' in db, '{local_name}' in local"
+    )]
+    NameMismatch {
+        version: u32,
+        db_name: String,
+        local_name: String,
+    },
+
+    #[error("Migration {name} (version={version}) is existing in db but not found in local")]
+    MissingLocal { version: u32, name: String },
+
+    #[error(
+        "Baseline {version} squashes versions {from}-{version} but the database only has some of them applied (missing {missing:?}), apply the archived migrations first"
+    )]
+    PartialBaseline {
+        version: u32,
+        from: u32,
+        missing: Vec<u32>,
+    },
+
+    #[error("Statement {} of {file} (version={version}) failed: {source}", .statement_index + 1)]
+    StatementFailed {
+        version: u32,
+        file: String,
+        /// 0-based position of the statement in the file
+        statement_index: usize,
+        source: ch::ClickhouseError,
+    },
+
+    #[error("Hook {hook} failed{}: {message}", .version.map(|v| format!(" for version {}", v)).unwrap_or_default())]
+    HookFailed {
+        /// Hook file and line, or the command that failed
+        hook: String,
+        /// Migration the hook ran for, unset for `before_all`/`after_all`
+        version: Option<u32>,
+        message: String,
+    },
+
+    #[error("Migration history is locked: {0}")]
+    Locked(String),
 
     #[error("Invalid Input: {0}")]
     InvalidInput(String),
 
+    #[error("Invalid migration set: {}", .0.iter().map(|i| i.to_string()).collect::<Vec<_>>().join("; "))]
+    InvalidMigrationSet(Vec<LayoutIssue>),
+
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 
     #[error(transparent)]
     ClickhouseError(#[from] ch::ClickhouseError),
 }
+
+impl Error {
+    /// Stable identifier of the error kind, safe to match on in tooling.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::OutOfOrder { .. } => "out_of_order",
+            Self::NameMismatch { .. } => "name_mismatch",
+            Self::MissingLocal { .. } => "missing_local",
+            Self::PartialBaseline { .. } => "partial_baseline",
+            Self::StatementFailed { .. } => "statement_failed",
+            Self::HookFailed { .. } => "hook_failed",
+            Self::Locked(_) => "locked",
+            Self::InvalidInput(_) => "invalid_input",
+            Self::InvalidMigrationSet(_) => "invalid_migration_set",
+            Self::IoError(_) => "io",
+            Self::ClickhouseError(_) => "clickhouse",
+        }
+    }
+}
+
+/// A problem with the files in the migration directory itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutIssue {
+    /// Several migrations with different names share one version
+    DuplicateVersion { version: u32, files: Vec<String> },
+    /// Only one half of a reversible migration exists
+    OrphanHalf {
+        version: u32,
+        file: String,
+        missing: String,
+    },
+    /// The same version exists both as a simple and a reversible migration
+    MixedModes { version: u32, files: Vec<String> },
+    /// A file looks like a migration but its name can't be parsed
+    UnparseableName { file: String },
+    /// Versions are not consecutive, only logged as a warning
+    Gap { after: u32, next: u32 },
+}
+
+impl std::fmt::Display for LayoutIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DuplicateVersion { version, files } => {
+                write!(f, "duplicate version {}: {}", version, files.join(", "))
+            }
+            Self::OrphanHalf {
+                version,
+                file,
+                missing,
+            } => write!(
+                f,
+                "version {} has {} but is missing {}",
+                version, file, missing
+            ),
+            Self::MixedModes { version, files } => write!(
+                f,
+                "version {} mixes simple and reversible files: {}",
+                version,
+                files.join(", ")
+            ),
+            Self::UnparseableName { file } => {
+                write!(f, "{} is not a valid migration file name", file)
+            }
+            Self::Gap { after, next } => {
+                write!(f, "gap in versions between {} and {}", after, next)
+            }
+        }
+    }
+}
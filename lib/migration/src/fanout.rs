@@ -0,0 +1,316 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ch::ClickhouseExtension;
+use futures::StreamExt;
+
+use crate::{Error, Migration, MigrationInfo, Migrator, RunOptions};
+
+/// Result of applying the migrations to one target (a server or a database) of a fan-out.
+#[derive(Debug)]
+pub struct TargetReport {
+    /// Label the target was given, e.g. its URL or database name
+    pub target: String,
+    pub outcome: TargetOutcome,
+}
+
+#[derive(Debug)]
+pub enum TargetOutcome {
+    /// Pending migrations were applied (or listed, on a dry run)
+    Applied(Vec<MigrationInfo>),
+    /// Nothing was pending
+    UpToDate,
+    /// The target was left at its last successfully applied migration
+    Failed(Error),
+    /// Not attempted because another target failed under `FailurePolicy::Stop`
+    Skipped,
+}
+
+/// What a fan-out does with the remaining targets once one fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum FailurePolicy {
+    /// Keep migrating the other targets
+    #[default]
+    Continue,
+    /// Don't start any new target, the ones already running finish
+    Stop,
+}
+
+/// Options for `run_targets`, defaults migrate one target at a time and continue on failure.
+#[derive(Debug, Clone)]
+pub struct FanOutOptions {
+    pub parallelism: usize,
+    pub on_failure: FailurePolicy,
+}
+
+impl Default for FanOutOptions {
+    fn default() -> Self {
+        Self {
+            parallelism: 1,
+            on_failure: FailurePolicy::default(),
+        }
+    }
+}
+
+impl FanOutOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum number of targets migrated concurrently
+    pub fn parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    pub fn on_failure(mut self, on_failure: FailurePolicy) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+}
+
+impl TargetReport {
+    pub fn is_failed(&self) -> bool {
+        matches!(self.outcome, TargetOutcome::Failed(_))
+    }
+}
+
+impl std::fmt::Display for TargetOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Applied(migs) => write!(f, "applied {} migration(s)", migs.len()),
+            Self::UpToDate => write!(f, "up to date"),
+            Self::Failed(err) => write!(f, "failed: {}", err),
+            Self::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// Apply the migrations of `src` to every `(label, migrator)` target.
+///
+/// Each target is migrated independently with its own history table: a failure stops
+/// that target only, and the others carry on or are skipped according to
+/// `FanOutOptions::on_failure`. Reports are in the order of `targets`.
+pub async fn run_targets(
+    targets: &[(String, Migrator)],
+    src: &str,
+    options: RunOptions,
+    fan_out: FanOutOptions,
+) -> Vec<TargetReport> {
+    let stop = AtomicBool::new(false);
+    let mut reports: Vec<(usize, TargetReport)> = futures::stream::iter(targets.iter().enumerate())
+        .map(|(index, (target, migrator))| {
+            let options = options.clone();
+            let stop = &stop;
+            async move {
+                let outcome = if stop.load(Ordering::SeqCst) {
+                    TargetOutcome::Skipped
+                } else {
+                    let result = match migrator.ensure_migrations_table().await {
+                        Ok(()) => migrator.run(src, options).await,
+                        Err(err) => Err(err),
+                    };
+                    match result {
+                        Ok(applied) if applied.is_empty() => TargetOutcome::UpToDate,
+                        Ok(applied) => TargetOutcome::Applied(applied),
+                        Err(err) => {
+                            if fan_out.on_failure == FailurePolicy::Stop {
+                                stop.store(true, Ordering::SeqCst);
+                            }
+                            TargetOutcome::Failed(err)
+                        }
+                    }
+                };
+                let report = TargetReport {
+                    target: target.clone(),
+                    outcome,
+                };
+                (index, report)
+            }
+        })
+        .buffer_unordered(fan_out.parallelism.max(1))
+        .collect()
+        .await;
+    reports.sort_by_key(|(index, _)| *index);
+    reports.into_iter().map(|(_, report)| report).collect()
+}
+
+impl Migrator {
+    /// The same migrator (observer and hooks included) pointed at `database`.
+    /// History is kept in that database's own `_ch_migrations` table: a store set with
+    /// `with_history` isn't carried over, since it would be shared by every database.
+    pub fn for_database(&self, database: &str) -> Self {
+        Self {
+            inner: std::sync::Arc::new((*self.inner).clone().with_database(database)),
+            history: None,
+            ..self.clone()
+        }
+    }
+
+    /// Resolve `selectors` to database names. Entries containing `*` or `?` are
+    /// matched against the server's databases, others are taken as-is.
+    pub async fn discover_databases(&self, selectors: &[String]) -> Result<Vec<String>, Error> {
+        let mut databases = vec![];
+        let mut existing = None;
+        for selector in selectors {
+            if !selector.contains(['*', '?']) {
+                databases.push(selector.clone());
+                continue;
+            }
+            if existing.is_none() {
+                existing = Some(self.inner.list_databases().await?);
+            }
+            databases.extend(
+                existing
+                    .iter()
+                    .flatten()
+                    .filter(|db| glob_match(selector, db))
+                    .cloned(),
+            );
+        }
+        databases.sort();
+        databases.dedup();
+        Ok(databases)
+    }
+
+    /// Apply the migrations of `src` to every database in `databases` of this server,
+    /// see `run_targets`.
+    pub async fn run_fan_out(
+        &self,
+        src: &str,
+        databases: &[String],
+        options: RunOptions,
+        fan_out: FanOutOptions,
+    ) -> Vec<TargetReport> {
+        let targets: Vec<_> = databases
+            .iter()
+            .map(|db| (db.clone(), self.for_database(db)))
+            .collect();
+        run_targets(&targets, src, options, fan_out).await
+    }
+}
+
+/// Shell-style matching with `*` (any run of characters) and `?` (one character).
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` and the name position it was tried at
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    p = bp;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ch::clickhouse;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("tenant_*", "tenant_0001"));
+        assert!(glob_match("tenant_*", "tenant_"));
+        assert!(glob_match("tenant_000?", "tenant_0001"));
+        assert!(glob_match("*_eu", "tenant_0001_eu"));
+        assert!(glob_match("t*_*1", "tenant_0001"));
+        assert!(!glob_match("tenant_*", "analytics"));
+        assert!(!glob_match("tenant_000?", "tenant_00010"));
+        assert!(!glob_match("tenant", "tenant_0001"));
+    }
+
+    #[tokio::test]
+    async fn test_discover_databases() {
+        let mock = clickhouse::test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+
+        mock.add(clickhouse::test::handlers::provide(vec![
+            "analytics".to_string(),
+            "tenant_0001".to_string(),
+            "tenant_0002".to_string(),
+        ]));
+
+        let databases = migrator
+            .discover_databases(&["tenant_*".to_string(), "shared".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(databases, vec!["shared", "tenant_0001", "tenant_0002"]);
+    }
+
+    #[test]
+    fn test_for_database_uses_own_history() {
+        let migrator = Migrator::from_client(clickhouse::Client::default())
+            .with_history(crate::MemoryHistory::new());
+        assert!(migrator.for_database("tenant_0001").history.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_fan_out_isolates_failures() {
+        use clickhouse::test::{handlers, status};
+
+        let mock = clickhouse::test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        // tenant_a cannot create its history table, tenant_b has nothing to apply
+        mock.add(handlers::failure(status::BAD_REQUEST));
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::provide::<MigrationInfo>(vec![]));
+
+        let databases = vec!["tenant_a".to_string(), "tenant_b".to_string()];
+        let reports = migrator
+            .run_fan_out(src, &databases, RunOptions::new(), FanOutOptions::new())
+            .await;
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0].is_failed());
+        assert!(matches!(reports[1].outcome, TargetOutcome::UpToDate));
+    }
+
+    #[tokio::test]
+    async fn test_run_targets_stop_on_failure() {
+        use clickhouse::test::{handlers, status};
+
+        let mock = clickhouse::test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        mock.add(handlers::failure(status::BAD_REQUEST));
+
+        let targets = vec![
+            ("eu".to_string(), migrator.clone()),
+            ("us".to_string(), migrator),
+        ];
+        let reports = run_targets(
+            &targets,
+            src,
+            RunOptions::new(),
+            FanOutOptions::new().on_failure(FailurePolicy::Stop),
+        )
+        .await;
+        assert_eq!(reports[0].target, "eu");
+        assert!(reports[0].is_failed());
+        assert!(matches!(reports[1].outcome, TargetOutcome::Skipped));
+    }
+}
@@ -5,6 +5,46 @@ pub async fn gen_migration_file(
     name: &str,
     mode: Option<crate::MigrationFileMode>,
 ) -> Result<Vec<String>, crate::Error> {
+    let (src, name, seq, mode) = prepare_migration(src, name, mode).await?;
+
+    let vars = TemplateVars {
+        version: seq,
+        name: &name,
+        author: &git_author(src).await,
+        created_at: chrono::Utc::now(),
+    };
+    let (up, down) = match mode {
+        crate::MigrationFileMode::Reversible => (
+            load_template(src, ".template.up.sql").await?,
+            load_template(src, ".template.down.sql").await?,
+        ),
+        crate::MigrationFileMode::Simple => (load_template(src, ".template.sql").await?, None),
+    };
+    let up = up.map(|t| render_template(&t, &vars)).unwrap_or_default();
+    let down = down.map(|t| render_template(&t, &vars)).unwrap_or_default();
+
+    write_migration_files(src, seq, &name, mode, &up, &down).await
+}
+
+/// Same as `gen_migration_file` but fills the new files with `up` and `down`
+/// instead of the project templates. Simple migrations only receive `up`.
+pub async fn gen_migration_file_with_content(
+    src: &str,
+    name: &str,
+    mode: Option<crate::MigrationFileMode>,
+    up: &str,
+    down: &str,
+) -> Result<Vec<String>, crate::Error> {
+    let (src, name, seq, mode) = prepare_migration(src, name, mode).await?;
+    write_migration_files(src, seq, &name, mode, up, down).await
+}
+
+/// Sanitize the name and pick the next version and the mode of a new migration.
+async fn prepare_migration<'a>(
+    src: &'a str,
+    name: &str,
+    mode: Option<crate::MigrationFileMode>,
+) -> Result<(&'a str, String, u32, crate::MigrationFileMode), crate::Error> {
     let src = src.strip_suffix("/").unwrap_or(src);
 
     let name = sanitize_name(name);
@@ -25,20 +65,81 @@ pub async fn gen_migration_file(
             _ => (1, crate::MigrationFileMode::Simple),
         },
     };
+    Ok((src, name, seq, mode))
+}
 
-    let filenames = match mode {
+async fn write_migration_files(
+    src: &str,
+    seq: u32,
+    name: &str,
+    mode: crate::MigrationFileMode,
+    up: &str,
+    down: &str,
+) -> Result<Vec<String>, crate::Error> {
+    let files = match mode {
         crate::MigrationFileMode::Reversible => vec![
-            build_file_path(src, seq, &name, mode, true),
-            build_file_path(src, seq, &name, mode, false),
+            (build_file_path(src, seq, name, mode, true), up),
+            (build_file_path(src, seq, name, mode, false), down),
         ],
-        crate::MigrationFileMode::Simple => vec![build_file_path(src, seq, &name, mode, false)],
+        crate::MigrationFileMode::Simple => {
+            vec![(build_file_path(src, seq, name, mode, false), up)]
+        }
     };
 
-    for filename in &filenames {
-        tokio::fs::write(filename, b"").await?;
+    for (filename, content) in &files {
+        tokio::fs::write(filename, content.as_bytes()).await?;
+    }
+
+    Ok(files.into_iter().map(|(filename, _)| filename).collect())
+}
+
+/// Values substituted into `.template*.sql` files.
+struct TemplateVars<'a> {
+    version: u32,
+    name: &'a str,
+    author: &'a str,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+async fn load_template(src: &str, file: &str) -> Result<Option<String>, crate::Error> {
+    match tokio::fs::read(format!("{}/{}", src, file)).await {
+        Ok(raw) => Ok(Some(String::from_utf8_lossy(&raw).into_owned())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
     }
+}
 
-    Ok(filenames)
+/// Replace `{{version}}`, `{{name}}`, `{{author}}` and `{{created_at}}`.
+fn render_template(template: &str, vars: &TemplateVars) -> String {
+    template
+        .replace("{{version}}", &format!("{:04}", vars.version))
+        .replace("{{name}}", vars.name)
+        .replace("{{author}}", vars.author)
+        .replace("{{created_at}}", &vars.created_at.to_rfc3339())
+}
+
+/// `user.name <user.email>` from git config, falling back to the OS user.
+async fn git_author(dir: &str) -> String {
+    async fn git_config(dir: &str, key: &str) -> Option<String> {
+        let output = tokio::process::Command::new("git")
+            .args(["config", key])
+            .current_dir(dir)
+            .output()
+            .await
+            .ok()?;
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !value.is_empty()).then_some(value)
+    }
+
+    match (
+        git_config(dir, "user.name").await,
+        git_config(dir, "user.email").await,
+    ) {
+        (Some(name), Some(email)) => format!("{} <{}>", name, email),
+        (Some(name), None) => name,
+        (None, Some(email)) => email,
+        (None, None) => crate::current_user(),
+    }
 }
 
 pub fn build_file_path(
@@ -86,6 +187,207 @@ pub async fn list_migrations(src: &str) -> Result<Vec<crate::MigrationFile>, cra
     Ok(files)
 }
 
+/// First line of a baseline migration written by `Migrator::squash`.
+pub const BASELINE_MARKER: &str = "-- chutils:baseline";
+
+/// Hex SHA-256 of a migration file, recorded in the history when it's applied.
+pub async fn checksum(path: &str) -> Result<String, crate::Error> {
+    use sha2::Digest;
+
+    let content = tokio::fs::read(path).await?;
+    Ok(format!("{:x}", sha2::Sha256::digest(&content)))
+}
+
+/// Versions squashed into a baseline migration, read from its `BASELINE_MARKER` header
+/// (`-- chutils:baseline from=1 to=12`).
+pub async fn baseline_range(
+    path: &str,
+) -> Result<Option<std::ops::RangeInclusive<u32>>, crate::Error> {
+    let raw = tokio::fs::read(path).await?;
+    let content = String::from_utf8_lossy(&raw);
+    Ok(parse_baseline_marker(
+        content.lines().next().unwrap_or_default(),
+    ))
+}
+
+fn parse_baseline_marker(line: &str) -> Option<std::ops::RangeInclusive<u32>> {
+    let mut from = None;
+    let mut to = None;
+    for part in line.strip_prefix(BASELINE_MARKER)?.split_whitespace() {
+        match part.split_once('=') {
+            Some(("from", v)) => from = v.parse().ok(),
+            Some(("to", v)) => to = v.parse().ok(),
+            _ => {}
+        }
+    }
+    Some(from?..=to?)
+}
+
+/// List every `*.sql` file directly under `dir`, sorted by file name.
+pub async fn list_sql_files(dir: &str) -> Result<Vec<String>, crate::Error> {
+    let mut it = tokio::fs::read_dir(dir).await?;
+    let mut files = vec![];
+    while let Some(entry) = it.next_entry().await? {
+        let path = entry.path();
+        if entry
+            .file_type()
+            .await
+            .map(|e| e.is_file())
+            .unwrap_or_default()
+            && path.extension().and_then(|e| e.to_str()) == Some("sql")
+        {
+            files.push(path.display().to_string());
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Rename every `(from, to)` pair, refusing up front when a destination exists. When a
+/// rename fails, the ones already done are undone before returning the error.
+pub(crate) async fn rename_all(moves: &[(String, String)]) -> Result<(), crate::Error> {
+    for (from, to) in moves {
+        if tokio::fs::try_exists(to).await? {
+            return Err(crate::Error::InvalidInput(format!(
+                "cannot rename {} to {}: file exists",
+                from, to
+            )));
+        }
+    }
+
+    for (i, (from, to)) in moves.iter().enumerate() {
+        if let Err(err) = tokio::fs::rename(from, to).await {
+            for (from, to) in moves[..i].iter().rev() {
+                if let Err(err) = tokio::fs::rename(to, from).await {
+                    tracing::error!(error = %err, from = %to, to = %from, "Failed to undo rename");
+                }
+            }
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+/// Like `list_migrations` but rejects directories with duplicate versions,
+/// orphan up/down halves, mixed modes or unparseable names. Gaps between versions, e.g.
+/// left by a migration removed before it was applied anywhere, are only logged.
+pub async fn list_migrations_strict(src: &str) -> Result<Vec<crate::MigrationFile>, crate::Error> {
+    let mut it = tokio::fs::read_dir(src).await?;
+    let mut files = vec![];
+    let mut issues = vec![];
+    while let Some(entry) = it.next_entry().await? {
+        if !entry
+            .file_type()
+            .await
+            .map(|e| e.is_file())
+            .unwrap_or_default()
+        {
+            continue;
+        }
+
+        let path = entry.path();
+        match parse_migration_file(src, &path) {
+            Some(file) => files.push(file),
+            None if looks_like_migration(&path) => {
+                issues.push(crate::LayoutIssue::UnparseableName {
+                    file: path.display().to_string(),
+                });
+            }
+            None => {}
+        }
+    }
+
+    files.sort_unstable_by(|a, b| (a.seq_num, &a.path).cmp(&(b.seq_num, &b.path)));
+    issues.sort_by_key(|i| i.to_string());
+    for issue in check_layout(&files) {
+        match issue {
+            crate::LayoutIssue::Gap { .. } => tracing::warn!(src, "{}", issue),
+            _ => issues.push(issue),
+        }
+    }
+
+    if !issues.is_empty() {
+        return Err(crate::Error::InvalidMigrationSet(issues));
+    }
+    Ok(files)
+}
+
+/// Any `*.sql` file, or a file starting with `NNNN_` regardless of its extension.
+/// Hidden files and hook files are never considered.
+fn looks_like_migration(path: &Path) -> bool {
+    let Some(filename) = path.file_name().and_then(|f| f.to_str()) else {
+        return false;
+    };
+    if filename.starts_with('.')
+        || crate::HookPoint::ALL
+            .iter()
+            .any(|h| h.file_name() == filename)
+    {
+        return false;
+    }
+
+    let is_sql = path.extension().and_then(|e| e.to_str()) == Some("sql");
+    let has_seq = filename
+        .split_once('_')
+        .map(|(seq, _)| !seq.is_empty() && seq.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or_default();
+    is_sql || has_seq
+}
+
+fn check_layout(files: &[crate::MigrationFile]) -> Vec<crate::LayoutIssue> {
+    use crate::{LayoutIssue, MigrationFileMode};
+    use std::collections::BTreeMap;
+
+    let mut by_version: BTreeMap<u32, Vec<&crate::MigrationFile>> = BTreeMap::new();
+    for file in files {
+        by_version.entry(file.seq_num).or_default().push(file);
+    }
+
+    let mut issues = vec![];
+    let mut prev: Option<u32> = None;
+    for (&version, group) in &by_version {
+        if let Some(prev) = prev {
+            if version != prev + 1 {
+                issues.push(LayoutIssue::Gap {
+                    after: prev,
+                    next: version,
+                });
+            }
+        }
+        prev = Some(version);
+
+        let paths = || group.iter().map(|f| f.path.clone()).collect::<Vec<_>>();
+
+        if group.iter().any(|f| f.name != group[0].name) {
+            issues.push(LayoutIssue::DuplicateVersion {
+                version,
+                files: paths(),
+            });
+            continue;
+        }
+
+        if group.iter().any(|f| f.mode != group[0].mode) {
+            issues.push(LayoutIssue::MixedModes {
+                version,
+                files: paths(),
+            });
+            continue;
+        }
+
+        if group[0].mode == MigrationFileMode::Reversible && group.len() == 1 {
+            let file = group[0];
+            issues.push(LayoutIssue::OrphanHalf {
+                version,
+                file: file.path.clone(),
+                missing: build_file_path(&file.src, version, &file.name, file.mode, !file.is_up),
+            });
+        }
+    }
+
+    issues
+}
+
 fn parse_migration_file(src: &str, path: &Path) -> Option<crate::MigrationFile> {
     // File format: xxxx_name.up/down.sql with mode as reversible
     //              xxxx_name.sql with mode as simple
@@ -123,7 +425,7 @@ fn parse_migration_file(src: &str, path: &Path) -> Option<crate::MigrationFile>
 
     let (seq, name) = parse_sequence_and_name(filename)?;
     Some(crate::MigrationFile {
-        path: filename.to_string(),
+        path: path.display().to_string(),
         name: name.to_string(),
         mode: crate::MigrationFileMode::Simple,
         is_up: false,
@@ -138,7 +440,7 @@ fn parse_sequence_and_name(filename: &str) -> Option<(u32, &str)> {
     Some((seq, name))
 }
 
-fn sanitize_name(name: &str) -> String {
+pub(crate) fn sanitize_name(name: &str) -> String {
     let sanitized: String = name
         .chars()
         .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
@@ -167,6 +469,59 @@ mod tests {
     use crate::MigrationFileMode;
     use std::path::PathBuf;
 
+    // ==================== baseline tests ====================
+
+    #[test]
+    fn test_parse_baseline_marker() {
+        assert_eq!(
+            parse_baseline_marker("-- chutils:baseline from=1 to=12"),
+            Some(1..=12)
+        );
+        assert_eq!(parse_baseline_marker("-- chutils:baseline"), None);
+        assert_eq!(parse_baseline_marker("CREATE TABLE a"), None);
+    }
+
+    // ==================== template tests ====================
+
+    #[test]
+    fn test_render_template() {
+        let vars = TemplateVars {
+            version: 7,
+            name: "add_email",
+            author: "Jane <jane@example.com>",
+            created_at: chrono::DateTime::from_timestamp(0, 0).unwrap(),
+        };
+        assert_eq!(
+            render_template(
+                "-- {{version}}_{{name}} by {{author}} at {{created_at}}\n{param:String}",
+                &vars
+            ),
+            "-- 0007_add_email by Jane <jane@example.com> at 1970-01-01T00:00:00+00:00\n{param:String}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gen_migration_file_uses_templates() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        tokio::fs::write(format!("{}/.template.up.sql", src), "-- up {{name}}\n")
+            .await
+            .unwrap();
+
+        let files = gen_migration_file(src, "add email", Some(MigrationFileMode::Reversible))
+            .await
+            .unwrap();
+
+        let up = tokio::fs::read_to_string(&files[0]).await.unwrap();
+        assert_eq!(up, "-- up add_email\n");
+        // No down template, the file stays empty
+        let down = tokio::fs::read_to_string(&files[1]).await.unwrap();
+        assert!(down.is_empty());
+
+        // Templates are not mistaken for migrations
+        assert_eq!(list_migrations_strict(src).await.unwrap().len(), 2);
+    }
+
     // ==================== sanitize_name tests ====================
 
     #[test]
@@ -338,6 +693,7 @@ mod tests {
         assert_eq!(file.name, "create_users");
         assert_eq!(file.mode, MigrationFileMode::Simple);
         assert!(!file.is_up);
+        assert_eq!(file.path, "migrations/0001_create_users.sql");
     }
 
     #[test]
@@ -647,9 +1003,183 @@ mod tests {
         assert!(!result[0].contains("//"));
     }
 
+    #[tokio::test]
+    async fn test_gen_migration_file_with_content() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        let result = gen_migration_file_with_content(
+            src,
+            "add_email",
+            Some(MigrationFileMode::Reversible),
+            "ALTER TABLE users ADD COLUMN email String;\n",
+            "ALTER TABLE users DROP COLUMN email;\n",
+        )
+        .await
+        .unwrap();
+
+        let up = tokio::fs::read_to_string(&result[0]).await.unwrap();
+        let down = tokio::fs::read_to_string(&result[1]).await.unwrap();
+        assert!(result[0].ends_with("0001_add_email.up.sql"));
+        assert!(up.contains("ADD COLUMN email"));
+        assert!(down.contains("DROP COLUMN email"));
+    }
+
+    #[tokio::test]
+    async fn test_list_sql_files_sorted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        tokio::fs::write(format!("{}/users.sql", src), b"")
+            .await
+            .unwrap();
+        tokio::fs::write(format!("{}/events.sql", src), b"")
+            .await
+            .unwrap();
+        tokio::fs::write(format!("{}/notes.txt", src), b"")
+            .await
+            .unwrap();
+
+        let result = list_sql_files(src).await.unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(result[0].ends_with("events.sql"));
+        assert!(result[1].ends_with("users.sql"));
+    }
+
+    // ==================== list_migrations_strict tests ====================
+
+    async fn write_files(src: &str, names: &[&str]) {
+        for name in names {
+            tokio::fs::write(format!("{}/{}", src, name), b"")
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn strict_issues(names: &[&str]) -> Vec<crate::LayoutIssue> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        write_files(src, names).await;
+
+        match list_migrations_strict(src).await {
+            Err(crate::Error::InvalidMigrationSet(issues)) => issues,
+            other => panic!("expected layout issues, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_migrations_strict_valid() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        write_files(
+            src,
+            &[
+                "0001_first.sql",
+                "0002_second.up.sql",
+                "0002_second.down.sql",
+                "readme.md",
+                ".hidden.sql",
+                "before_all.sql",
+                "after_each.sql",
+            ],
+        )
+        .await;
+
+        let files = list_migrations_strict(src).await.unwrap();
+        assert_eq!(files.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_list_migrations_strict_duplicate_version() {
+        let issues = strict_issues(&["0001_first.sql", "0001_other.sql"]).await;
+        assert_eq!(issues.len(), 1);
+        assert!(matches!(
+            &issues[0],
+            crate::LayoutIssue::DuplicateVersion { version: 1, files } if files.len() == 2
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_migrations_strict_orphan_half() {
+        let issues = strict_issues(&["0001_first.up.sql"]).await;
+        assert!(matches!(
+            &issues[0],
+            crate::LayoutIssue::OrphanHalf { version: 1, missing, .. } if missing.ends_with("0001_first.down.sql")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_migrations_strict_mixed_modes() {
+        let issues = strict_issues(&["0001_first.sql", "0001_first.up.sql"]).await;
+        assert!(matches!(
+            &issues[0],
+            crate::LayoutIssue::MixedModes { version: 1, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_migrations_strict_unparseable_names() {
+        let issues = strict_issues(&["0001_first.sql", "first.sql", "0002_typo.txt"]).await;
+        assert_eq!(issues.len(), 2);
+        assert!(
+            issues
+                .iter()
+                .all(|i| matches!(i, crate::LayoutIssue::UnparseableName { .. }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_migrations_strict_allows_gap() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        write_files(src, &["0001_first.sql", "0003_third.sql"]).await;
+
+        let files = list_migrations_strict(src).await.unwrap();
+        assert_eq!(
+            files.iter().map(|f| f.seq_num).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+
+        let issues = check_layout(&files);
+        assert_eq!(issues, vec![crate::LayoutIssue::Gap { after: 1, next: 3 }]);
+        assert_eq!(issues[0].to_string(), "gap in versions between 1 and 3");
+
+        // Gaps don't hide the issues that are errors
+        write_files(src, &["0005_fifth.up.sql"]).await;
+        assert!(matches!(
+            list_migrations_strict(src).await,
+            Err(crate::Error::InvalidMigrationSet(issues))
+                if matches!(&issues[..], [crate::LayoutIssue::OrphanHalf { version: 5, .. }])
+        ));
+    }
+
     #[tokio::test]
     async fn test_list_migrations_nonexistent_dir() {
         let result = list_migrations("/nonexistent/path").await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_rename_all_undoes_renames_on_failure() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        write_files(src, &["0001_a.sql", "0002_b.sql"]).await;
+
+        let path = |name: &str| format!("{}/{}", src, name);
+        let moves = vec![
+            (path("0001_a.sql"), path("0003_a.sql")),
+            (path("0002_b.sql"), path("0004_b.sql")),
+            (path("0005_missing.sql"), path("0006_missing.sql")),
+        ];
+        assert!(rename_all(&moves).await.is_err());
+
+        let mut names: Vec<_> = list_migrations(src)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|f| f.seq_num)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec![1, 2]);
+    }
 }
@@ -0,0 +1,427 @@
+use crate::{Error, MigrationFileMode, fs, sql};
+
+/// Result of `gen_down`.
+#[derive(Debug, Clone)]
+pub struct GeneratedDown {
+    /// Path of the `.down.sql` file that was written
+    pub path: String,
+    /// Number of inverse statements
+    pub statements: usize,
+    /// Number of statements left as TODO comments
+    pub todos: usize,
+}
+
+/// Fill the `.down.sql` file of reversible migration `version` with the inverse of
+/// its up statements, in reverse order.
+///
+/// Refuses to overwrite a down file that already has statements unless `overwrite` is set.
+pub async fn gen_down(src: &str, version: u32, overwrite: bool) -> Result<GeneratedDown, Error> {
+    let files: Vec<_> = fs::list_migrations_strict(src)
+        .await?
+        .into_iter()
+        .filter(|mf| mf.seq_num == version)
+        .collect();
+
+    let (Some(up), Some(down)) = (
+        files
+            .iter()
+            .find(|mf| mf.mode == MigrationFileMode::Reversible && mf.is_up),
+        files
+            .iter()
+            .find(|mf| mf.mode == MigrationFileMode::Reversible && !mf.is_up),
+    ) else {
+        return Err(Error::InvalidInput(format!(
+            "no reversible migration with version {}",
+            version
+        )));
+    };
+
+    let existing = tokio::fs::read(&down.path).await?;
+    if !overwrite && !sql::split_statements(&String::from_utf8_lossy(&existing)).is_empty() {
+        return Err(Error::InvalidInput(format!(
+            "{} already has statements, overwrite it explicitly",
+            down.path
+        )));
+    }
+
+    let raw = tokio::fs::read(&up.path).await?;
+    let content = String::from_utf8_lossy(&raw);
+
+    let mut blocks = vec![];
+    let mut result = GeneratedDown {
+        path: down.path.clone(),
+        statements: 0,
+        todos: 0,
+    };
+    for stmt in sql::split_statements(&content).iter().rev() {
+        for inv in invert(&stmt.sql) {
+            match inv {
+                Inverse::Statement(sql) => {
+                    result.statements += 1;
+                    blocks.push(format!("{};", sql));
+                }
+                Inverse::Todo(sql) => {
+                    result.todos += 1;
+                    let commented: Vec<_> = sql.lines().map(|l| format!("-- {}", l)).collect();
+                    blocks.push(format!(
+                        "-- TODO: cannot invert automatically:\n{}",
+                        commented.join("\n")
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut out = format!(
+        "-- Generated by chutils migrate gen-down from {}\n",
+        up.path
+    );
+    for block in blocks {
+        out.push('\n');
+        out.push_str(&block);
+        out.push('\n');
+    }
+    tokio::fs::write(&down.path, out).await?;
+
+    Ok(result)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Inverse {
+    Statement(String),
+    /// Statement (or ALTER action) that has no mechanical inverse
+    Todo(String),
+}
+
+/// Inverse of a single up statement, in the order it should be executed.
+/// An ALTER with several actions yields one entry per action.
+pub(crate) fn invert(statement: &str) -> Vec<Inverse> {
+    let stripped = strip_inline_comments(statement);
+    let mut cur = Cursor::new(&stripped);
+    let todo = || vec![Inverse::Todo(statement.to_string())];
+
+    if cur.keyword("CREATE") {
+        if cur.keyword("OR") {
+            // CREATE OR REPLACE loses the previous definition
+            return todo();
+        }
+        let kind = if cur.keyword("TEMPORARY") && cur.keyword("TABLE") || cur.keyword("TABLE") {
+            "TABLE"
+        } else if cur.keyword("MATERIALIZED") && cur.keyword("VIEW") || cur.keyword("VIEW") {
+            "VIEW"
+        } else if cur.keyword("DICTIONARY") {
+            "DICTIONARY"
+        } else if cur.keyword("DATABASE") {
+            "DATABASE"
+        } else {
+            return todo();
+        };
+        let _ = cur.keyword("IF") && cur.keyword("NOT") && cur.keyword("EXISTS");
+        let Some(name) = cur.ident() else {
+            return todo();
+        };
+        return vec![Inverse::Statement(format!(
+            "DROP {} IF EXISTS {}{}",
+            kind,
+            name,
+            cur.on_cluster()
+        ))];
+    }
+
+    if cur.keyword("RENAME") && cur.keyword("TABLE") {
+        let (Some(from), true, Some(to)) = (cur.ident(), cur.keyword("TO"), cur.ident()) else {
+            return todo();
+        };
+        let cluster = cur.on_cluster();
+        if !cur.rest().is_empty() {
+            // Several renames in one statement
+            return todo();
+        }
+        return vec![Inverse::Statement(format!(
+            "RENAME TABLE {} TO {}{}",
+            to, from, cluster
+        ))];
+    }
+
+    if cur.keyword("ALTER") && cur.keyword("TABLE") {
+        let Some(table) = cur.ident() else {
+            return todo();
+        };
+        let prefix = format!("ALTER TABLE {}{}", table, cur.on_cluster());
+        return split_top_level(cur.rest())
+            .into_iter()
+            .rev()
+            .map(|action| match invert_alter_action(action) {
+                Some(inverse) => Inverse::Statement(format!("{} {}", prefix, inverse)),
+                None => Inverse::Todo(format!("{} {}", prefix, action)),
+            })
+            .collect();
+    }
+
+    todo()
+}
+
+fn invert_alter_action(action: &str) -> Option<String> {
+    let mut cur = Cursor::new(action);
+    if cur.keyword("ADD") {
+        let kind = ["COLUMN", "INDEX", "PROJECTION", "CONSTRAINT"]
+            .into_iter()
+            .find(|k| cur.keyword(k))?;
+        let _ = cur.keyword("IF") && cur.keyword("NOT") && cur.keyword("EXISTS");
+        let name = cur.ident()?;
+        return Some(format!("DROP {} IF EXISTS {}", kind, name));
+    }
+
+    if cur.keyword("RENAME") && cur.keyword("COLUMN") {
+        let _ = cur.keyword("IF") && cur.keyword("EXISTS");
+        let (from, true, to) = (cur.ident()?, cur.keyword("TO"), cur.ident()?) else {
+            return None;
+        };
+        return Some(format!("RENAME COLUMN IF EXISTS {} TO {}", to, from));
+    }
+
+    None
+}
+
+/// Drop `-- ...` comments outside of string literals.
+fn strip_inline_comments(sql: &str) -> String {
+    sql.lines()
+        .map(|line| {
+            let mut quote = None;
+            let mut prev = ' ';
+            for (i, c) in line.char_indices() {
+                match (quote, c) {
+                    (None, '\'' | '"' | '`') => quote = Some(c),
+                    (Some(q), c) if c == q => quote = None,
+                    (None, '-') if prev == '-' => return &line[..i - 1],
+                    _ => {}
+                }
+                prev = c;
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Split on commas that are not nested in parentheses or quotes.
+pub(crate) fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut depth, mut quote, mut start) = (0i32, None, 0);
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].trim());
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+struct Cursor<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(s: &'a str) -> Self {
+        Self { s, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        self.s[self.pos..].trim()
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consume `kw` (case-insensitive) if it is the next word.
+    fn keyword(&mut self, kw: &str) -> bool {
+        self.skip_ws();
+        let rest = &self.s[self.pos..];
+        let matches = rest
+            .get(..kw.len())
+            .is_some_and(|w| w.eq_ignore_ascii_case(kw))
+            && !rest[kw.len()..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        if matches {
+            self.pos += kw.len();
+        }
+        matches
+    }
+
+    /// Consume a possibly qualified and quoted identifier such as `db`.`table`.
+    fn ident(&mut self) -> Option<&'a str> {
+        self.skip_ws();
+        let start = self.pos;
+        let bytes = self.s.as_bytes();
+        while self.pos < bytes.len() {
+            match bytes[self.pos] {
+                q @ (b'`' | b'"') => {
+                    let end = self.s[self.pos + 1..].find(q as char)?;
+                    self.pos += end + 2;
+                }
+                c if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' => self.pos += 1,
+                _ => break,
+            }
+        }
+        (self.pos > start).then(|| &self.s[start..self.pos])
+    }
+
+    /// ` ON CLUSTER <name>` if present, empty otherwise.
+    fn on_cluster(&mut self) -> String {
+        let save = self.pos;
+        if self.keyword("ON") && self.keyword("CLUSTER") {
+            if let Some(cluster) = self.ident().or_else(|| self.quoted()) {
+                return format!(" ON CLUSTER {}", cluster);
+            }
+        }
+        self.pos = save;
+        String::new()
+    }
+
+    fn quoted(&mut self) -> Option<&'a str> {
+        self.skip_ws();
+        let rest = &self.s[self.pos..];
+        let end = rest.strip_prefix('\'')?.find('\'')?;
+        self.pos += end + 2;
+        Some(&rest[..end + 2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stmt(sql: &str) -> Inverse {
+        Inverse::Statement(sql.to_string())
+    }
+
+    #[test]
+    fn test_invert_create() {
+        assert_eq!(
+            invert("CREATE TABLE IF NOT EXISTS db.users (id UInt32) ENGINE = Memory"),
+            vec![stmt("DROP TABLE IF EXISTS db.users")]
+        );
+        assert_eq!(
+            invert("create table `my users`(id UInt32) ENGINE = Memory"),
+            vec![stmt("DROP TABLE IF EXISTS `my users`")]
+        );
+        assert_eq!(
+            invert("CREATE MATERIALIZED VIEW mv ON CLUSTER main TO t AS SELECT 1"),
+            vec![stmt("DROP VIEW IF EXISTS mv ON CLUSTER main")]
+        );
+        assert_eq!(
+            invert("CREATE DICTIONARY d (id UInt64) PRIMARY KEY id"),
+            vec![stmt("DROP DICTIONARY IF EXISTS d")]
+        );
+    }
+
+    #[test]
+    fn test_invert_create_or_replace_is_todo() {
+        let sql = "CREATE OR REPLACE VIEW v AS SELECT 1";
+        assert_eq!(invert(sql), vec![Inverse::Todo(sql.to_string())]);
+    }
+
+    #[test]
+    fn test_invert_alter_actions_in_reverse() {
+        let inverse = invert(
+            "ALTER TABLE users ON CLUSTER main ADD COLUMN email String DEFAULT concat('a', ','), ADD INDEX idx email TYPE bloom_filter GRANULARITY 1, DROP COLUMN legacy",
+        );
+        assert_eq!(
+            inverse,
+            vec![
+                Inverse::Todo("ALTER TABLE users ON CLUSTER main DROP COLUMN legacy".to_string()),
+                stmt("ALTER TABLE users ON CLUSTER main DROP INDEX IF EXISTS idx"),
+                stmt("ALTER TABLE users ON CLUSTER main DROP COLUMN IF EXISTS email"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invert_renames() {
+        assert_eq!(
+            invert("RENAME TABLE a TO b"),
+            vec![stmt("RENAME TABLE b TO a")]
+        );
+        assert_eq!(
+            invert("ALTER TABLE t RENAME COLUMN a TO b"),
+            vec![stmt("ALTER TABLE t RENAME COLUMN IF EXISTS b TO a")]
+        );
+    }
+
+    #[test]
+    fn test_invert_modify_is_todo() {
+        assert_eq!(
+            invert("ALTER TABLE t MODIFY COLUMN a UInt64"),
+            vec![Inverse::Todo(
+                "ALTER TABLE t MODIFY COLUMN a UInt64".to_string()
+            )]
+        );
+        assert!(matches!(
+            &invert("INSERT INTO t VALUES (1)")[0],
+            Inverse::Todo(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_gen_down_writes_reverse_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        tokio::fs::write(
+            format!("{}/0001_init.up.sql", src),
+            "CREATE TABLE a (id UInt32) ENGINE = Memory;\n-- note\nCREATE TABLE b (id UInt32) ENGINE = Memory;\nALTER TABLE a MODIFY COLUMN id UInt64;\n",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(format!("{}/0001_init.down.sql", src), "-- empty\n")
+            .await
+            .unwrap();
+
+        let result = gen_down(src, 1, false).await.unwrap();
+        assert_eq!(result.statements, 2);
+        assert_eq!(result.todos, 1);
+
+        let content = tokio::fs::read_to_string(&result.path).await.unwrap();
+        let todo = content
+            .find("-- ALTER TABLE a MODIFY COLUMN id UInt64")
+            .unwrap();
+        let drop_b = content.find("DROP TABLE IF EXISTS b;").unwrap();
+        let drop_a = content.find("DROP TABLE IF EXISTS a;").unwrap();
+        assert!(todo < drop_b && drop_b < drop_a);
+
+        // Now the down file has statements and must not be overwritten silently
+        assert!(matches!(
+            gen_down(src, 1, false).await,
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(gen_down(src, 1, true).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_gen_down_requires_reversible() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        tokio::fs::write(format!("{}/0001_init.sql", src), "SELECT 1")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            gen_down(src, 1, false).await,
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
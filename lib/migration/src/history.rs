@@ -0,0 +1,367 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use ch::clickhouse;
+
+use crate::{Error, MigrationInfo, MigrationStatus};
+
+/// Where a `Migrator` keeps the record of applied migrations.
+///
+/// Defaults to the `_ch_migrations` table of the target database, see
+/// `Migrator::with_history` for the alternatives.
+#[async_trait::async_trait]
+pub trait HistoryStore: Send + Sync {
+    /// Create the storage if needed, called by `Migration::ensure_migrations_table`.
+    async fn ensure(&self) -> Result<(), Error>;
+
+    /// Every recorded migration, in any order.
+    async fn load(&self) -> Result<Vec<MigrationInfo>, Error>;
+
+    async fn record_applied(&self, info: &MigrationInfo) -> Result<(), Error>;
+
+    async fn record_reverted(&self, version: u32) -> Result<(), Error>;
+
+    /// Taken for the whole of a non dry-run `run` / `revert`. Fails with
+    /// `Error::Locked` when another run holds it. No-op by default.
+    async fn lock(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn unlock(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The `_ch_migrations` table, the default store.
+///
+/// ClickHouse has nothing to build a lock on, concurrent runs against the same database
+/// aren't prevented.
+#[derive(Clone)]
+pub struct ClickhouseHistory {
+    client: Arc<clickhouse::Client>,
+}
+
+impl ClickhouseHistory {
+    pub fn new(client: clickhouse::Client) -> Self {
+        Self::from_arc(Arc::new(client))
+    }
+
+    pub(crate) fn from_arc(client: Arc<clickhouse::Client>) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait::async_trait]
+impl HistoryStore for ClickhouseHistory {
+    async fn ensure(&self) -> Result<(), Error> {
+        self.client
+            .query(
+                "
+            CREATE TABLE IF NOT EXISTS _ch_migrations (
+                version UInt32,
+                name String,
+                status Enum('pending' = 1, 'applied' = 2),
+                applied_at DateTime DEFAULT now(),
+                duration_ms UInt64 DEFAULT 0,
+                applied_by String DEFAULT '',
+                host String DEFAULT '',
+                chutils_version String DEFAULT '',
+                checksum String DEFAULT '',
+                transactional Bool DEFAULT false
+                ) ENGINE = MergeTree()
+            ORDER BY(applied_at, version)
+            ",
+            )
+            .execute()
+            .await?;
+
+        // Upgrade tables created by older versions
+        self.client
+            .query(
+                "
+            ALTER TABLE _ch_migrations
+                ADD COLUMN IF NOT EXISTS duration_ms UInt64 DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS applied_by String DEFAULT '',
+                ADD COLUMN IF NOT EXISTS host String DEFAULT '',
+                ADD COLUMN IF NOT EXISTS chutils_version String DEFAULT '',
+                ADD COLUMN IF NOT EXISTS checksum String DEFAULT '',
+                ADD COLUMN IF NOT EXISTS transactional Bool DEFAULT false
+            ",
+            )
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn load(&self) -> Result<Vec<MigrationInfo>, Error> {
+        Ok(self
+            .client
+            .query(
+                "SELECT version, name, status, applied_at, duration_ms, applied_by, host, chutils_version, checksum, transactional
+                FROM _ch_migrations",
+            )
+            .fetch_all::<MigrationInfo>()
+            .await?)
+    }
+
+    async fn record_applied(&self, info: &MigrationInfo) -> Result<(), Error> {
+        let mut insert = self.client.insert::<MigrationInfo>("_ch_migrations")?;
+        insert.write(info).await?;
+        insert.end().await?;
+        Ok(())
+    }
+
+    async fn record_reverted(&self, version: u32) -> Result<(), Error> {
+        self.client
+            .query("DELETE FROM _ch_migrations WHERE version = ?")
+            .bind(version)
+            .execute()
+            .await?;
+        Ok(())
+    }
+}
+
+/// A local JSON file, for read-only replicas and air-gapped rigs.
+///
+/// The file holds an array of history rows and is created by `ensure`. The lock is a
+/// `<path>.lock` file next to it naming its holder; remove it by hand if a crashed run
+/// left it behind.
+#[derive(Debug, Clone)]
+pub struct JsonFileHistory {
+    path: PathBuf,
+}
+
+impl JsonFileHistory {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".lock");
+        path.into()
+    }
+
+    async fn save(&self, rows: &[MigrationInfo]) -> Result<(), Error> {
+        let json = serde_json::to_vec_pretty(rows)
+            .map_err(|e| Error::InvalidInput(format!("cannot serialize history: {}", e)))?;
+        // Write a sibling file and rename it over, so a crash never leaves half a file
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, json).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl HistoryStore for JsonFileHistory {
+    async fn ensure(&self) -> Result<(), Error> {
+        if !tokio::fs::try_exists(&self.path).await? {
+            self.save(&[]).await?;
+        }
+        Ok(())
+    }
+
+    async fn load(&self) -> Result<Vec<MigrationInfo>, Error> {
+        let raw = tokio::fs::read(&self.path).await?;
+        serde_json::from_slice(&raw).map_err(|e| {
+            Error::InvalidInput(format!(
+                "{} is not a valid history file: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+
+    async fn record_applied(&self, info: &MigrationInfo) -> Result<(), Error> {
+        let mut rows = self.load().await?;
+        rows.push(info.clone());
+        self.save(&rows).await
+    }
+
+    async fn record_reverted(&self, version: u32) -> Result<(), Error> {
+        let mut rows = self.load().await?;
+        rows.retain(|r| r.version != version);
+        self.save(&rows).await
+    }
+
+    async fn lock(&self) -> Result<(), Error> {
+        let path = self.lock_path();
+        let holder = format!(
+            "{}@{} (pid {})",
+            crate::current_user(),
+            crate::current_host(),
+            std::process::id()
+        );
+        match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(_) => {
+                tokio::fs::write(&path, holder).await?;
+                Ok(())
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                let holder = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+                Err(Error::Locked(format!(
+                    "{} held by {}",
+                    path.display(),
+                    holder
+                )))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn unlock(&self) -> Result<(), Error> {
+        tokio::fs::remove_file(self.lock_path()).await?;
+        Ok(())
+    }
+}
+
+/// Keeps the history in memory, to test migration ordering without a server.
+/// Clones share the same rows and lock.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryHistory {
+    rows: Arc<Mutex<Vec<MigrationInfo>>>,
+    locked: Arc<AtomicBool>,
+}
+
+impl MemoryHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start with `version` recorded as applied.
+    pub fn with_applied(self, version: u32, name: &str) -> Self {
+        let mut info = MigrationInfo::from(crate::MigrationFile {
+            path: String::new(),
+            name: name.to_string(),
+            mode: crate::MigrationFileMode::Simple,
+            src: String::new(),
+            is_up: true,
+            seq_num: version,
+        });
+        info.status = MigrationStatus::Applied;
+        self.rows.lock().unwrap().push(info);
+        self
+    }
+
+    /// The recorded rows, in the order they were recorded.
+    pub fn rows(&self) -> Vec<MigrationInfo> {
+        self.rows.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl HistoryStore for MemoryHistory {
+    async fn ensure(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn load(&self) -> Result<Vec<MigrationInfo>, Error> {
+        Ok(self.rows())
+    }
+
+    async fn record_applied(&self, info: &MigrationInfo) -> Result<(), Error> {
+        self.rows.lock().unwrap().push(info.clone());
+        Ok(())
+    }
+
+    async fn record_reverted(&self, version: u32) -> Result<(), Error> {
+        self.rows.lock().unwrap().retain(|r| r.version != version);
+        Ok(())
+    }
+
+    async fn lock(&self) -> Result<(), Error> {
+        if self.locked.swap(true, Ordering::SeqCst) {
+            return Err(Error::Locked("in-memory history".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn unlock(&self) -> Result<(), Error> {
+        self.locked.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Migration, MigrationEvent, Migrator, RunOptions};
+
+    #[tokio::test]
+    async fn test_json_file_history() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = JsonFileHistory::new(temp_dir.path().join("history.json"));
+        store.ensure().await.unwrap();
+        assert!(store.load().await.unwrap().is_empty());
+
+        let rows = MemoryHistory::new()
+            .with_applied(1, "a")
+            .with_applied(2, "b")
+            .rows();
+        for row in &rows {
+            store.record_applied(row).await.unwrap();
+        }
+        store.record_reverted(2).await.unwrap();
+        let loaded = store.load().await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].name, "a");
+        assert_eq!(loaded[0].status, MigrationStatus::Applied);
+
+        store.lock().await.unwrap();
+        assert!(matches!(store.lock().await, Err(Error::Locked(_))));
+        store.unlock().await.unwrap();
+        store.lock().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_history_without_server() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        for name in ["0001_a.sql", "0002_b.sql"] {
+            tokio::fs::write(temp_dir.path().join(name), "SELECT 1")
+                .await
+                .unwrap();
+        }
+
+        let history = MemoryHistory::new().with_applied(1, "a");
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        // Nothing listens on this URL: the history never reaches the server
+        let migrator =
+            Migrator::from_client(clickhouse::Client::default().with_url("http://127.0.0.1:1"))
+                .with_history(history.clone())
+                .with_observer(move |e: &MigrationEvent| recorded.lock().unwrap().push(e.clone()));
+
+        let pending = migrator
+            .run(src, RunOptions::new().dry_run(true))
+            .await
+            .unwrap();
+        assert_eq!(pending.iter().map(|m| m.version).collect::<Vec<_>>(), [2]);
+        assert!(events.lock().unwrap().is_empty());
+
+        let mut applied = pending[0].clone();
+        applied.status = MigrationStatus::Applied;
+        history.record_applied(&applied).await.unwrap();
+        assert!(
+            migrator
+                .run(src, RunOptions::new())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(matches!(
+            events.lock().unwrap()[..],
+            [MigrationEvent::LockAcquired]
+        ));
+        // Released after the run
+        history.lock().await.unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{Direction, Error, MigrationInfo, Migrator, sql};
+
+/// Points of a `run`/`revert` where hooks are executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HookPoint {
+    BeforeAll,
+    AfterAll,
+    BeforeEach,
+    AfterEach,
+}
+
+impl HookPoint {
+    pub const ALL: [HookPoint; 4] = [
+        HookPoint::BeforeAll,
+        HookPoint::AfterAll,
+        HookPoint::BeforeEach,
+        HookPoint::AfterEach,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::BeforeAll => "before_all",
+            Self::AfterAll => "after_all",
+            Self::BeforeEach => "before_each",
+            Self::AfterEach => "after_each",
+        }
+    }
+
+    /// Name of the SQL hook file in the migrations directory
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::BeforeAll => "before_all.sql",
+            Self::AfterAll => "after_all.sql",
+            Self::BeforeEach => "before_each.sql",
+            Self::AfterEach => "after_each.sql",
+        }
+    }
+}
+
+impl std::fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl std::str::FromStr for HookPoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|h| h.id() == s)
+            .ok_or_else(|| Error::InvalidInput(format!("unknown hook '{}'", s)))
+    }
+}
+
+impl Migrator {
+    /// Run `command` through `sh -c` at `point`, after the SQL hook file of the same point.
+    ///
+    /// The command gets `CHUTILS_HOOK`, `CHUTILS_DIRECTION` and, for `*_each` hooks,
+    /// `CHUTILS_MIGRATION_VERSION` and `CHUTILS_MIGRATION_NAME` in its environment.
+    pub fn with_hook_command(mut self, point: HookPoint, command: impl Into<String>) -> Self {
+        self.hooks.push((point, command.into()));
+        self
+    }
+
+    /// Execute the SQL hook file then the commands configured for `point`.
+    /// `mig` is only set for `*_each` hooks.
+    pub(crate) async fn run_hooks(
+        &self,
+        src: &str,
+        point: HookPoint,
+        mig: Option<&MigrationInfo>,
+        direction: Direction,
+    ) -> Result<(), Error> {
+        let failed = |hook: String, message: String| Error::HookFailed {
+            hook,
+            version: mig.map(|m| m.version),
+            message,
+        };
+
+        let file = format!("{}/{}", src.trim_end_matches('/'), point.file_name());
+        if tokio::fs::try_exists(&file).await? {
+            let raw = tokio::fs::read(&file).await?;
+            let content = String::from_utf8_lossy(&raw);
+            for stmt in sql::split_statements(&content) {
+                // Hook files reference these as {version:UInt32}, {name:String}, {direction:String}
+                let mut query = self
+                    .inner
+                    .query(&stmt.sql)
+                    .param("direction", direction.to_string());
+                if let Some(mig) = mig {
+                    query = query.param("version", mig.version).param("name", &mig.name);
+                }
+                query
+                    .execute()
+                    .await
+                    .map_err(|err| failed(format!("{}:{}", file, stmt.line), err.to_string()))?;
+            }
+        }
+
+        for (_, command) in self.hooks.iter().filter(|(p, _)| *p == point) {
+            let mut cmd = tokio::process::Command::new("sh");
+            cmd.arg("-c")
+                .arg(command)
+                .env("CHUTILS_HOOK", point.id())
+                .env("CHUTILS_DIRECTION", direction.to_string());
+            if let Some(mig) = mig {
+                cmd.env("CHUTILS_MIGRATION_VERSION", mig.version.to_string())
+                    .env("CHUTILS_MIGRATION_NAME", &mig.name);
+            }
+
+            let status = cmd
+                .status()
+                .await
+                .map_err(|err| failed(format!("`{}`", command), err.to_string()))?;
+            if !status.success() {
+                return Err(failed(
+                    format!("`{}`", command),
+                    format!("exited with {}", status),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_point_from_str() {
+        for point in HookPoint::ALL {
+            assert_eq!(point.id().parse::<HookPoint>().unwrap(), point);
+            assert_eq!(point.file_name(), format!("{}.sql", point));
+        }
+        assert!("before".parse::<HookPoint>().is_err());
+    }
+}
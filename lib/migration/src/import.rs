@@ -0,0 +1,578 @@
+use std::collections::BTreeMap;
+
+use ch::clickhouse;
+
+use crate::{
+    Error, MigrationFile, MigrationFileMode, MigrationInfo, MigrationStatus, Migrator, fs,
+};
+
+/// Migration tools whose history `Migrator::import_history` can take over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum SourceTool {
+    /// golang-migrate: `schema_migrations`, `NNN_name.up.sql` / `NNN_name.down.sql`
+    GolangMigrate,
+    /// goose: `goose_db_version`, `NNN_name.sql` with `-- +goose Up` / `-- +goose Down`
+    Goose,
+    /// clickhouse-migrations: `_migrations`, `NNN_name.sql`
+    ClickhouseMigrations,
+}
+
+/// A migration of the other tool, renamed (or split) to chutils' convention.
+#[derive(Debug, Clone)]
+pub struct ImportedMigration {
+    /// chutils version, migrations are renumbered from 1 in the other tool's order
+    pub version: u32,
+    pub name: String,
+    /// Version in the other tool, often a timestamp
+    pub source_version: u64,
+    /// Whether the other tool recorded it as applied
+    pub applied: bool,
+    /// Files of the other tool
+    pub from: Vec<String>,
+    /// chutils files they become
+    pub to: Vec<String>,
+}
+
+/// `NNN_name.sql` / `NNN_name.up.sql` / `NNN_name.down.sql` of the other tool.
+#[derive(Debug, Default)]
+struct SourceMigration {
+    name: String,
+    single: Option<String>,
+    up: Option<String>,
+    down: Option<String>,
+}
+
+/// A migration to import, with the mode and up/down contents of its new files.
+type ImportPlan = (ImportedMigration, MigrationFileMode, String, Option<String>);
+
+struct AppliedSource {
+    version: u64,
+    applied_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, clickhouse::Row, serde::Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct GolangMigrateRow {
+    version: i64,
+    dirty: u8,
+}
+
+#[derive(Debug, clickhouse::Row, serde::Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct TimestampedRow {
+    version: u64,
+    #[serde(with = "ch::clickhouse::serde::chrono::datetime")]
+    applied_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Migrator {
+    /// Take over the history of another migration tool: rename its files in `src` to
+    /// chutils' `NNNN_name[.up|.down].sql` convention and record the migrations it applied
+    /// in the history store.
+    ///
+    /// Migrations are renumbered from 1 in the other tool's order, so timestamp versions
+    /// become a gap-free sequence. goose files are split at their `-- +goose Down`
+    /// annotation. The other tool's table is left as-is. Refuses to run when the history
+    /// has rows other than the ones an interrupted import recorded, which are skipped.
+    /// Nothing is changed when `dry_run` is set.
+    pub async fn import_history(
+        &self,
+        src: &str,
+        tool: SourceTool,
+        dry_run: bool,
+    ) -> Result<Vec<ImportedMigration>, Error> {
+        let src = src.strip_suffix('/').unwrap_or(src);
+
+        let history = self.history();
+        let recorded = history.load().await?;
+
+        let sources = list_source_migrations(src).await?;
+        let applied = self.source_history(tool, &sources).await?;
+        if let Some(missing) = applied.iter().find(|a| !sources.contains_key(&a.version)) {
+            return Err(Error::InvalidInput(format!(
+                "version {} is applied according to {:?} but has no local file",
+                missing.version, tool
+            )));
+        }
+
+        let mut plan: Vec<ImportPlan> = vec![];
+        for (i, (&source_version, mig)) in sources.iter().enumerate() {
+            let version = i as u32 + 1;
+            let (mode, up, down) = convert(tool, mig).await?;
+            let to = match mode {
+                MigrationFileMode::Simple => {
+                    vec![fs::build_file_path(src, version, &mig.name, mode, true)]
+                }
+                MigrationFileMode::Reversible => vec![
+                    fs::build_file_path(src, version, &mig.name, mode, true),
+                    fs::build_file_path(src, version, &mig.name, mode, false),
+                ],
+            };
+            let from: Vec<String> = [&mig.single, &mig.up, &mig.down]
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect();
+            for path in &to {
+                if !from.contains(path) && tokio::fs::try_exists(path).await? {
+                    return Err(Error::InvalidInput(format!(
+                        "cannot import version {} as {}: file exists",
+                        source_version, path
+                    )));
+                }
+            }
+            plan.push((
+                ImportedMigration {
+                    version,
+                    name: mig.name.clone(),
+                    source_version,
+                    applied: applied.iter().any(|a| a.version == source_version),
+                    from,
+                    to,
+                },
+                mode,
+                up,
+                down,
+            ));
+        }
+
+        // Rows left by an interrupted import are fine as long as they are part of this one
+        if let Some(row) = recorded.iter().find(|row| {
+            !plan
+                .iter()
+                .any(|(i, ..)| i.applied && i.version == row.version && i.name == row.name)
+        }) {
+            return Err(Error::InvalidInput(format!(
+                "the history already has version {} ({}), which isn't part of the import, refusing to import",
+                row.version, row.name
+            )));
+        }
+
+        if dry_run {
+            return Ok(plan.into_iter().map(|(imported, ..)| imported).collect());
+        }
+
+        // New files are written and the history recorded before any old file is removed:
+        // when recording fails, the new files are removed again and running the import
+        // once more resumes from the rows already recorded
+        for (imported, _, up, down) in &plan {
+            tokio::fs::write(&imported.to[0], up).await?;
+            if let (Some(path), Some(down)) = (imported.to.get(1), down) {
+                tokio::fs::write(path, down).await?;
+            }
+        }
+        if let Err(err) = self.record_imported(src, &plan, &applied, &recorded).await {
+            for (imported, ..) in &plan {
+                for path in imported.to.iter().filter(|p| !imported.from.contains(p)) {
+                    if let Err(err) = tokio::fs::remove_file(path).await {
+                        tracing::error!(error = %err, path, "Failed to remove imported file");
+                    }
+                }
+            }
+            return Err(err);
+        }
+        for (imported, ..) in &plan {
+            for path in imported.from.iter().filter(|p| !imported.to.contains(p)) {
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+
+        Ok(plan.into_iter().map(|(imported, ..)| imported).collect())
+    }
+
+    /// Record the applied migrations of `plan` that aren't `recorded` yet.
+    async fn record_imported(
+        &self,
+        src: &str,
+        plan: &[ImportPlan],
+        applied: &[AppliedSource],
+        recorded: &[MigrationInfo],
+    ) -> Result<(), Error> {
+        let history = self.history();
+        let (applied_by, host) = (crate::current_user(), crate::current_host());
+        for (imported, mode, ..) in plan.iter().filter(|(i, ..)| i.applied) {
+            if recorded.iter().any(|row| row.version == imported.version) {
+                continue;
+            }
+            let mut mig = MigrationInfo::from(MigrationFile {
+                path: imported.to[0].clone(),
+                name: imported.name.clone(),
+                mode: *mode,
+                src: src.to_string(),
+                is_up: true,
+                seq_num: imported.version,
+            });
+            mig.status = MigrationStatus::Applied;
+            if let Some(applied_at) = applied
+                .iter()
+                .find(|a| a.version == imported.source_version)
+                .and_then(|a| a.applied_at)
+            {
+                mig.applied_at = applied_at;
+            }
+            mig.applied_by = applied_by.clone();
+            mig.host = host.clone();
+            mig.chutils_version = info::version().to_string();
+            mig.checksum = fs::checksum(&imported.to[0]).await?;
+            history.record_applied(&mig).await?;
+        }
+        Ok(())
+    }
+
+    /// Versions the other tool applied.
+    async fn source_history(
+        &self,
+        tool: SourceTool,
+        sources: &BTreeMap<u64, SourceMigration>,
+    ) -> Result<Vec<AppliedSource>, Error> {
+        let applied = match tool {
+            // Only the current version is meaningful, older rows may have been cleaned up
+            SourceTool::GolangMigrate => {
+                let current = self
+                    .inner
+                    .query("SELECT version, dirty FROM schema_migrations ORDER BY sequence DESC LIMIT 1")
+                    .fetch_optional::<GolangMigrateRow>()
+                    .await?;
+                match current {
+                    Some(row) if row.dirty != 0 => {
+                        return Err(Error::InvalidInput(format!(
+                            "golang-migrate version {} is dirty, fix it with `migrate force` first",
+                            row.version
+                        )));
+                    }
+                    Some(row) if row.version >= 0 => {
+                        let mut applied: Vec<_> = sources
+                            .keys()
+                            .filter(|v| **v <= row.version as u64)
+                            .map(|&version| AppliedSource {
+                                version,
+                                applied_at: None,
+                            })
+                            .collect();
+                        // Reported as missing if the current version has no local file
+                        if !sources.contains_key(&(row.version as u64)) {
+                            applied.push(AppliedSource {
+                                version: row.version as u64,
+                                applied_at: None,
+                            });
+                        }
+                        applied
+                    }
+                    _ => vec![],
+                }
+            }
+            SourceTool::Goose => self
+                .inner
+                .query(
+                    "SELECT toUInt64(version_id) AS version, max(tstamp) AS applied_at
+                    FROM goose_db_version
+                    GROUP BY version_id
+                    HAVING argMax(is_applied, tstamp) = 1 AND version_id > 0",
+                )
+                .fetch_all::<TimestampedRow>()
+                .await?
+                .into_iter()
+                .map(|row| AppliedSource {
+                    version: row.version,
+                    applied_at: Some(row.applied_at),
+                })
+                .collect(),
+            SourceTool::ClickhouseMigrations => self
+                .inner
+                .query("SELECT toUInt64(version) AS version, applied_at FROM _migrations")
+                .fetch_all::<TimestampedRow>()
+                .await?
+                .into_iter()
+                .map(|row| AppliedSource {
+                    version: row.version,
+                    applied_at: Some(row.applied_at),
+                })
+                .collect(),
+        };
+        Ok(applied)
+    }
+}
+
+/// Every `NNN_name[.up|.down].sql` in `src`, by the other tool's version.
+async fn list_source_migrations(src: &str) -> Result<BTreeMap<u64, SourceMigration>, Error> {
+    let mut sources: BTreeMap<u64, SourceMigration> = BTreeMap::new();
+    let mut it = tokio::fs::read_dir(src).await?;
+    while let Some(entry) = it.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some((version, name, kind)) = parse_source_name(&file_name) else {
+            continue;
+        };
+        let path = format!("{}/{}", src, file_name);
+        let mig = sources.entry(version).or_default();
+        if !mig.name.is_empty() && mig.name != name {
+            return Err(Error::InvalidInput(format!(
+                "version {} is used by both {} and {}",
+                version, mig.name, name
+            )));
+        }
+        mig.name = name;
+        match kind {
+            Some(true) => mig.up = Some(path),
+            Some(false) => mig.down = Some(path),
+            None => mig.single = Some(path),
+        }
+    }
+    Ok(sources)
+}
+
+/// `20230102150405_add_email.up.sql` -> `(20230102150405, "add_email", Some(true))`
+fn parse_source_name(file_name: &str) -> Option<(u64, String, Option<bool>)> {
+    let stem = file_name.strip_suffix(".sql")?;
+    let (stem, kind) = if let Some(stem) = stem.strip_suffix(".up") {
+        (stem, Some(true))
+    } else if let Some(stem) = stem.strip_suffix(".down") {
+        (stem, Some(false))
+    } else {
+        (stem, None)
+    };
+    let (version, name) = stem.split_once('_')?;
+    let version = version.parse().ok()?;
+    let name = fs::sanitize_name(name);
+    (!name.is_empty()).then_some((version, name, kind))
+}
+
+/// Mode and up/down contents of a migration in chutils' layout.
+async fn convert(
+    tool: SourceTool,
+    mig: &SourceMigration,
+) -> Result<(MigrationFileMode, String, Option<String>), Error> {
+    let read = |path: &String| {
+        let path = path.clone();
+        async move {
+            let raw = tokio::fs::read(&path).await?;
+            Ok::<_, Error>(String::from_utf8_lossy(&raw).into_owned())
+        }
+    };
+    let invalid =
+        |what: &str| Error::InvalidInput(format!("{:?} migration {} {}", tool, mig.name, what));
+
+    match (tool, &mig.single, &mig.up, &mig.down) {
+        (SourceTool::GolangMigrate, None, Some(up), Some(down)) => Ok((
+            MigrationFileMode::Reversible,
+            read(up).await?,
+            Some(read(down).await?),
+        )),
+        (SourceTool::GolangMigrate, None, Some(up), None) => {
+            Ok((MigrationFileMode::Simple, read(up).await?, None))
+        }
+        (SourceTool::Goose, Some(single), None, None) => {
+            let (up, down) = split_goose(&read(single).await?);
+            match crate::sql::split_statements(&down).is_empty() {
+                true => Ok((MigrationFileMode::Simple, up, None)),
+                false => Ok((MigrationFileMode::Reversible, up, Some(down))),
+            }
+        }
+        (SourceTool::ClickhouseMigrations, Some(single), None, None) => {
+            Ok((MigrationFileMode::Simple, read(single).await?, None))
+        }
+        (SourceTool::GolangMigrate, ..) => Err(invalid("needs a .up.sql file")),
+        _ => Err(invalid("must be a single .sql file")),
+    }
+}
+
+/// Split a goose file into its up and down sections, dropping goose annotations.
+fn split_goose(content: &str) -> (String, String) {
+    let (mut up, mut down) = (String::new(), String::new());
+    let mut section = None;
+    for line in content.lines() {
+        let annotation = line.trim().strip_prefix("-- +goose ").map(str::trim);
+        match annotation {
+            Some(a) if a.eq_ignore_ascii_case("up") => section = Some(&mut up),
+            Some(a) if a.eq_ignore_ascii_case("down") => section = Some(&mut down),
+            Some(_) => {}
+            None => {
+                if let Some(out) = section.as_mut() {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+    }
+    (up.trim().to_string() + "\n", down.trim().to_string() + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickhouse::test;
+
+    #[test]
+    fn test_parse_source_name() {
+        assert_eq!(
+            parse_source_name("20230102150405_add_email.up.sql"),
+            Some((20230102150405, "add_email".to_string(), Some(true)))
+        );
+        assert_eq!(
+            parse_source_name("00002_add-phone.sql"),
+            Some((2, "add_phone".to_string(), None))
+        );
+        assert_eq!(parse_source_name("before_all.sql"), None);
+        assert_eq!(parse_source_name("1_.sql"), None);
+    }
+
+    #[test]
+    fn test_split_goose() {
+        let (up, down) = split_goose(
+            "-- +goose Up\n-- +goose StatementBegin\nCREATE TABLE a (id UInt8) ENGINE = Log;\n-- +goose StatementEnd\n\n-- +goose Down\nDROP TABLE a;\n",
+        );
+        assert_eq!(up, "CREATE TABLE a (id UInt8) ENGINE = Log;\n");
+        assert_eq!(down, "DROP TABLE a;\n");
+    }
+
+    #[tokio::test]
+    async fn test_import_goose_history() {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        tokio::fs::write(
+            format!("{}/20240101000000_users.sql", src),
+            "-- +goose Up\nCREATE TABLE users (id UInt64) ENGINE = Log;\n-- +goose Down\nDROP TABLE users;\n",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            format!("{}/20240202000000_posts.sql", src),
+            "-- +goose Up\nCREATE TABLE posts (id UInt64) ENGINE = Log;\n",
+        )
+        .await
+        .unwrap();
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::provide(vec![TimestampedRow {
+            version: 20240101000000,
+            applied_at: chrono::DateTime::from_timestamp(1_704_067_200, 0).unwrap(),
+        }]));
+        let recording = mock.add(test::handlers::record());
+
+        let imported = migrator
+            .import_history(src, SourceTool::Goose, false)
+            .await
+            .unwrap();
+        assert_eq!(imported.len(), 2);
+        assert!(imported[0].applied && !imported[1].applied);
+
+        let files: Vec<_> = fs::list_migrations_strict(src)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|f| (f.seq_num, f.name, f.mode))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (1, "users".to_string(), MigrationFileMode::Reversible),
+                (1, "users".to_string(), MigrationFileMode::Reversible),
+                (2, "posts".to_string(), MigrationFileMode::Simple),
+            ]
+        );
+
+        let rows: Vec<MigrationInfo> = recording.collect().await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].version, 1);
+        assert_eq!(rows[0].name, "users");
+        assert_eq!(rows[0].applied_at.timestamp(), 1_704_067_200);
+    }
+
+    #[tokio::test]
+    async fn test_import_resumes_after_history_failure() {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        let sources = ["20240101000000_users.sql", "20240202000000_posts.sql"];
+        for name in sources {
+            tokio::fs::write(
+                format!("{}/{}", src, name),
+                "CREATE TABLE t (id UInt64) ENGINE = Log;\n",
+            )
+            .await
+            .unwrap();
+        }
+        let goose_rows = || {
+            vec![
+                TimestampedRow {
+                    version: 20240101000000,
+                    applied_at: chrono::DateTime::from_timestamp(1_704_067_200, 0).unwrap(),
+                },
+                TimestampedRow {
+                    version: 20240202000000,
+                    applied_at: chrono::DateTime::from_timestamp(1_706_832_000, 0).unwrap(),
+                },
+            ]
+        };
+
+        // The second history row fails: the goose files stay, the new ones go
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::provide(goose_rows()));
+        let first = mock.add(test::handlers::record());
+        mock.add(test::handlers::failure(test::status::BAD_REQUEST));
+        assert!(
+            migrator
+                .import_history(src, SourceTool::Goose, false)
+                .await
+                .is_err()
+        );
+        let recorded: Vec<MigrationInfo> = first.collect().await;
+        assert_eq!(recorded[0].name, "users");
+        for name in sources {
+            assert!(temp_dir.path().join(name).exists());
+        }
+        assert!(!temp_dir.path().join("0001_users.sql").exists());
+
+        // Running it again skips the row already recorded
+        mock.add(test::handlers::provide(recorded));
+        mock.add(test::handlers::provide(goose_rows()));
+        let second = mock.add(test::handlers::record());
+        migrator
+            .import_history(src, SourceTool::Goose, false)
+            .await
+            .unwrap();
+        let rows: Vec<MigrationInfo> = second.collect().await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].version, rows[0].name.as_str()), (2, "posts"));
+        for name in sources {
+            assert!(!temp_dir.path().join(name).exists());
+        }
+        assert!(temp_dir.path().join("0001_users.sql").exists());
+        assert!(temp_dir.path().join("0002_posts.sql").exists());
+    }
+
+    #[tokio::test]
+    async fn test_import_refuses_unrelated_history() {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        tokio::fs::write(format!("{}/1_users.sql", src), "SELECT 1")
+            .await
+            .unwrap();
+
+        let mut row = MigrationInfo::from(MigrationFile {
+            path: format!("{}/0001_accounts.sql", src),
+            name: "accounts".to_string(),
+            mode: MigrationFileMode::Simple,
+            src: src.to_string(),
+            is_up: true,
+            seq_num: 1,
+        });
+        row.status = MigrationStatus::Applied;
+        mock.add(test::handlers::provide(vec![row]));
+        mock.add(test::handlers::provide(Vec::<TimestampedRow>::new()));
+        assert!(matches!(
+            migrator.import_history(src, SourceTool::ClickhouseMigrations, false).await,
+            Err(Error::InvalidInput(msg)) if msg.contains("accounts")
+        ));
+    }
+}
@@ -1,10 +1,49 @@
+mod audit;
+mod backfill;
+mod database;
+mod diff;
 pub mod error;
+mod fanout;
 mod fs;
+mod gendown;
+mod history;
+mod hooks;
+mod import;
+mod lint;
+mod observer;
+mod renumber;
+mod report;
+mod roundtrip;
+mod scratch;
+mod shadow;
+mod sql;
+mod squash;
+mod status;
+mod transaction;
+mod validate;
 
 use ch::clickhouse;
 
-pub use error::Error;
+pub use audit::QueryLogEntry;
+pub use backfill::{BackfillChunks, BackfillOptions, BackfillReport};
+pub use database::{CreateDatabaseOptions, DatabaseEngine};
+pub use diff::{DiffBase, SchemaChanges};
+pub use error::{Error, LayoutIssue};
+pub use fanout::{FailurePolicy, FanOutOptions, TargetOutcome, TargetReport, run_targets};
+pub use gendown::{GeneratedDown, gen_down};
+pub use history::{ClickhouseHistory, HistoryStore, JsonFileHistory, MemoryHistory};
+pub use hooks::HookPoint;
+pub use import::{ImportedMigration, SourceTool};
+pub use lint::{LintConfig, LintFinding, LintRule, Severity, lint};
+pub use observer::{Direction, MigrationEvent, Observer};
+pub use renumber::Renumbered;
+pub use report::{ChecksumState, REPORT_SCHEMA_VERSION, Report, ReportEntry};
+pub use roundtrip::{RoundtripOutcome, RoundtripReport, SchemaDiff};
+pub use shadow::{ShadowReport, ShadowStatement};
+pub use squash::SquashReport;
+pub use status::SchemaState;
 use std::{collections::BTreeMap, sync::Arc};
+pub use validate::ValidationError;
 
 #[async_trait::async_trait]
 pub trait Migration: Send + Sync {
@@ -12,33 +51,9 @@ pub trait Migration: Send + Sync {
 
     async fn ping(&self) -> Result<(), Error>;
 
-    /// Create a new migration file to the source directory.
-    /// If latest migration is reversible, new one will be too (unless the file mode
-    /// is MigrationFileMode::Simple).
-    /// Returns a list of migration files if success
-    async fn add(
-        src: &str,
-        name: &str,
-        mode: Option<MigrationFileMode>,
-    ) -> Result<Vec<String>, Error> {
-        fs::gen_migration_file(src, name, mode).await
-    }
-
-    async fn run(
-        &self,
-        src: &str,
-        dry_run: bool,
-        ignore_missing: bool,
-        target_version: Option<u32>,
-    ) -> Result<Vec<MigrationInfo>, Error>;
+    async fn run(&self, src: &str, options: RunOptions) -> Result<Vec<MigrationInfo>, Error>;
 
-    async fn revert(
-        &self,
-        src: &str,
-        dry_run: bool,
-        ignore_missing: bool,
-        target_version: Option<u32>,
-    ) -> Result<Vec<MigrationInfo>, Error>;
+    async fn revert(&self, src: &str, options: RevertOptions) -> Result<Vec<MigrationInfo>, Error>;
 
     async fn info(&self, src: &str, ignore_missing: bool) -> Result<Vec<MigrationInfo>, Error>;
 }
@@ -66,6 +81,21 @@ pub struct MigrationInfo {
     pub status: MigrationStatus,
     #[serde(with = "ch::clickhouse::serde::chrono::datetime")]
     pub applied_at: chrono::DateTime<chrono::Utc>,
+    /// How long the up migration took, 0 if not applied
+    pub duration_ms: u64,
+    /// OS user that applied the migration
+    pub applied_by: String,
+    /// Machine the migration was applied from
+    pub host: String,
+    /// `info::version()` of the build that applied the migration
+    pub chutils_version: String,
+    /// SHA-256 of the up file when the migration was applied, empty for pending
+    /// migrations and for rows recorded before checksums existed
+    pub checksum: String,
+    /// Whether the migration and its history row were committed in one server
+    /// transaction, see `Migrator::with_transactions`
+    #[serde(default)]
+    pub transactional: bool,
 
     #[serde(skip)]
     mode: MigrationFileMode,
@@ -94,9 +124,76 @@ pub struct MigrationFile {
     pub seq_num: u32,
 }
 
+/// Options for `Migration::run`, defaults apply every pending migration.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    pub dry_run: bool,
+    pub ignore_missing: bool,
+    /// Apply migrations up to this version (inclusive)
+    pub target_version: Option<u32>,
+}
+
+/// Options for `Migration::revert`, defaults revert only the latest migration.
+#[derive(Debug, Clone, Default)]
+pub struct RevertOptions {
+    pub dry_run: bool,
+    pub ignore_missing: bool,
+    /// Revert every migration after this version (exclusive)
+    pub target_version: Option<u32>,
+}
+
+impl RunOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn ignore_missing(mut self, ignore_missing: bool) -> Self {
+        self.ignore_missing = ignore_missing;
+        self
+    }
+
+    pub fn target_version(mut self, version: Option<u32>) -> Self {
+        self.target_version = version;
+        self
+    }
+}
+
+impl RevertOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn ignore_missing(mut self, ignore_missing: bool) -> Self {
+        self.ignore_missing = ignore_missing;
+        self
+    }
+
+    pub fn target_version(mut self, version: Option<u32>) -> Self {
+        self.target_version = version;
+        self
+    }
+}
+
 #[derive(Clone)]
 pub struct Migrator {
     inner: Arc<clickhouse::Client>,
+    observer: Option<Arc<dyn Observer>>,
+    hooks: Vec<(HookPoint, String)>,
+    /// `None` keeps the history in the `_ch_migrations` table of `inner`
+    history: Option<Arc<dyn HistoryStore>>,
+    transactions: bool,
+    /// Set when running in a scratch database, see `ScratchDatabase::migrator`
+    scratch: Option<Arc<scratch::ScratchScope>>,
 }
 
 impl Migrator {
@@ -105,38 +202,182 @@ impl Migrator {
     pub fn from_client(client: clickhouse::Client) -> Self {
         Self {
             inner: Arc::new(client),
+            observer: None,
+            hooks: vec![],
+            history: None,
+            transactions: false,
+            scratch: None,
         }
     }
+
+    /// Report progress of `run` and `revert` to `observer`.
+    pub fn with_observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Keep the history in `history` instead of the `_ch_migrations` table.
+    /// Migrations themselves still run through the ClickHouse client.
+    pub fn with_history(mut self, history: impl HistoryStore + 'static) -> Self {
+        self.history = Some(Arc::new(history));
+        self
+    }
+
+    /// Create a new migration file to the source directory.
+    /// If latest migration is reversible, new one will be too (unless the file mode
+    /// is MigrationFileMode::Simple).
+    /// Returns a list of migration files if success
+    pub async fn add(
+        src: &str,
+        name: &str,
+        mode: Option<MigrationFileMode>,
+    ) -> Result<Vec<String>, Error> {
+        fs::gen_migration_file(src, name, mode).await
+    }
 }
 
 impl Migrator {
-    async fn execute_migration(&self, info: &MigrationInfo, is_up: bool) -> Result<(), Error> {
-        let raw = tokio::fs::read(&info.file_path(is_up)).await?;
+    pub(crate) fn history(&self) -> Arc<dyn HistoryStore> {
+        match &self.history {
+            Some(history) => history.clone(),
+            None => Arc::new(ClickhouseHistory::from_arc(self.inner.clone())),
+        }
+    }
+
+    /// Run `f` holding the history lock, releasing it whatever the outcome.
+    async fn with_lock<T>(
+        &self,
+        f: impl std::future::Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let history = self.history();
+        history.lock().await?;
+        self.emit(MigrationEvent::LockAcquired);
+        let result = f.await;
+        let unlocked = history.unlock().await;
+        let value = result?;
+        unlocked?;
+        Ok(value)
+    }
+
+    fn emit(&self, event: MigrationEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    /// Run the up or down file of a migration, tagging its queries with `run_id`.
+    async fn execute_migration(
+        &self,
+        info: &MigrationInfo,
+        is_up: bool,
+        run_id: &str,
+    ) -> Result<(), Error> {
+        let direction = if is_up {
+            Direction::Up
+        } else {
+            Direction::Down
+        };
+        self.emit(MigrationEvent::MigrationStarted {
+            version: info.version,
+            name: info.name.clone(),
+            direction,
+        });
+
+        let file = info.file_path(is_up);
+        let tag = audit::StatementTag {
+            run_id,
+            version: info.version,
+            name: &info.name,
+            file: &file,
+            direction,
+        };
+        let started = std::time::Instant::now();
+        let result = self.execute_file(&file, Some(&tag)).await;
+
+        let (version, name, duration) = (info.version, info.name.clone(), started.elapsed());
+        self.emit(match (&result, direction) {
+            (Err(err), _) => MigrationEvent::MigrationFailed {
+                version,
+                name,
+                direction,
+                error: err.to_string(),
+            },
+            (Ok(_), Direction::Up) => MigrationEvent::MigrationApplied {
+                version,
+                name,
+                duration,
+            },
+            (Ok(_), Direction::Down) => MigrationEvent::MigrationReverted {
+                version,
+                name,
+                duration,
+            },
+        });
+        result
+    }
+
+    /// Run every statement of a SQL file in order.
+    /// Statements of a migration file (`tag` is set) get a `query_id` and `log_comment`,
+    /// and their failures are reported as `Error::StatementFailed`. In a scratch
+    /// database, the whole file is rewritten (or refused) before anything runs.
+    async fn execute_file(
+        &self,
+        path: &str,
+        tag: Option<&audit::StatementTag<'_>>,
+    ) -> Result<(), Error> {
+        let version = tag.map(|t| t.version);
+        let raw = tokio::fs::read(path).await?;
         let content = String::from_utf8_lossy(&raw).to_string();
 
-        // Process content: remove full-line comments, then split by semicolon
-        let queries: Vec<String> = content
-            .split(';')
-            .map(|stmt| {
-                // For each statement, filter out comment-only lines but keep inline comments
-                // (ClickHouse handles inline comments fine)
-                stmt.lines()
-                    .filter(|line| {
-                        let trimmed = line.trim();
-                        // Keep non-empty lines that don't start with --
-                        !trimmed.is_empty() && !trimmed.starts_with("--")
-                    })
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            })
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
+        let mut statements: Vec<String> = sql::split_statements(&content)
+            .into_iter()
+            .map(|s| s.sql)
             .collect();
+        if let Some(scope) = &self.scratch {
+            statements = statements
+                .iter()
+                .map(|stmt| scope.rewrite(stmt))
+                .collect::<Result<_, _>>()?;
+        }
 
-        for query in queries {
-            self.inner.query(&query).execute().await.inspect_err(|err| {
-                tracing::debug!(error=?err,%query, version=info.full_version(), "Failed to execute query");
-            })?;
+        for (index, query) in statements.into_iter().enumerate() {
+            if let Some(version) = version {
+                self.emit(MigrationEvent::StatementStarted {
+                    version,
+                    index,
+                    sql: query.clone(),
+                });
+            }
+
+            let started = std::time::Instant::now();
+            let mut q = self.inner.query(&query);
+            if let Some(tag) = tag {
+                q = q
+                    .with_option("query_id", tag.query_id(index))
+                    .with_option("log_comment", tag.log_comment(index));
+            }
+            let result = q.execute().await;
+            let Err(err) = result else {
+                if let Some(version) = version {
+                    self.emit(MigrationEvent::StatementFinished {
+                        version,
+                        index,
+                        duration: started.elapsed(),
+                    });
+                }
+                continue;
+            };
+
+            tracing::debug!(error=?err, %query, file=path, ?version, "Failed to execute query");
+            return Err(match version {
+                Some(version) => Error::StatementFailed {
+                    version,
+                    file: path.to_string(),
+                    statement_index: index,
+                    source: err,
+                },
+                None => err.into(),
+            });
         }
         Ok(())
     }
@@ -145,21 +386,7 @@ impl Migrator {
 #[async_trait::async_trait]
 impl Migration for Migrator {
     async fn ensure_migrations_table(&self) -> Result<(), Error> {
-        self.inner
-            .query(
-                "
-            CREATE TABLE IF NOT EXISTS _ch_migrations (
-                version UInt32,
-                name String,
-                status Enum('pending' = 1, 'applied' = 2),
-                applied_at DateTime DEFAULT now()
-                ) ENGINE = MergeTree()
-            ORDER BY(applied_at, version)
-            ",
-            )
-            .execute()
-            .await?;
-        Ok(())
+        self.history().ensure().await
     }
 
     async fn ping(&self) -> Result<(), Error> {
@@ -167,15 +394,33 @@ impl Migration for Migrator {
         Ok(())
     }
 
-    async fn run(
+    async fn run(&self, src: &str, options: RunOptions) -> Result<Vec<MigrationInfo>, Error> {
+        if options.dry_run {
+            return self.run_unlocked(src, options).await;
+        }
+        self.with_lock(self.run_unlocked(src, options)).await
+    }
+
+    async fn revert(&self, src: &str, options: RevertOptions) -> Result<Vec<MigrationInfo>, Error> {
+        if options.dry_run {
+            return self.revert_unlocked(src, options).await;
+        }
+        self.with_lock(self.revert_unlocked(src, options)).await
+    }
+
+    async fn info(&self, src: &str, ignore_missing: bool) -> Result<Vec<MigrationInfo>, Error> {
+        self.load_info(src, ignore_missing).await
+    }
+}
+
+impl Migrator {
+    async fn run_unlocked(
         &self,
         src: &str,
-        dry_run: bool,
-        ignore_missing: bool,
-        target_version: Option<u32>,
+        options: RunOptions,
     ) -> Result<Vec<MigrationInfo>, Error> {
         // Load all migrations in the src folder
-        let migs = self.info(src, ignore_missing).await?;
+        let migs = self.info(src, options.ignore_missing).await?;
 
         let max_applied = migs
             .iter()
@@ -192,10 +437,10 @@ impl Migration for Migrator {
         // Validate no pending migration should be older than max_applied
         for mig in &pending {
             if mig.version < max_applied {
-                return Err(Error::MigrationCorrupted(format!(
-                    "migration out of order pending version: {}, latest version: {}",
-                    mig.version, max_applied
-                )));
+                return Err(Error::OutOfOrder {
+                    pending: mig.version,
+                    latest_applied: max_applied,
+                });
             }
         }
 
@@ -203,35 +448,61 @@ impl Migration for Migrator {
             return Ok(pending);
         }
 
-        if let Some(version) = target_version {
+        if let Some(version) = options.target_version {
             pending.retain(|mig| mig.version <= version);
         }
 
-        if dry_run {
+        if pending.is_empty() || options.dry_run {
             return Ok(pending);
         }
 
-        let mut insert = self.inner.insert::<MigrationInfo>("_ch_migrations")?;
-
+        let (applied_by, host) = (current_user(), current_host());
+        let run_id = audit::new_run_id();
+        let transactional = self.transactions_supported(&run_id).await;
+        self.run_hooks(src, HookPoint::BeforeAll, None, Direction::Up)
+            .await?;
         for mig in pending.iter_mut() {
-            self.execute_migration(mig, true).await?;
-            mig.status = MigrationStatus::Applied;
-            mig.applied_at = chrono::Utc::now();
-            insert.write(mig).await?;
+            self.run_hooks(src, HookPoint::BeforeEach, Some(mig), Direction::Up)
+                .await?;
+
+            mig.checksum = fs::checksum(&mig.file_path(true)).await?;
+            let session = self
+                .begin_transaction(transactional, &run_id, mig, Direction::Up)
+                .await?;
+            let target = session.as_ref().unwrap_or(self);
+            let result = async {
+                let started = std::time::Instant::now();
+                target.execute_migration(mig, true, &run_id).await?;
+                mig.status = MigrationStatus::Applied;
+                mig.applied_at = chrono::Utc::now();
+                mig.duration_ms = started.elapsed().as_millis() as u64;
+                mig.applied_by = applied_by.clone();
+                mig.host = host.clone();
+                mig.chutils_version = info::version().to_string();
+                mig.transactional = session.is_some();
+
+                // Record each migration right away so a failing hook or later migration
+                // doesn't lose the history of the ones already applied
+                target.history().record_applied(mig).await
+            }
+            .await;
+            transaction::end_transaction(session, result).await?;
+
+            self.run_hooks(src, HookPoint::AfterEach, Some(mig), Direction::Up)
+                .await?;
         }
+        self.run_hooks(src, HookPoint::AfterAll, None, Direction::Up)
+            .await?;
 
-        insert.end().await?;
         Ok(pending)
     }
 
-    async fn revert(
+    async fn revert_unlocked(
         &self,
         src: &str,
-        dry_run: bool,
-        ignore_missing: bool,
-        target_version: Option<u32>,
+        options: RevertOptions,
     ) -> Result<Vec<MigrationInfo>, Error> {
-        let migs = self.info(src, ignore_missing).await?;
+        let migs = self.info(src, options.ignore_missing).await?;
 
         let mut targets = vec![];
         for mig in migs.into_iter().rev() {
@@ -242,53 +513,99 @@ impl Migration for Migrator {
             }
         }
 
-        if let Some(version) = target_version {
+        if let Some(version) = options.target_version {
             targets.retain(|mig| mig.version > version);
         } else {
             targets.truncate(1);
         }
 
-        if targets.is_empty() || dry_run {
+        if targets.is_empty() || options.dry_run {
             return Ok(targets);
         }
 
+        let run_id = audit::new_run_id();
+        let transactional = self.transactions_supported(&run_id).await;
+        self.run_hooks(src, HookPoint::BeforeAll, None, Direction::Down)
+            .await?;
         for mig in targets.iter_mut() {
-            self.execute_migration(mig, false).await?;
-
-            self.inner
-                .query("DELETE FROM _ch_migrations WHERE version = ?")
-                .bind(mig.version)
-                .execute()
+            self.run_hooks(src, HookPoint::BeforeEach, Some(mig), Direction::Down)
+                .await?;
+            let session = self
+                .begin_transaction(transactional, &run_id, mig, Direction::Down)
                 .await?;
+            let target = session.as_ref().unwrap_or(self);
+            let result = async {
+                target.execute_migration(mig, false, &run_id).await?;
+                target.history().record_reverted(mig.version).await
+            }
+            .await;
+            transaction::end_transaction(session, result).await?;
+
             mig.status = MigrationStatus::Pending;
             mig.applied_at = chrono::Utc::now();
+            mig.duration_ms = 0;
+            mig.applied_by.clear();
+            mig.host.clear();
+            mig.chutils_version.clear();
+            mig.checksum.clear();
+            mig.transactional = false;
+
+            self.run_hooks(src, HookPoint::AfterEach, Some(mig), Direction::Down)
+                .await?;
         }
+        self.run_hooks(src, HookPoint::AfterAll, None, Direction::Down)
+            .await?;
 
         Ok(targets)
     }
 
-    async fn info(&self, src: &str, ignore_missing: bool) -> Result<Vec<MigrationInfo>, Error> {
-        let mut migrations: BTreeMap<u32, MigrationInfo> = fs::list_migrations(src)
+    async fn load_info(
+        &self,
+        src: &str,
+        ignore_missing: bool,
+    ) -> Result<Vec<MigrationInfo>, Error> {
+        let mut migrations: BTreeMap<u32, MigrationInfo> = fs::list_migrations_strict(src)
             .await?
             .into_iter()
             .map(|mf| (mf.seq_num, mf.into()))
             .collect();
 
-        let mut cursor = self
-            .inner
-            .query("SELECT version, name, status, applied_at FROM _ch_migrations")
-            .fetch::<MigrationInfo>()?;
+        // Versions squashed into a local baseline, and the history rows they match
+        let mut baselines = vec![];
+        for mig in migrations.values() {
+            if mig.name == squash::BASELINE_NAME && mig.mode == MigrationFileMode::Simple {
+                if let Some(range) = fs::baseline_range(&mig.file_path(true)).await? {
+                    baselines.push((mig.version, range, vec![]));
+                }
+            }
+        }
+
+        for info in self.history().load().await? {
+            let squashed = baselines.iter_mut().find(|(version, range, _)| {
+                range.contains(&info.version)
+                    && !(info.version == *version && info.name == squash::BASELINE_NAME)
+            });
+            if let Some((_, _, rows)) = squashed {
+                rows.push(info);
+                continue;
+            }
 
-        while let Some(info) = cursor.next().await? {
             if let Some(mig) = migrations.get_mut(&info.version) {
                 if mig.name != info.name {
-                    return Err(Error::MigrationCorrupted(format!(
-                        "Migration name miss match in db: '{}', local: '{}'",
-                        info.name, mig.name
-                    )));
+                    return Err(Error::NameMismatch {
+                        version: info.version,
+                        db_name: info.name,
+                        local_name: mig.name.clone(),
+                    });
                 }
                 mig.status = info.status;
                 mig.applied_at = info.applied_at;
+                mig.duration_ms = info.duration_ms;
+                mig.applied_by = info.applied_by;
+                mig.host = info.host;
+                mig.chutils_version = info.chutils_version;
+                mig.checksum = info.checksum;
+                mig.transactional = info.transactional;
                 continue;
             }
 
@@ -297,10 +614,37 @@ impl Migration for Migrator {
                 continue;
             }
 
-            return Err(Error::MigrationCorrupted(format!(
-                "Migration {} (version={}) is existing in db but not found in local",
-                info.name, info.version
-            )));
+            return Err(Error::MissingLocal {
+                version: info.version,
+                name: info.name,
+            });
+        }
+
+        // A database that applied every squashed version has the baseline applied
+        for (version, range, rows) in baselines {
+            let Some(latest) = rows.iter().max_by_key(|r| r.version) else {
+                continue;
+            };
+            let missing: Vec<_> = range
+                .clone()
+                .filter(|v| !rows.iter().any(|r| r.version == *v))
+                .collect();
+            if !missing.is_empty() {
+                return Err(Error::PartialBaseline {
+                    version,
+                    from: *range.start(),
+                    missing,
+                });
+            }
+
+            if let Some(mig) = migrations.get_mut(&version) {
+                mig.status = MigrationStatus::Applied;
+                mig.applied_at = latest.applied_at;
+                mig.duration_ms = latest.duration_ms;
+                mig.applied_by = latest.applied_by.clone();
+                mig.host = latest.host.clone();
+                mig.chutils_version = latest.chutils_version.clone();
+            }
         }
 
         Ok(migrations.into_values().collect())
@@ -323,6 +667,12 @@ impl From<MigrationFile> for MigrationInfo {
             version: value.seq_num,
             status: MigrationStatus::Pending,
             applied_at: chrono::Utc::now(),
+            duration_ms: 0,
+            applied_by: String::new(),
+            host: String::new(),
+            chutils_version: String::new(),
+            checksum: String::new(),
+            transactional: false,
 
             mode: value.mode,
             src: value.src,
@@ -330,6 +680,16 @@ impl From<MigrationFile> for MigrationInfo {
     }
 }
 
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+fn current_host() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
 impl TryFrom<ch::Builder> for Migrator {
     type Error = ch::Error;
     fn try_from(value: ch::Builder) -> Result<Self, Self::Error> {
@@ -343,6 +703,26 @@ mod tests {
     use super::*;
     use clickhouse::test;
 
+    /// A local migration as `info` would list it before it is applied.
+    pub(crate) fn migration(version: u32, name: &str, mode: MigrationFileMode) -> MigrationInfo {
+        MigrationInfo::from(MigrationFile {
+            path: String::new(),
+            name: name.to_string(),
+            mode,
+            src: String::new(),
+            is_up: true,
+            seq_num: version,
+        })
+    }
+
+    /// A history row; tests override the metadata they check.
+    pub(crate) fn applied(version: u32, name: &str, mode: MigrationFileMode) -> MigrationInfo {
+        MigrationInfo {
+            status: MigrationStatus::Applied,
+            ..migration(version, name, mode)
+        }
+    }
+
     // ==================== MigrationStatus tests ====================
 
     #[test]
@@ -362,12 +742,8 @@ mod tests {
     #[test]
     fn test_migration_info_full_version() {
         let info = MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Pending,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
             src: "migrations".to_string(),
+            ..migration(1, "create_users", MigrationFileMode::Simple)
         };
         assert_eq!(info.full_version(), "0001_create_users");
     }
@@ -375,12 +751,8 @@ mod tests {
     #[test]
     fn test_migration_info_full_version_large_number() {
         let info = MigrationInfo {
-            version: 12345,
-            name: "test".to_string(),
-            status: MigrationStatus::Pending,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
             src: "migrations".to_string(),
+            ..migration(12345, "test", MigrationFileMode::Simple)
         };
         assert_eq!(info.full_version(), "12345_test");
     }
@@ -388,12 +760,8 @@ mod tests {
     #[test]
     fn test_migration_info_file_path_simple() {
         let info = MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Pending,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
             src: "migrations".to_string(),
+            ..migration(1, "create_users", MigrationFileMode::Simple)
         };
         // Simple mode ignores is_up
         assert_eq!(info.file_path(true), "migrations/0001_create_users.sql");
@@ -403,12 +771,8 @@ mod tests {
     #[test]
     fn test_migration_info_file_path_reversible() {
         let info = MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Pending,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Reversible,
             src: "migrations".to_string(),
+            ..migration(1, "create_users", MigrationFileMode::Reversible)
         };
         assert_eq!(info.file_path(true), "migrations/0001_create_users.up.sql");
         assert_eq!(
@@ -438,6 +802,31 @@ mod tests {
         assert_eq!(info.src, "migrations");
     }
 
+    // ==================== Options tests ====================
+
+    #[test]
+    fn test_run_options_builder() {
+        let options = RunOptions::default();
+        assert!(!options.dry_run);
+        assert!(!options.ignore_missing);
+        assert_eq!(options.target_version, None);
+
+        let options = RunOptions::new()
+            .dry_run(true)
+            .ignore_missing(true)
+            .target_version(Some(3));
+        assert!(options.dry_run);
+        assert!(options.ignore_missing);
+        assert_eq!(options.target_version, Some(3));
+    }
+
+    #[test]
+    fn test_revert_options_builder() {
+        let options = RevertOptions::new().target_version(Some(0));
+        assert!(!options.dry_run);
+        assert_eq!(options.target_version, Some(0));
+    }
+
     // ==================== Migrator with mock tests ====================
 
     fn create_mock_migrator(mock: &test::Mock) -> Migrator {
@@ -445,6 +834,16 @@ mod tests {
         Migrator::from_client(client)
     }
 
+    #[tokio::test]
+    async fn test_migrator_as_trait_object() {
+        let mock = test::Mock::new();
+        let migrator: Box<dyn Migration> = Box::new(create_mock_migrator(&mock));
+
+        mock.add(test::handlers::record_ddl());
+
+        assert!(migrator.ping().await.is_ok());
+    }
+
     #[tokio::test]
     async fn test_ping_success() {
         let mock = test::Mock::new();
@@ -473,12 +872,15 @@ mod tests {
         let migrator = create_mock_migrator(&mock);
 
         let recording = mock.add(test::handlers::record_ddl());
+        let upgrade = mock.add(test::handlers::record_ddl());
 
         let result = migrator.ensure_migrations_table().await;
         assert!(result.is_ok());
 
         let query = recording.query().await;
         assert!(query.contains("CREATE TABLE IF NOT EXISTS _ch_migrations"));
+        let query = upgrade.query().await;
+        assert!(query.contains("ADD COLUMN IF NOT EXISTS duration_ms"));
     }
 
     #[tokio::test]
@@ -541,12 +943,10 @@ mod tests {
 
         // Mock response with first migration applied
         let applied = vec![MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Applied,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
-            src: String::new(),
+            duration_ms: 42,
+            applied_by: "deployer".to_string(),
+            host: "ci-runner".to_string(),
+            ..applied(1, "create_users", MigrationFileMode::Simple)
         }];
         mock.add(test::handlers::provide(applied));
 
@@ -557,6 +957,53 @@ mod tests {
         assert_eq!(migrations.len(), 2);
         assert_eq!(migrations[0].status, MigrationStatus::Applied);
         assert_eq!(migrations[1].status, MigrationStatus::Pending);
+        assert_eq!(migrations[0].duration_ms, 42);
+        assert_eq!(migrations[0].applied_by, "deployer");
+        assert_eq!(migrations[0].host, "ci-runner");
+    }
+
+    #[tokio::test]
+    async fn test_info_baseline_covers_squashed_history() {
+        let mock = test::Mock::new();
+        let migrator = create_mock_migrator(&mock);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        tokio::fs::write(
+            format!("{}/0002_baseline.sql", src),
+            "-- chutils:baseline from=1 to=2\nCREATE TABLE users (id UInt64) ENGINE = Log;",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(format!("{}/0003_create_posts.sql", src), "SELECT 1")
+            .await
+            .unwrap();
+
+        // Fully migrated database: the baseline counts as applied
+        mock.add(test::handlers::provide(vec![
+            applied(1, "create_users", MigrationFileMode::Simple),
+            MigrationInfo {
+                duration_ms: 2,
+                ..applied(2, "add_email", MigrationFileMode::Simple)
+            },
+        ]));
+        let migrations = migrator.info(src, false).await.unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert_eq!(migrations[0].status, MigrationStatus::Applied);
+        assert_eq!(migrations[0].duration_ms, 2);
+        assert_eq!(migrations[1].status, MigrationStatus::Pending);
+
+        // Database stopped halfway through the squashed range
+        mock.add(test::handlers::provide(vec![applied(
+            1,
+            "create_users",
+            MigrationFileMode::Simple,
+        )]));
+        let err = migrator.info(src, false).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::PartialBaseline { version: 2, from: 1, ref missing } if missing == &[2]
+        ));
     }
 
     #[tokio::test]
@@ -576,19 +1023,23 @@ mod tests {
         .unwrap();
 
         // Mock response with different name for same version
-        let applied = vec![MigrationInfo {
-            version: 1,
-            name: "different_name".to_string(), // Mismatch!
-            status: MigrationStatus::Applied,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
-            src: String::new(),
-        }];
+        // Name mismatch with the local file
+        let applied = vec![applied(1, "different_name", MigrationFileMode::Simple)];
         mock.add(test::handlers::provide(applied));
 
         let result = migrator.info(src, false).await;
-        assert!(result.is_err());
-        assert!(matches!(result.unwrap_err(), Error::MigrationCorrupted(_)));
+        match result.unwrap_err() {
+            Error::NameMismatch {
+                version,
+                db_name,
+                local_name,
+            } => {
+                assert_eq!(version, 1);
+                assert_eq!(db_name, "different_name");
+                assert_eq!(local_name, "create_users");
+            }
+            err => panic!("unexpected error: {}", err),
+        }
     }
 
     #[tokio::test]
@@ -600,19 +1051,15 @@ mod tests {
         let src = temp_dir.path().to_str().unwrap();
 
         // No local files, but db has applied migration
-        let applied = vec![MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Applied,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
-            src: String::new(),
-        }];
+        let applied = vec![applied(1, "create_users", MigrationFileMode::Simple)];
         mock.add(test::handlers::provide(applied));
 
         let result = migrator.info(src, false).await;
-        assert!(result.is_err());
-        assert!(matches!(result.unwrap_err(), Error::MigrationCorrupted(_)));
+        let err = result.unwrap_err();
+        assert!(
+            matches!(err, Error::MissingLocal { version: 1, ref name } if name == "create_users")
+        );
+        assert_eq!(err.code(), "missing_local");
     }
 
     #[tokio::test]
@@ -624,14 +1071,7 @@ mod tests {
         let src = temp_dir.path().to_str().unwrap();
 
         // No local files, but db has applied migration
-        let applied = vec![MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Applied,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
-            src: String::new(),
-        }];
+        let applied = vec![applied(1, "create_users", MigrationFileMode::Simple)];
         mock.add(test::handlers::provide(applied));
 
         // With ignore_missing = true
@@ -658,7 +1098,7 @@ mod tests {
 
         mock.add(test::handlers::provide::<MigrationInfo>(vec![]));
 
-        let result = migrator.run(src, true, false, None).await;
+        let result = migrator.run(src, RunOptions::new().dry_run(true)).await;
         assert!(result.is_ok());
 
         let pending = result.unwrap();
@@ -684,17 +1124,10 @@ mod tests {
         .unwrap();
 
         // All migrations already applied
-        let applied = vec![MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Applied,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
-            src: String::new(),
-        }];
+        let applied = vec![applied(1, "create_users", MigrationFileMode::Simple)];
         mock.add(test::handlers::provide(applied));
 
-        let result = migrator.run(src, false, false, None).await;
+        let result = migrator.run(src, RunOptions::new()).await;
         assert!(result.is_ok());
         assert!(result.unwrap().is_empty());
     }
@@ -720,7 +1153,9 @@ mod tests {
         mock.add(test::handlers::provide::<MigrationInfo>(vec![]));
 
         // Only run up to version 2
-        let result = migrator.run(src, true, false, Some(2)).await;
+        let result = migrator
+            .run(src, RunOptions::new().dry_run(true).target_version(Some(2)))
+            .await;
         assert!(result.is_ok());
 
         let pending = result.unwrap();
@@ -749,29 +1184,21 @@ mod tests {
 
         // Version 3 is applied but version 2 is not (out of order)
         let applied = vec![
-            MigrationInfo {
-                version: 1,
-                name: "first".to_string(),
-                status: MigrationStatus::Applied,
-                applied_at: chrono::Utc::now(),
-                mode: MigrationFileMode::Simple,
-                src: String::new(),
-            },
-            MigrationInfo {
-                version: 3,
-                name: "third".to_string(),
-                status: MigrationStatus::Applied,
-                applied_at: chrono::Utc::now(),
-                mode: MigrationFileMode::Simple,
-                src: String::new(),
-            },
+            applied(1, "first", MigrationFileMode::Simple),
+            applied(3, "third", MigrationFileMode::Simple),
         ];
         mock.add(test::handlers::provide(applied));
 
-        let result = migrator.run(src, false, false, None).await;
+        let result = migrator.run(src, RunOptions::new()).await;
         assert!(result.is_err());
         let err = result.unwrap_err();
-        assert!(matches!(err, Error::MigrationCorrupted(_)));
+        assert!(matches!(
+            err,
+            Error::OutOfOrder {
+                pending: 2,
+                latest_applied: 3
+            }
+        ));
         assert!(err.to_string().contains("out of order"));
     }
 
@@ -797,7 +1224,7 @@ mod tests {
         // Insert recording
         let insert_recording = mock.add(test::handlers::record());
 
-        let result = migrator.run(src, false, false, None).await;
+        let result = migrator.run(src, RunOptions::new()).await;
         assert!(result.is_ok());
 
         let applied = result.unwrap();
@@ -808,6 +1235,137 @@ mod tests {
         let inserted: Vec<MigrationInfo> = insert_recording.collect().await;
         assert_eq!(inserted.len(), 1);
         assert_eq!(inserted[0].version, 1);
+        assert_eq!(inserted[0].chutils_version, info::version());
+        assert!(!inserted[0].host.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_notifies_observer() {
+        let mock = test::Mock::new();
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = events.clone();
+        let migrator = create_mock_migrator(&mock).with_observer(move |e: &MigrationEvent| {
+            sink.lock().unwrap().push(e.clone());
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        tokio::fs::write(
+            format!("{}/0001_create_users.sql", src),
+            b"CREATE TABLE users (id Int32) ENGINE = Memory;\nCREATE TABLEE broken",
+        )
+        .await
+        .unwrap();
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::failure(test::status::BAD_REQUEST));
+
+        assert!(migrator.run(src, RunOptions::new()).await.is_err());
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 6);
+        assert!(matches!(events[0], MigrationEvent::LockAcquired));
+        assert!(matches!(
+            events[1],
+            MigrationEvent::MigrationStarted {
+                version: 1,
+                direction: Direction::Up,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events[2],
+            MigrationEvent::StatementStarted { index: 0, .. }
+        ));
+        assert!(matches!(
+            events[3],
+            MigrationEvent::StatementFinished { index: 0, .. }
+        ));
+        assert!(matches!(
+            events[4],
+            MigrationEvent::StatementStarted { index: 1, .. }
+        ));
+        assert!(matches!(
+            events[5],
+            MigrationEvent::MigrationFailed { version: 1, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_run_executes_hooks() {
+        let mock = test::Mock::new();
+        let migrator = create_mock_migrator(&mock).with_hook_command(
+            HookPoint::AfterEach,
+            "test \"$CHUTILS_MIGRATION_NAME\" = first && exit 3",
+        );
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        tokio::fs::write(format!("{}/0001_first.sql", src), b"SELECT 1")
+            .await
+            .unwrap();
+        tokio::fs::write(format!("{}/before_each.sql", src), b"SYSTEM STOP MERGES")
+            .await
+            .unwrap();
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        let hook_recording = mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        let insert_recording = mock.add(test::handlers::record());
+
+        let err = migrator.run(src, RunOptions::new()).await.unwrap_err();
+        match &err {
+            Error::HookFailed { hook, version, .. } => {
+                assert!(hook.contains("exit 3"));
+                assert_eq!(*version, Some(1));
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+
+        assert!(hook_recording.query().await.contains("SYSTEM STOP MERGES"));
+        // The migration itself was recorded before the failing hook ran
+        let inserted: Vec<MigrationInfo> = insert_recording.collect().await;
+        assert_eq!(inserted.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_failing_statement() {
+        let mock = test::Mock::new();
+        let migrator = create_mock_migrator(&mock);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        tokio::fs::write(
+            format!("{}/0001_create_users.sql", src),
+            b"CREATE TABLE users (id Int32) ENGINE = Memory;\nCREATE TABLEE broken",
+        )
+        .await
+        .unwrap();
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::failure(test::status::BAD_REQUEST));
+
+        let err = migrator.run(src, RunOptions::new()).await.unwrap_err();
+        match &err {
+            Error::StatementFailed {
+                version,
+                file,
+                statement_index,
+                ..
+            } => {
+                assert_eq!(*version, 1);
+                assert!(file.ends_with("0001_create_users.sql"));
+                assert_eq!(*statement_index, 1);
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(err.code(), "statement_failed");
+        assert!(err.to_string().starts_with("Statement 2 of "));
     }
 
     #[tokio::test]
@@ -832,17 +1390,12 @@ mod tests {
         .await
         .unwrap();
 
-        let applied = vec![MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Applied,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Reversible,
-            src: String::new(),
-        }];
+        let applied = vec![applied(1, "create_users", MigrationFileMode::Reversible)];
         mock.add(test::handlers::provide(applied));
 
-        let result = migrator.revert(src, true, false, None).await;
+        let result = migrator
+            .revert(src, RevertOptions::new().dry_run(true))
+            .await;
         assert!(result.is_ok());
 
         let targets = result.unwrap();
@@ -867,17 +1420,12 @@ mod tests {
         .await
         .unwrap();
 
-        let applied = vec![MigrationInfo {
-            version: 1,
-            name: "create_users".to_string(),
-            status: MigrationStatus::Applied,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Simple,
-            src: String::new(),
-        }];
+        let applied = vec![applied(1, "create_users", MigrationFileMode::Simple)];
         mock.add(test::handlers::provide(applied));
 
-        let result = migrator.revert(src, true, false, None).await;
+        let result = migrator
+            .revert(src, RevertOptions::new().dry_run(true))
+            .await;
         assert!(result.is_ok());
         // No targets because simple migrations can't be reverted
         assert!(result.unwrap().is_empty());
@@ -906,27 +1454,15 @@ mod tests {
             .unwrap();
 
         let applied = vec![
-            MigrationInfo {
-                version: 1,
-                name: "first".to_string(),
-                status: MigrationStatus::Applied,
-                applied_at: chrono::Utc::now(),
-                mode: MigrationFileMode::Reversible,
-                src: String::new(),
-            },
-            MigrationInfo {
-                version: 2,
-                name: "second".to_string(),
-                status: MigrationStatus::Applied,
-                applied_at: chrono::Utc::now(),
-                mode: MigrationFileMode::Reversible,
-                src: String::new(),
-            },
+            applied(1, "first", MigrationFileMode::Reversible),
+            applied(2, "second", MigrationFileMode::Reversible),
         ];
         mock.add(test::handlers::provide(applied));
 
         // Without target_version, should only revert the latest
-        let result = migrator.revert(src, true, false, None).await;
+        let result = migrator
+            .revert(src, RevertOptions::new().dry_run(true))
+            .await;
         assert!(result.is_ok());
 
         let targets = result.unwrap();
@@ -953,19 +1489,17 @@ mod tests {
         }
 
         let applied: Vec<MigrationInfo> = (1..=4)
-            .map(|i| MigrationInfo {
-                version: i,
-                name: format!("m{}", i),
-                status: MigrationStatus::Applied,
-                applied_at: chrono::Utc::now(),
-                mode: MigrationFileMode::Reversible,
-                src: String::new(),
-            })
+            .map(|i| applied(i, &format!("m{}", i), MigrationFileMode::Reversible))
             .collect();
         mock.add(test::handlers::provide(applied));
 
         // Revert down to version 2 (keep 1 and 2, revert 3 and 4)
-        let result = migrator.revert(src, true, false, Some(2)).await;
+        let result = migrator
+            .revert(
+                src,
+                RevertOptions::new().dry_run(true).target_version(Some(2)),
+            )
+            .await;
         assert!(result.is_ok());
 
         let targets = result.unwrap();
@@ -991,19 +1525,17 @@ mod tests {
         }
 
         let applied: Vec<MigrationInfo> = (1..=3)
-            .map(|i| MigrationInfo {
-                version: i,
-                name: format!("m{}", i),
-                status: MigrationStatus::Applied,
-                applied_at: chrono::Utc::now(),
-                mode: MigrationFileMode::Reversible,
-                src: String::new(),
-            })
+            .map(|i| applied(i, &format!("m{}", i), MigrationFileMode::Reversible))
             .collect();
         mock.add(test::handlers::provide(applied));
 
         // Revert to 0 means revert all
-        let result = migrator.revert(src, true, false, Some(0)).await;
+        let result = migrator
+            .revert(
+                src,
+                RevertOptions::new().dry_run(true).target_version(Some(0)),
+            )
+            .await;
         assert!(result.is_ok());
 
         let targets = result.unwrap();
@@ -1025,14 +1557,7 @@ mod tests {
             .await
             .unwrap();
 
-        let applied = vec![MigrationInfo {
-            version: 1,
-            name: "test".to_string(),
-            status: MigrationStatus::Applied,
-            applied_at: chrono::Utc::now(),
-            mode: MigrationFileMode::Reversible,
-            src: String::new(),
-        }];
+        let applied = vec![applied(1, "test", MigrationFileMode::Reversible)];
         mock.add(test::handlers::provide(applied));
 
         // DDL for down migration
@@ -1040,7 +1565,7 @@ mod tests {
         // DELETE query
         mock.add(test::handlers::record_ddl());
 
-        let result = migrator.revert(src, false, false, None).await;
+        let result = migrator.revert(src, RevertOptions::new()).await;
         assert!(result.is_ok());
 
         let reverted = result.unwrap();
@@ -1050,4 +1575,35 @@ mod tests {
         let query = ddl_recording.query().await;
         assert!(query.contains("DROP TABLE test"));
     }
+
+    #[tokio::test]
+    async fn test_validate_propagates_server_errors() {
+        let mock = test::Mock::new();
+        let migrator = create_mock_migrator(&mock);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        tokio::fs::write(format!("{}/0001_first.sql", src), b"SELECT 1")
+            .await
+            .unwrap();
+        tokio::fs::write(
+            format!("{}/0002_second.sql", src),
+            b"-- broken\nCREATE TABLEE users;\nSELECT 2;\nSELEC 3",
+        )
+        .await
+        .unwrap();
+
+        // 0001 is already applied and must not be validated again
+        let applied = vec![applied(1, "first", MigrationFileMode::Simple)];
+        mock.add(test::handlers::provide(applied));
+        // Not a parse error: the server is unusable, validation must fail rather than
+        // report every statement as broken
+        mock.add(test::handlers::failure(test::status::BAD_REQUEST));
+
+        assert!(matches!(
+            migrator.validate(src, false).await,
+            Err(Error::ClickhouseError(_))
+        ));
+    }
 }
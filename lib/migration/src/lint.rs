@@ -0,0 +1,621 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::sql::{self, Statement};
+use crate::{Error, MigrationFile, MigrationFileMode, fs};
+
+/// Marker to suppress rules for a whole file, e.g.
+/// `-- chutils-lint: disable=optimize-final,alter-mutation`
+const SUPPRESS_MARKER: &str = "chutils-lint:";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LintRule {
+    /// `DROP TABLE`/`DROP COLUMN` in a migration that can't be reverted
+    DropWithoutDown,
+    /// `ALTER TABLE ... UPDATE/DELETE` and `DELETE FROM` rewrite whole parts
+    AlterMutation,
+    /// `OPTIMIZE ... FINAL` merges every part of the table
+    OptimizeFinal,
+    /// `CREATE`/`DROP`/`ADD`/`DROP COLUMN` without `IF [NOT] EXISTS`
+    MissingIfExists,
+    /// DDL without `ON CLUSTER` while a cluster is configured
+    MissingOnCluster,
+    /// `Nullable` column used in the sorting key
+    NullableSortingKey,
+    /// Reversible migration whose down file has no statements
+    EmptyDown,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LintFinding {
+    pub rule: LintRule,
+    pub severity: Severity,
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LintConfig {
+    /// Cluster name, enables the `missing-on-cluster` rule
+    pub cluster: Option<String>,
+    pub disabled: HashSet<LintRule>,
+    /// Override the default severity of a rule
+    pub severities: HashMap<LintRule, Severity>,
+}
+
+impl LintRule {
+    pub const ALL: &'static [LintRule] = &[
+        LintRule::DropWithoutDown,
+        LintRule::AlterMutation,
+        LintRule::OptimizeFinal,
+        LintRule::MissingIfExists,
+        LintRule::MissingOnCluster,
+        LintRule::NullableSortingKey,
+        LintRule::EmptyDown,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::DropWithoutDown => "drop-without-down",
+            Self::AlterMutation => "alter-mutation",
+            Self::OptimizeFinal => "optimize-final",
+            Self::MissingIfExists => "missing-if-exists",
+            Self::MissingOnCluster => "missing-on-cluster",
+            Self::NullableSortingKey => "nullable-sorting-key",
+            Self::EmptyDown => "empty-down",
+        }
+    }
+
+    pub fn default_severity(&self) -> Severity {
+        match self {
+            Self::AlterMutation | Self::OptimizeFinal | Self::MissingIfExists => Severity::Warning,
+            Self::DropWithoutDown
+            | Self::MissingOnCluster
+            | Self::NullableSortingKey
+            | Self::EmptyDown => Severity::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for LintRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl std::str::FromStr for LintRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|r| r.id() == s)
+            .copied()
+            .ok_or_else(|| Error::InvalidInput(format!("unknown lint rule '{}'", s)))
+    }
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cluster<T>(mut self, cluster: Option<T>) -> Self
+    where
+        T: Into<String>,
+    {
+        self.cluster = cluster.map(|c| c.into());
+        self
+    }
+
+    pub fn disable(mut self, rule: LintRule) -> Self {
+        self.disabled.insert(rule);
+        self
+    }
+
+    pub fn severity(mut self, rule: LintRule, severity: Severity) -> Self {
+        self.severities.insert(rule, severity);
+        self
+    }
+
+    fn severity_of(&self, rule: LintRule) -> Severity {
+        self.severities
+            .get(&rule)
+            .copied()
+            .unwrap_or_else(|| rule.default_severity())
+    }
+}
+
+/// Lint every migration file in `src` without touching a database.
+/// Findings are ordered by file and line.
+pub async fn lint(src: &str, config: &LintConfig) -> Result<Vec<LintFinding>, Error> {
+    let mut by_version: BTreeMap<u32, Vec<MigrationFile>> = BTreeMap::new();
+    for mf in fs::list_migrations(src).await? {
+        by_version.entry(mf.seq_num).or_default().push(mf);
+    }
+
+    let mut findings = vec![];
+    for files in by_version.values() {
+        let mut contents = Vec::with_capacity(files.len());
+        for mf in files {
+            let raw = tokio::fs::read(&mf.path).await?;
+            contents.push((mf, String::from_utf8_lossy(&raw).to_string()));
+        }
+
+        let down_is_empty = contents
+            .iter()
+            .find(|(mf, _)| mf.mode == MigrationFileMode::Reversible && !mf.is_up)
+            .map(|(_, content)| sql::split_statements(content).is_empty());
+
+        for (mf, content) in &contents {
+            findings.extend(lint_file(mf, content, down_is_empty, config));
+        }
+    }
+
+    findings.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    Ok(findings)
+}
+
+/// `down_is_empty` is `None` when the migration has no down file at all.
+fn lint_file(
+    mf: &MigrationFile,
+    content: &str,
+    down_is_empty: Option<bool>,
+    config: &LintConfig,
+) -> Vec<LintFinding> {
+    let suppressed = suppressed_rules(content);
+    let is_down = mf.mode == MigrationFileMode::Reversible && !mf.is_up;
+    let statements = sql::split_statements(content);
+
+    let mut raw: Vec<(LintRule, usize, String)> = vec![];
+
+    if is_down && statements.is_empty() {
+        raw.push((
+            LintRule::EmptyDown,
+            1,
+            "down migration is empty, reverting it silently does nothing".to_string(),
+        ));
+    }
+
+    let revertible = matches!(down_is_empty, Some(false));
+    for stmt in &statements {
+        let norm = sql::normalize(&stmt.sql);
+        if !is_down && !revertible {
+            check_drop_without_down(stmt, &norm, &mut raw);
+        }
+        check_mutation(stmt, &norm, &mut raw);
+        check_optimize_final(stmt, &norm, &mut raw);
+        check_if_exists(stmt, &norm, &mut raw);
+        if let Some(cluster) = &config.cluster {
+            check_on_cluster(stmt, &norm, cluster, &mut raw);
+        }
+        check_nullable_sorting_key(stmt, &norm, &mut raw);
+    }
+
+    raw.into_iter()
+        .filter(|(rule, _, _)| !config.disabled.contains(rule) && !suppressed.contains(rule))
+        .map(|(rule, line, message)| LintFinding {
+            rule,
+            severity: config.severity_of(rule),
+            file: mf.path.clone(),
+            line,
+            message,
+        })
+        .collect()
+}
+
+fn suppressed_rules(content: &str) -> HashSet<LintRule> {
+    let mut rules = HashSet::new();
+    for line in content.lines() {
+        let Some(comment) = line.trim().strip_prefix("--") else {
+            continue;
+        };
+        let Some((_, directive)) = comment.split_once(SUPPRESS_MARKER) else {
+            continue;
+        };
+        let Some(list) = directive.trim().strip_prefix("disable=") else {
+            continue;
+        };
+        for id in list.split(',') {
+            match id.trim().parse::<LintRule>() {
+                Ok(rule) => {
+                    rules.insert(rule);
+                }
+                Err(_) => {
+                    tracing::warn!(
+                        rule = id.trim(),
+                        "Ignoring unknown lint rule in suppression"
+                    )
+                }
+            }
+        }
+    }
+    rules
+}
+
+fn check_drop_without_down(stmt: &Statement, norm: &str, raw: &mut Vec<(LintRule, usize, String)>) {
+    if norm.starts_with("DROP TABLE") || norm.starts_with("DROP DATABASE") {
+        raw.push((
+            LintRule::DropWithoutDown,
+            stmt.line,
+            "drops data without a down migration to restore it".to_string(),
+        ));
+    } else if norm.starts_with("ALTER TABLE") && norm.contains(" DROP COLUMN ") {
+        raw.push((
+            LintRule::DropWithoutDown,
+            stmt.line,
+            "drops a column without a down migration to restore it".to_string(),
+        ));
+    }
+}
+
+fn check_mutation(stmt: &Statement, norm: &str, raw: &mut Vec<(LintRule, usize, String)>) {
+    let is_mutation = (norm.starts_with("ALTER TABLE")
+        && (norm.contains(" UPDATE ") || norm.contains(" DELETE WHERE ")))
+        || norm.starts_with("DELETE FROM");
+    if is_mutation {
+        raw.push((
+            LintRule::AlterMutation,
+            stmt.line,
+            "mutation rewrites every affected part and can take hours on large tables".to_string(),
+        ));
+    }
+}
+
+fn check_optimize_final(stmt: &Statement, norm: &str, raw: &mut Vec<(LintRule, usize, String)>) {
+    if norm.starts_with("OPTIMIZE ") && norm.split(' ').any(|w| w == "FINAL") {
+        raw.push((
+            LintRule::OptimizeFinal,
+            stmt.line,
+            "OPTIMIZE ... FINAL merges all parts of the table and is very expensive".to_string(),
+        ));
+    }
+}
+
+fn check_if_exists(stmt: &Statement, norm: &str, raw: &mut Vec<(LintRule, usize, String)>) {
+    let missing = if norm.starts_with("CREATE ") {
+        !norm.starts_with("CREATE OR REPLACE") && !norm.contains(" IF NOT EXISTS ")
+    } else if norm.starts_with("DROP ") {
+        !norm.contains(" IF EXISTS ")
+    } else if norm.starts_with("ALTER TABLE") {
+        ["ADD COLUMN ", "ADD INDEX ", "ADD PROJECTION "]
+            .iter()
+            .any(|action| missing_guard(norm, action, "IF NOT EXISTS"))
+            || ["DROP COLUMN ", "DROP INDEX ", "DROP PROJECTION "]
+                .iter()
+                .any(|action| missing_guard(norm, action, "IF EXISTS"))
+    } else {
+        false
+    };
+
+    if missing {
+        raw.push((
+            LintRule::MissingIfExists,
+            stmt.line,
+            "statement is not idempotent, add IF EXISTS / IF NOT EXISTS".to_string(),
+        ));
+    }
+}
+
+fn missing_guard(norm: &str, action: &str, guard: &str) -> bool {
+    norm.match_indices(action)
+        .any(|(pos, _)| !norm[pos + action.len()..].starts_with(guard))
+}
+
+fn check_on_cluster(
+    stmt: &Statement,
+    norm: &str,
+    cluster: &str,
+    raw: &mut Vec<(LintRule, usize, String)>,
+) {
+    let is_ddl = ["CREATE ", "DROP ", "ALTER ", "RENAME ", "TRUNCATE "]
+        .iter()
+        .any(|kw| norm.starts_with(kw));
+    if is_ddl && !norm.contains(" ON CLUSTER ") {
+        raw.push((
+            LintRule::MissingOnCluster,
+            stmt.line,
+            format!("DDL runs on a single node, add ON CLUSTER {}", cluster),
+        ));
+    }
+}
+
+fn check_nullable_sorting_key(
+    stmt: &Statement,
+    norm: &str,
+    raw: &mut Vec<(LintRule, usize, String)>,
+) {
+    if !(norm.starts_with("CREATE TABLE") || norm.starts_with("CREATE OR REPLACE TABLE")) {
+        return;
+    }
+
+    let nullable = nullable_columns(&stmt.sql);
+    if nullable.is_empty() {
+        return;
+    }
+
+    let Some(order_by) = sorting_key(norm) else {
+        return;
+    };
+
+    let mut used: Vec<&String> = nullable
+        .iter()
+        .filter(|col| {
+            order_by
+                .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .any(|ident| ident == col.to_uppercase())
+        })
+        .collect();
+    used.sort();
+
+    for col in used {
+        raw.push((
+            LintRule::NullableSortingKey,
+            stmt.line,
+            format!("Nullable column '{}' is part of the sorting key", col),
+        ));
+    }
+}
+
+/// Names of `Nullable` columns declared in the column list of a `CREATE TABLE`.
+fn nullable_columns(sql: &str) -> Vec<String> {
+    let Some(start) = sql.find('(') else {
+        return vec![];
+    };
+
+    let mut depth = 0;
+    let mut defs = vec![];
+    let mut current = String::new();
+    for c in sql[start + 1..].chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => break,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                defs.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    defs.push(current);
+
+    defs.iter()
+        .filter_map(|def| {
+            let mut parts = def.split_whitespace();
+            let name = parts.next()?.trim_matches('`');
+            let data_type = parts.next()?.to_uppercase();
+            (data_type.starts_with("NULLABLE(")
+                || data_type.starts_with("LOWCARDINALITY(NULLABLE("))
+            .then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// Expression following `ORDER BY` in a normalized `CREATE TABLE`.
+fn sorting_key(norm: &str) -> Option<&str> {
+    let (_, rest) = norm.rsplit_once(" ORDER BY ")?;
+    let end = [
+        " PARTITION BY ",
+        " PRIMARY KEY ",
+        " SAMPLE BY ",
+        " TTL ",
+        " SETTINGS ",
+        " COMMENT ",
+        " AS SELECT ",
+    ]
+    .iter()
+    .filter_map(|kw| rest.find(kw))
+    .min()
+    .unwrap_or(rest.len());
+    Some(&rest[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, mode: MigrationFileMode, is_up: bool) -> MigrationFile {
+        MigrationFile {
+            path: path.to_string(),
+            name: "test".to_string(),
+            mode,
+            src: "migrations".to_string(),
+            is_up,
+            seq_num: 1,
+        }
+    }
+
+    fn rules(findings: &[LintFinding]) -> Vec<LintRule> {
+        findings.iter().map(|f| f.rule).collect()
+    }
+
+    fn lint_simple(content: &str) -> Vec<LintFinding> {
+        let mf = file("0001_test.sql", MigrationFileMode::Simple, false);
+        lint_file(&mf, content, None, &LintConfig::default())
+    }
+
+    #[test]
+    fn test_rule_ids_roundtrip() {
+        for rule in LintRule::ALL {
+            assert_eq!(rule.id().parse::<LintRule>().unwrap(), *rule);
+        }
+        assert!("nope".parse::<LintRule>().is_err());
+    }
+
+    #[test]
+    fn test_clean_migration() {
+        let findings = lint_simple(
+            "CREATE TABLE IF NOT EXISTS users (id UInt64) ENGINE = MergeTree ORDER BY id;",
+        );
+        assert!(findings.is_empty(), "{:?}", findings);
+    }
+
+    #[test]
+    fn test_drop_without_down() {
+        let findings = lint_simple(
+            "DROP TABLE IF EXISTS users;\nALTER TABLE posts DROP COLUMN IF EXISTS body;",
+        );
+        assert_eq!(
+            rules(&findings),
+            vec![LintRule::DropWithoutDown, LintRule::DropWithoutDown]
+        );
+        assert_eq!(findings[1].line, 2);
+    }
+
+    #[test]
+    fn test_drop_with_down_is_fine() {
+        let mf = file("0001_test.up.sql", MigrationFileMode::Reversible, true);
+        let findings = lint_file(
+            &mf,
+            "DROP TABLE IF EXISTS users",
+            Some(false),
+            &LintConfig::default(),
+        );
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn test_drop_with_empty_down_is_flagged() {
+        let mf = file("0001_test.up.sql", MigrationFileMode::Reversible, true);
+        let findings = lint_file(
+            &mf,
+            "DROP TABLE IF EXISTS users",
+            Some(true),
+            &LintConfig::default(),
+        );
+        assert_eq!(rules(&findings), vec![LintRule::DropWithoutDown]);
+    }
+
+    #[test]
+    fn test_empty_down() {
+        let mf = file("0001_test.down.sql", MigrationFileMode::Reversible, false);
+        let findings = lint_file(&mf, "-- nothing here\n", Some(true), &LintConfig::default());
+        assert_eq!(rules(&findings), vec![LintRule::EmptyDown]);
+        assert_eq!(findings[0].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_mutations() {
+        let findings = lint_simple(
+            "ALTER TABLE users UPDATE name = 'x' WHERE id = 1;\nALTER TABLE users DELETE WHERE id = 1;\nDELETE FROM users WHERE id = 1;",
+        );
+        assert_eq!(
+            rules(&findings),
+            vec![
+                LintRule::AlterMutation,
+                LintRule::AlterMutation,
+                LintRule::AlterMutation
+            ]
+        );
+    }
+
+    #[test]
+    fn test_optimize_final() {
+        let findings = lint_simple("OPTIMIZE TABLE users FINAL");
+        assert_eq!(rules(&findings), vec![LintRule::OptimizeFinal]);
+        assert_eq!(findings[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn test_missing_if_exists() {
+        let findings = lint_simple(
+            "CREATE TABLE users (id UInt64) ENGINE = Memory;\nALTER TABLE users ADD COLUMN email String;\nALTER TABLE users ADD COLUMN IF NOT EXISTS name String;\nCREATE OR REPLACE VIEW v AS SELECT 1;",
+        );
+        assert_eq!(
+            rules(&findings),
+            vec![LintRule::MissingIfExists, LintRule::MissingIfExists]
+        );
+        assert_eq!(findings[1].line, 2);
+    }
+
+    #[test]
+    fn test_missing_on_cluster_only_with_cluster() {
+        let mf = file("0001_test.sql", MigrationFileMode::Simple, false);
+        let sql = "CREATE TABLE IF NOT EXISTS users (id UInt64) ENGINE = Memory;\nCREATE TABLE IF NOT EXISTS posts ON CLUSTER main (id UInt64) ENGINE = Memory;";
+
+        assert!(lint_file(&mf, sql, None, &LintConfig::default()).is_empty());
+
+        let config = LintConfig::new().cluster(Some("main"));
+        let findings = lint_file(&mf, sql, None, &config);
+        assert_eq!(rules(&findings), vec![LintRule::MissingOnCluster]);
+        assert_eq!(findings[0].line, 1);
+    }
+
+    #[test]
+    fn test_nullable_sorting_key() {
+        let findings = lint_simple(
+            "CREATE TABLE IF NOT EXISTS events (\n  ts DateTime,\n  user_id Nullable(UInt64),\n  note Nullable(String)\n) ENGINE = MergeTree ORDER BY (ts, user_id) SETTINGS allow_nullable_key = 1",
+        );
+        assert_eq!(rules(&findings), vec![LintRule::NullableSortingKey]);
+        assert!(findings[0].message.contains("user_id"));
+    }
+
+    #[test]
+    fn test_file_suppression() {
+        let findings = lint_simple(
+            "-- chutils-lint: disable=optimize-final, alter-mutation\nOPTIMIZE TABLE users FINAL;\nALTER TABLE users DELETE WHERE id = 1;",
+        );
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn test_config_disable_and_severity() {
+        let mf = file("0001_test.sql", MigrationFileMode::Simple, false);
+        let sql = "OPTIMIZE TABLE users FINAL;\nCREATE TABLE users (id UInt64) ENGINE = Memory;";
+
+        let config = LintConfig::new()
+            .disable(LintRule::MissingIfExists)
+            .severity(LintRule::OptimizeFinal, Severity::Error);
+        let findings = lint_file(&mf, sql, None, &config);
+        assert_eq!(rules(&findings), vec![LintRule::OptimizeFinal]);
+        assert_eq!(findings[0].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_finding_serializes_rule_id() {
+        let findings = lint_simple("OPTIMIZE TABLE users FINAL");
+        let json = serde_json::to_value(&findings[0]).unwrap();
+        assert_eq!(json["rule"], "optimize-final");
+        assert_eq!(json["severity"], "warning");
+        assert_eq!(json["line"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_lint_directory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+
+        tokio::fs::write(
+            format!("{}/0001_create.up.sql", src),
+            b"CREATE TABLE IF NOT EXISTS users (id UInt64) ENGINE = Memory",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(format!("{}/0001_create.down.sql", src), b"")
+            .await
+            .unwrap();
+
+        let findings = lint(src, &LintConfig::default()).await.unwrap();
+        assert_eq!(rules(&findings), vec![LintRule::EmptyDown]);
+        assert!(findings[0].file.ends_with("0001_create.down.sql"));
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+/// Which way a migration is executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// Progress of `Migration::run` / `Migration::revert`, delivered to an `Observer`.
+#[derive(Debug, Clone)]
+pub enum MigrationEvent {
+    /// The history lock was acquired before running migrations
+    LockAcquired,
+    MigrationStarted {
+        version: u32,
+        name: String,
+        direction: Direction,
+    },
+    StatementStarted {
+        version: u32,
+        /// 0-based position of the statement in the file
+        index: usize,
+        sql: String,
+    },
+    /// Rows affected aren't reported: the server sends them in the `X-ClickHouse-Summary`
+    /// response header, which the clickhouse 0.13 client doesn't expose for executed
+    /// statements. `Migrator::audit` has the written bytes of every statement.
+    StatementFinished {
+        version: u32,
+        index: usize,
+        duration: Duration,
+    },
+    MigrationApplied {
+        version: u32,
+        name: String,
+        duration: Duration,
+    },
+    MigrationReverted {
+        version: u32,
+        name: String,
+        duration: Duration,
+    },
+    MigrationFailed {
+        version: u32,
+        name: String,
+        direction: Direction,
+        error: String,
+    },
+}
+
+/// Receives progress events from a `Migrator`.
+///
+/// Called inline while migrations execute, so implementations should return quickly
+/// (e.g. forward to a channel).
+pub trait Observer: Send + Sync {
+    fn on_event(&self, event: &MigrationEvent);
+}
+
+impl<F> Observer for F
+where
+    F: Fn(&MigrationEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &MigrationEvent) {
+        self(event)
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Up => write!(f, "up"),
+            Self::Down => write!(f, "down"),
+        }
+    }
+}
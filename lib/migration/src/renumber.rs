@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{Error, MigrationFile, Migrator, fs};
+
+/// A pending migration moved to a new version by `Migrator::renumber`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Renumbered {
+    pub name: String,
+    pub from: u32,
+    pub to: u32,
+    /// `(old path, new path)` of every file of the migration
+    pub files: Vec<(String, String)>,
+}
+
+impl Migrator {
+    /// Resolve version collisions (e.g. two branches both adding `0012_*`) by moving the
+    /// colliding migrations that were never applied to the end of the sequence.
+    ///
+    /// For each collided version, the migration recorded in the history keeps its
+    /// version. When none is applied, the first one by name keeps it. Nothing is renamed
+    /// when `dry_run` is set, or when any destination already exists.
+    pub async fn renumber(&self, src: &str, dry_run: bool) -> Result<Vec<Renumbered>, Error> {
+        let src = src.strip_suffix('/').unwrap_or(src);
+        let files = fs::list_migrations(src).await?;
+
+        let applied: HashSet<(u32, String)> = self
+            .history()
+            .load()
+            .await?
+            .into_iter()
+            .map(|a| (a.version, a.name))
+            .collect();
+
+        let plan = plan_renumber(src, &files, &applied);
+        if dry_run {
+            return Ok(plan);
+        }
+
+        let moves: Vec<_> = plan.iter().flat_map(|m| m.files.clone()).collect();
+        fs::rename_all(&moves).await?;
+        Ok(plan)
+    }
+}
+
+pub(crate) fn plan_renumber(
+    src: &str,
+    files: &[MigrationFile],
+    applied: &HashSet<(u32, String)>,
+) -> Vec<Renumbered> {
+    // version -> name -> files
+    let mut by_version: BTreeMap<u32, BTreeMap<&str, Vec<&MigrationFile>>> = BTreeMap::new();
+    for file in files {
+        by_version
+            .entry(file.seq_num)
+            .or_default()
+            .entry(&file.name)
+            .or_default()
+            .push(file);
+    }
+
+    let mut next = by_version.keys().max().copied().unwrap_or_default() + 1;
+    let mut plan = vec![];
+    for (&version, names) in &by_version {
+        if names.len() < 2 {
+            continue;
+        }
+
+        let applied_here = names
+            .keys()
+            .find(|name| applied.contains(&(version, name.to_string())));
+        // The applied one stays. If the version was applied under a name we don't
+        // have, every local one has to move.
+        let keep = match applied_here {
+            Some(name) => Some(*name),
+            None if applied.iter().any(|(v, _)| *v == version) => None,
+            None => names.keys().next().copied(),
+        };
+
+        for (name, group) in names {
+            if Some(*name) == keep {
+                continue;
+            }
+            plan.push(Renumbered {
+                name: name.to_string(),
+                from: version,
+                to: next,
+                files: group
+                    .iter()
+                    .map(|f| {
+                        let to = fs::build_file_path(src, next, &f.name, f.mode, f.is_up);
+                        (f.path.clone(), to)
+                    })
+                    .collect(),
+            });
+            next += 1;
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryHistory, MigrationFileMode};
+
+    fn file(version: u32, name: &str, mode: MigrationFileMode, is_up: bool) -> MigrationFile {
+        MigrationFile {
+            path: fs::build_file_path("m", version, name, mode, is_up),
+            name: name.to_string(),
+            mode,
+            src: "m".to_string(),
+            is_up,
+            seq_num: version,
+        }
+    }
+
+    #[test]
+    fn test_plan_renumber_moves_unapplied_collision() {
+        let files = vec![
+            file(11, "base", MigrationFileMode::Simple, false),
+            file(12, "add_email", MigrationFileMode::Reversible, true),
+            file(12, "add_email", MigrationFileMode::Reversible, false),
+            file(12, "add_phone", MigrationFileMode::Reversible, true),
+            file(12, "add_phone", MigrationFileMode::Reversible, false),
+            file(13, "other", MigrationFileMode::Simple, false),
+        ];
+        // add_phone was applied somewhere, add_email has to move
+        let applied = HashSet::from([(12, "add_phone".to_string())]);
+
+        let plan = plan_renumber("m", &files, &applied);
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].name, "add_email");
+        assert_eq!((plan[0].from, plan[0].to), (12, 14));
+        assert_eq!(
+            plan[0].files,
+            vec![
+                (
+                    "m/0012_add_email.up.sql".to_string(),
+                    "m/0014_add_email.up.sql".to_string()
+                ),
+                (
+                    "m/0012_add_email.down.sql".to_string(),
+                    "m/0014_add_email.down.sql".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_renumber_keeps_first_when_none_applied() {
+        let files = vec![
+            file(1, "b", MigrationFileMode::Simple, false),
+            file(1, "a", MigrationFileMode::Simple, false),
+        ];
+        let plan = plan_renumber("m", &files, &HashSet::new());
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].name, "b");
+        assert_eq!(plan[0].to, 2);
+    }
+
+    #[test]
+    fn test_plan_renumber_moves_all_when_applied_under_other_name() {
+        let files = vec![
+            file(1, "a", MigrationFileMode::Simple, false),
+            file(1, "b", MigrationFileMode::Simple, false),
+        ];
+        let applied = HashSet::from([(1, "gone".to_string())]);
+        let plan = plan_renumber("m", &files, &applied);
+        assert_eq!(plan.iter().map(|r| r.to).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_renumber_renames_files() {
+        let migrator = Migrator::from_client(ch::clickhouse::Client::default())
+            .with_history(MemoryHistory::new().with_applied(1, "b"));
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        for name in ["0001_a.sql", "0001_b.sql"] {
+            tokio::fs::write(format!("{}/{}", src, name), "SELECT 1")
+                .await
+                .unwrap();
+        }
+
+        let renumbered = migrator.renumber(src, false).await.unwrap();
+        assert_eq!(renumbered.len(), 1);
+        assert_eq!(renumbered[0].name, "a");
+
+        let names: Vec<_> = fs::list_migrations_strict(src)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|f| (f.seq_num, f.name))
+            .collect();
+        assert_eq!(names, vec![(1, "b".to_string()), (2, "a".to_string())]);
+    }
+
+    #[test]
+    fn test_plan_renumber_no_collision() {
+        let files = vec![file(1, "a", MigrationFileMode::Simple, false)];
+        assert!(plan_renumber("m", &files, &HashSet::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_renumber_checks_every_destination_first() {
+        let migrator = Migrator::from_client(ch::clickhouse::Client::default())
+            .with_history(MemoryHistory::new());
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        for name in ["0001_a.sql", "0001_b.sql", "0001_c.sql"] {
+            tokio::fs::write(format!("{}/{}", src, name), "SELECT 1")
+                .await
+                .unwrap();
+        }
+        // b moves to 2 and c to 3, where something is in the way
+        tokio::fs::create_dir(format!("{}/0003_c.sql", src))
+            .await
+            .unwrap();
+
+        let result = migrator.renumber(src, false).await;
+        assert!(matches!(result, Err(Error::InvalidInput(msg)) if msg.contains("file exists")));
+        assert!(temp_dir.path().join("0001_b.sql").exists());
+        assert!(!temp_dir.path().join("0002_b.sql").exists());
+    }
+}
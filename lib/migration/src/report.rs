@@ -0,0 +1,185 @@
+use crate::{Direction, Error, MigrationFileMode, MigrationInfo, MigrationStatus, fs, sql};
+
+/// Version of the `Report` layout. Bumped on any change that isn't a new optional field,
+/// so consumers can refuse versions they don't know.
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+/// Machine-readable output of `migrate info`, `up` and `down`, see the
+/// "Machine-readable output" section of the README for the documented schema.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Report {
+    pub schema_version: u32,
+    /// `info`, `up` or `down`
+    pub command: String,
+    pub dry_run: bool,
+    pub migrations: Vec<ReportEntry>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ReportEntry {
+    pub version: u32,
+    pub name: String,
+    /// `0005_add_email`
+    pub full_version: String,
+    /// `pending` or `applied`
+    pub status: String,
+    /// `simple` or `reversible`
+    pub mode: String,
+    pub up_file: String,
+    /// Only set for reversible migrations
+    pub down_file: Option<String>,
+    /// RFC 3339, only set for applied migrations
+    pub applied_at: Option<String>,
+    pub duration_ms: u64,
+    pub applied_by: String,
+    pub host: String,
+    pub chutils_version: String,
+    /// SHA-256 of the local up file
+    pub checksum: String,
+    /// SHA-256 recorded when the migration was applied
+    pub recorded_checksum: Option<String>,
+    pub checksum_state: ChecksumState,
+    /// Applied in a server transaction, see `Migrator::with_transactions`
+    pub transactional: bool,
+    /// Statements a dry run would execute, in order
+    pub statements: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumState {
+    /// Not applied yet, nothing to compare with
+    Pending,
+    /// The local up file is the one that was applied
+    Unchanged,
+    /// The local up file was edited after it was applied
+    Changed,
+    /// Applied before checksums were recorded
+    Unknown,
+}
+
+impl Report {
+    /// Describe `migrations` as returned by `info`, `run` or `revert`.
+    ///
+    /// With `statements` set (dry runs), every entry lists the statements of its file in
+    /// that direction.
+    pub async fn build(
+        command: &str,
+        dry_run: bool,
+        migrations: &[MigrationInfo],
+        statements: Option<Direction>,
+    ) -> Result<Self, Error> {
+        let mut entries = vec![];
+        for mig in migrations {
+            entries.push(ReportEntry::build(mig, statements).await?);
+        }
+        Ok(Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            command: command.to_string(),
+            dry_run,
+            migrations: entries,
+        })
+    }
+}
+
+impl ReportEntry {
+    async fn build(mig: &MigrationInfo, statements: Option<Direction>) -> Result<Self, Error> {
+        let applied = mig.status == MigrationStatus::Applied;
+        let up_file = mig.file_path(true);
+        let checksum = fs::checksum(&up_file).await?;
+        let checksum_state = match (applied, mig.checksum.as_str()) {
+            (false, _) => ChecksumState::Pending,
+            (true, "") => ChecksumState::Unknown,
+            (true, recorded) if recorded == checksum => ChecksumState::Unchanged,
+            (true, _) => ChecksumState::Changed,
+        };
+
+        let statements = match statements {
+            Some(direction) => {
+                let raw = tokio::fs::read(mig.file_path(direction == Direction::Up)).await?;
+                let content = String::from_utf8_lossy(&raw);
+                Some(
+                    sql::split_statements(&content)
+                        .into_iter()
+                        .map(|s| s.sql)
+                        .collect(),
+                )
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            version: mig.version,
+            name: mig.name.clone(),
+            full_version: mig.full_version(),
+            status: match mig.status {
+                MigrationStatus::Pending => "pending",
+                MigrationStatus::Applied => "applied",
+            }
+            .to_string(),
+            mode: match mig.mode {
+                MigrationFileMode::Simple => "simple",
+                MigrationFileMode::Reversible => "reversible",
+            }
+            .to_string(),
+            down_file: (mig.mode == MigrationFileMode::Reversible).then(|| mig.file_path(false)),
+            up_file,
+            applied_at: applied.then(|| mig.applied_at.to_rfc3339()),
+            duration_ms: mig.duration_ms,
+            applied_by: mig.applied_by.clone(),
+            host: mig.host.clone(),
+            chutils_version: mig.chutils_version.clone(),
+            checksum,
+            recorded_checksum: (!mig.checksum.is_empty()).then(|| mig.checksum.clone()),
+            checksum_state,
+            transactional: mig.transactional,
+            statements,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MigrationFile;
+
+    #[tokio::test]
+    async fn test_report_entry() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        let path = fs::build_file_path(src, 1, "users", MigrationFileMode::Reversible, true);
+        tokio::fs::write(&path, "CREATE TABLE a (id UInt8) ENGINE = Log;\nSELECT 1;")
+            .await
+            .unwrap();
+
+        let mut mig = MigrationInfo::from(MigrationFile {
+            path: path.clone(),
+            name: "users".to_string(),
+            mode: MigrationFileMode::Reversible,
+            src: src.to_string(),
+            is_up: true,
+            seq_num: 1,
+        });
+        let pending = ReportEntry::build(&mig, Some(Direction::Up)).await.unwrap();
+        assert_eq!(pending.checksum_state, ChecksumState::Pending);
+        assert_eq!(pending.applied_at, None);
+        assert_eq!(pending.statements.unwrap().len(), 2);
+        assert!(pending.down_file.unwrap().ends_with("0001_users.down.sql"));
+
+        mig.status = MigrationStatus::Applied;
+        mig.checksum = fs::checksum(&path).await.unwrap();
+        let applied = ReportEntry::build(&mig, None).await.unwrap();
+        assert_eq!(applied.checksum_state, ChecksumState::Unchanged);
+        assert_eq!(applied.statements, None);
+
+        tokio::fs::write(&path, "SELECT 2;").await.unwrap();
+        let changed = ReportEntry::build(&mig, None).await.unwrap();
+        assert_eq!(changed.checksum_state, ChecksumState::Changed);
+
+        let json = serde_json::to_value(Report::build("info", false, &[mig], None).await.unwrap())
+            .unwrap();
+        assert_eq!(json["schema_version"], REPORT_SCHEMA_VERSION);
+        assert_eq!(json["migrations"][0]["status"], "applied");
+        assert_eq!(json["migrations"][0]["checksum_state"], "changed");
+    }
+}
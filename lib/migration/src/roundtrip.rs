@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::audit::new_scratch_run_id;
+use crate::scratch::{ScratchDatabase, TableDefinition};
+use crate::{Error, MigrationFileMode, MigrationInfo, Migrator, fs};
+
+#[derive(Debug, Clone)]
+pub struct RoundtripReport {
+    pub version: u32,
+    pub name: String,
+    /// Path of the `.down.sql` file under test
+    pub down_file: String,
+    pub outcome: RoundtripOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoundtripOutcome {
+    /// Down restored the exact schema seen before up, and up applied again cleanly
+    Restored,
+    /// Down ran but left the schema different from the state before up
+    SchemaMismatch(SchemaDiff),
+    /// Executing the down file failed
+    DownFailed(String),
+    /// Down looked fine but running up a second time failed
+    ReapplyFailed(String),
+}
+
+/// Difference between two schema snapshots, by table name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+    /// Tables present after down but not before up
+    pub added: Vec<String>,
+    /// Tables present before up but missing after down
+    pub removed: Vec<String>,
+    /// Tables whose definition differs
+    pub changed: Vec<String>,
+}
+
+impl RoundtripReport {
+    pub fn is_ok(&self) -> bool {
+        self.outcome == RoundtripOutcome::Restored
+    }
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl std::fmt::Display for RoundtripOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Restored => write!(f, "restored"),
+            Self::SchemaMismatch(diff) => write!(f, "schema mismatch ({})", diff),
+            Self::DownFailed(err) => write!(f, "down failed: {}", err),
+            Self::ReapplyFailed(err) => write!(f, "re-applying up failed: {}", err),
+        }
+    }
+}
+
+impl std::fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if !self.added.is_empty() {
+            parts.push(format!("left behind: {}", self.added.join(", ")));
+        }
+        if !self.removed.is_empty() {
+            parts.push(format!("not restored: {}", self.removed.join(", ")));
+        }
+        if !self.changed.is_empty() {
+            parts.push(format!("changed: {}", self.changed.join(", ")));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+impl Migrator {
+    /// Replay every local migration in a scratch database and check that each
+    /// reversible migration's down file restores the schema seen before its up file.
+    ///
+    /// Simple migrations are applied as-is so later migrations see the right state.
+    /// Verification stops at the first migration that leaves the scratch database
+    /// in an unknown state (a failing down or re-applied up).
+    pub async fn verify_roundtrip(&self, src: &str) -> Result<Vec<RoundtripReport>, Error> {
+        let migrations: BTreeMap<u32, MigrationInfo> = fs::list_migrations_strict(src)
+            .await?
+            .into_iter()
+            .map(|mf| (mf.seq_num, mf.into()))
+            .collect();
+
+        let scratch = ScratchDatabase::create(&self.inner, "roundtrip").await?;
+        tracing::info!(database = scratch.name(), "Verifying migrations round-trip");
+
+        let result = roundtrip(&scratch, migrations.into_values()).await;
+        scratch.drop().await?;
+        result
+    }
+}
+
+async fn roundtrip(
+    scratch: &ScratchDatabase,
+    migrations: impl Iterator<Item = MigrationInfo>,
+) -> Result<Vec<RoundtripReport>, Error> {
+    let migrator = scratch.migrator();
+    let run_id = new_scratch_run_id();
+    let mut reports = vec![];
+
+    for mig in migrations {
+        if mig.mode != MigrationFileMode::Reversible {
+            migrator.execute_migration(&mig, true, &run_id).await?;
+            continue;
+        }
+
+        let before = scratch.schema().await?;
+        migrator.execute_migration(&mig, true, &run_id).await?;
+
+        let mut report = RoundtripReport {
+            version: mig.version,
+            name: mig.name.clone(),
+            down_file: mig.file_path(false),
+            outcome: RoundtripOutcome::Restored,
+        };
+
+        if let Err(err) = migrator.execute_migration(&mig, false, &run_id).await {
+            report.outcome = RoundtripOutcome::DownFailed(err.to_string());
+            reports.push(report);
+            break;
+        }
+
+        let diff = compare_schemas(&before, &scratch.schema().await?);
+        if !diff.is_empty() {
+            report.outcome = RoundtripOutcome::SchemaMismatch(diff);
+        }
+
+        if let Err(err) = migrator.execute_migration(&mig, true, &run_id).await {
+            if report.is_ok() {
+                report.outcome = RoundtripOutcome::ReapplyFailed(err.to_string());
+            }
+            reports.push(report);
+            break;
+        }
+
+        reports.push(report);
+    }
+
+    Ok(reports)
+}
+
+pub(crate) fn compare_schemas(before: &[TableDefinition], after: &[TableDefinition]) -> SchemaDiff {
+    let before: BTreeMap<_, _> = before
+        .iter()
+        .map(|t| (t.name.as_str(), t.create_table_query.as_str()))
+        .collect();
+    let after: BTreeMap<_, _> = after
+        .iter()
+        .map(|t| (t.name.as_str(), t.create_table_query.as_str()))
+        .collect();
+
+    let names: BTreeSet<_> = before.keys().chain(after.keys()).collect();
+
+    let mut diff = SchemaDiff::default();
+    for name in names {
+        match (before.get(name), after.get(name)) {
+            (Some(_), None) => diff.removed.push(name.to_string()),
+            (None, Some(_)) => diff.added.push(name.to_string()),
+            (Some(b), Some(a)) if b != a => diff.changed.push(name.to_string()),
+            _ => {}
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(name: &str, query: &str) -> TableDefinition {
+        TableDefinition {
+            name: name.to_string(),
+            create_table_query: query.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_keeps_qualified_statements_in_scratch() {
+        use ch::clickhouse::{self, test};
+
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        let databases = || {
+            mock.add(test::handlers::provide(vec!["analytics".to_string()]));
+            mock.add(test::handlers::provide(vec![
+                "analytics".to_string(),
+                "billing".to_string(),
+                "system".to_string(),
+            ]));
+        };
+
+        tokio::fs::write(
+            temp_dir.path().join("0001_events.sql"),
+            "CREATE TABLE analytics.events (id UInt64) ENGINE = MergeTree ORDER BY id",
+        )
+        .await
+        .unwrap();
+        databases();
+        mock.add(test::handlers::record_ddl());
+        let create = mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        assert!(migrator.verify_roundtrip(src).await.unwrap().is_empty());
+        assert!(create.query().await.starts_with("CREATE TABLE events "));
+
+        // Another database can't be redirected to the scratch database
+        tokio::fs::write(
+            temp_dir.path().join("0002_invoices.sql"),
+            "DROP TABLE billing.invoices",
+        )
+        .await
+        .unwrap();
+        databases();
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        let drop = mock.add(test::handlers::record_ddl());
+        assert!(matches!(
+            migrator.verify_roundtrip(src).await,
+            Err(Error::InvalidInput(msg)) if msg.contains("billing")
+        ));
+        assert!(drop.query().await.starts_with("DROP DATABASE"));
+    }
+
+    #[test]
+    fn test_compare_schemas_identical() {
+        let before = vec![table("users", "CREATE TABLE users (id UInt32)")];
+        let after = before.clone();
+        assert!(compare_schemas(&before, &after).is_empty());
+    }
+
+    #[test]
+    fn test_compare_schemas_left_behind_table() {
+        let before = vec![];
+        let after = vec![table("users", "CREATE TABLE users (id UInt32)")];
+
+        let diff = compare_schemas(&before, &after);
+        assert_eq!(diff.added, vec!["users"]);
+        assert!(diff.removed.is_empty());
+        assert!(diff.changed.is_empty());
+    }
+
+    #[test]
+    fn test_compare_schemas_not_restored_table() {
+        let before = vec![table("users", "CREATE TABLE users (id UInt32)")];
+        let after = vec![];
+
+        let diff = compare_schemas(&before, &after);
+        assert_eq!(diff.removed, vec!["users"]);
+    }
+
+    #[test]
+    fn test_compare_schemas_changed_definition() {
+        let before = vec![table("users", "CREATE TABLE users (id UInt32)")];
+        let after = vec![table(
+            "users",
+            "CREATE TABLE users (id UInt32, email String)",
+        )];
+
+        let diff = compare_schemas(&before, &after);
+        assert_eq!(diff.changed, vec!["users"]);
+        assert_eq!(diff.to_string(), "changed: users");
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use ch::clickhouse;
+use clickhouse::sql::Identifier;
+
+use crate::{Error, Migrator, diff};
+
+/// Databases every server has, which replayed statements may read from.
+const BUILTIN_DATABASES: &[&str] = &["system", "information_schema", "INFORMATION_SCHEMA"];
+
+/// A throwaway database created on the same server as the migrator's target.
+/// Used to replay migrations without touching the real database.
+pub(crate) struct ScratchDatabase {
+    name: String,
+    client: clickhouse::Client,
+    scope: Arc<ScratchScope>,
+}
+
+/// Keeps statements replayed in a scratch database inside it.
+#[derive(Debug)]
+pub(crate) struct ScratchScope {
+    /// Database the statements were written for, its qualifier is dropped
+    source_database: String,
+    /// The other databases of the server, statements naming them are refused
+    other_databases: Vec<String>,
+}
+
+impl ScratchScope {
+    /// `statement` with references to the source database pointed at the scratch
+    /// database. Statements naming another database of the server would reach it from
+    /// the scratch database too, they are refused.
+    pub fn rewrite(&self, statement: &str) -> Result<String, Error> {
+        let qualifiers = diff::database_qualifiers(statement);
+        if let Some(database) = qualifiers.iter().find(|q| self.other_databases.contains(q)) {
+            return Err(Error::InvalidInput(format!(
+                "statement names database {}, refusing to replay it in a scratch database: {}",
+                database, statement
+            )));
+        }
+        Ok(diff::strip_database(statement, &self.source_database))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clickhouse::Row, serde::Serialize, serde::Deserialize)]
+pub(crate) struct TableDefinition {
+    pub name: String,
+    pub create_table_query: String,
+}
+
+impl ScratchDatabase {
+    /// Create a new, empty database with a unique name derived from `purpose`.
+    pub async fn create(client: &clickhouse::Client, purpose: &str) -> Result<Self, Error> {
+        let name = format!(
+            "_chutils_{}_{}_{}",
+            purpose,
+            std::process::id(),
+            chrono::Utc::now().timestamp_millis()
+        );
+
+        let source_database: String = client.query("SELECT currentDatabase()").fetch_one().await?;
+        let other_databases = client
+            .query("SELECT name FROM system.databases")
+            .fetch_all::<String>()
+            .await?
+            .into_iter()
+            .filter(|db| *db != source_database && !BUILTIN_DATABASES.contains(&db.as_str()))
+            .collect();
+
+        client
+            .query("CREATE DATABASE ?")
+            .bind(Identifier(&name))
+            .execute()
+            .await?;
+
+        tracing::debug!(database = %name, "Created scratch database");
+
+        Ok(Self {
+            client: client.clone().with_database(&name),
+            name,
+            scope: Arc::new(ScratchScope {
+                source_database,
+                other_databases,
+            }),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// A migrator whose queries run inside the scratch database. Statements of the files
+    /// it executes are rewritten by `ScratchScope::rewrite`.
+    pub fn migrator(&self) -> Migrator {
+        let mut migrator = Migrator::from_client(self.client.clone());
+        migrator.scratch = Some(self.scope.clone());
+        migrator
+    }
+
+    /// `statement` kept inside the scratch database, see `ScratchScope::rewrite`.
+    pub fn rewrite(&self, statement: &str) -> Result<String, Error> {
+        self.scope.rewrite(statement)
+    }
+
+    /// Snapshot the `CREATE` statement of every table, view and dictionary
+    /// in the scratch database, ordered by name.
+    pub async fn schema(&self) -> Result<Vec<TableDefinition>, Error> {
+        let tables = self
+            .client
+            .query(
+                "SELECT name, create_table_query FROM system.tables WHERE database = ? ORDER BY name",
+            )
+            .bind(&self.name)
+            .fetch_all::<TableDefinition>()
+            .await?;
+        Ok(tables)
+    }
+
+    /// `CREATE` statements of the scratch database, see `creation_script`.
+    pub async fn creation_script(&self) -> Result<Vec<TableDefinition>, Error> {
+        creation_script(&self.client, &self.name).await
+    }
+
+    pub async fn drop(self) -> Result<(), Error> {
+        self.client
+            .query("DROP DATABASE IF EXISTS ? SYNC")
+            .bind(Identifier(&self.name))
+            .execute()
+            .await?;
+        tracing::debug!(database = %self.name, "Dropped scratch database");
+        Ok(())
+    }
+}
+
+/// `CREATE` statements of `database` in an order they can be replayed in: tables, then
+/// dictionaries, then views. Inner tables of materialized views are left out since the
+/// views create them.
+pub(crate) async fn creation_script(
+    client: &clickhouse::Client,
+    database: &str,
+) -> Result<Vec<TableDefinition>, Error> {
+    let tables = client
+        .query(
+            "SELECT name, create_table_query FROM system.tables
+            WHERE database = ? AND NOT startsWith(name, '.inner')
+            ORDER BY multiIf(engine LIKE '%View', 2, engine = 'Dictionary', 1, 0), name",
+        )
+        .bind(database)
+        .fetch_all::<TableDefinition>()
+        .await?;
+    Ok(tables)
+}
@@ -0,0 +1,414 @@
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::scratch::{self, ScratchDatabase};
+use crate::{Error, Migration, MigrationInfo, Migrator, RunOptions, diff, gendown, sql};
+
+/// Outcome of `Migrator::shadow_run`.
+#[derive(Debug, Clone)]
+pub struct ShadowReport {
+    /// Database whose structure was cloned
+    pub source_database: String,
+    /// The temporary database the migrations ran in, dropped by the time this is returned
+    pub shadow_database: String,
+    /// Pending migrations, whether or not all their statements ran
+    pub migrations: Vec<MigrationInfo>,
+    /// Every statement that was executed, in order. Execution stops at the first failure.
+    pub statements: Vec<ShadowStatement>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShadowStatement {
+    pub version: u32,
+    pub file: String,
+    /// 0-based position of the statement in the file
+    pub index: usize,
+    pub sql: String,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+impl ShadowReport {
+    pub fn is_ok(&self) -> bool {
+        self.statements.iter().all(|s| s.error.is_none())
+    }
+}
+
+impl Migrator {
+    /// Prove the pending migrations apply cleanly by running them against a copy of the
+    /// target database's structure.
+    ///
+    /// The tables, views and dictionaries of the target database are recreated in a
+    /// temporary database on the same server, with references to the target database
+    /// rewritten and replicated engines turned into their non-replicated counterparts so
+    /// the copy doesn't register replicas. Tables of engines that reach outside the table
+    /// (`Distributed`, `Buffer`, `Merge`, `Kafka`, ...), whether copied or created by a
+    /// pending migration, get the `Null` engine instead: they would otherwise write to the
+    /// real tables or consume messages meant for the real consumers. Pending statements
+    /// get the same engine rewrites and lose their `ON CLUSTER` clause, and the ones naming
+    /// another database fail instead of running. Hooks don't run and the history isn't
+    /// touched.
+    /// The shadow database is dropped afterwards, whatever the outcome.
+    pub async fn shadow_run(&self, src: &str, options: RunOptions) -> Result<ShadowReport, Error> {
+        let pending = self.run(src, options.dry_run(true)).await?;
+
+        let source_database: String = self
+            .inner
+            .query("SELECT currentDatabase()")
+            .fetch_one()
+            .await?;
+
+        let shadow = ScratchDatabase::create(&self.inner, "shadow").await?;
+        tracing::info!(
+            source = source_database,
+            shadow = shadow.name(),
+            "Running pending migrations in shadow database"
+        );
+        let shadow_database = shadow.name().to_string();
+        let result = shadow_execute(&shadow, &source_database, &pending).await;
+        shadow.drop().await?;
+
+        Ok(ShadowReport {
+            source_database,
+            shadow_database,
+            migrations: pending,
+            statements: result?,
+        })
+    }
+}
+
+async fn shadow_execute(
+    shadow: &ScratchDatabase,
+    source_database: &str,
+    pending: &[MigrationInfo],
+) -> Result<Vec<ShadowStatement>, Error> {
+    let client = shadow.migrator().inner;
+
+    for table in scratch::creation_script(&client, source_database).await? {
+        if table.name == "_ch_migrations" {
+            continue;
+        }
+        let query = stub_external_engine(&unreplicate(&diff::strip_database(
+            &table.create_table_query,
+            source_database,
+        )));
+        client.query(&query).execute().await?;
+    }
+
+    let mut statements = vec![];
+    for mig in pending {
+        let file = mig.file_path(true);
+        let raw = tokio::fs::read(&file).await?;
+        let content = String::from_utf8_lossy(&raw);
+
+        for (index, stmt) in sql::split_statements(&content).into_iter().enumerate() {
+            let started = std::time::Instant::now();
+            let error = match shadow.rewrite(&stmt.sql) {
+                Ok(query) => {
+                    let query = stub_external_engine(&unreplicate(&strip_on_cluster(&query)));
+                    client.query(&query).execute().await.err().map(Error::from)
+                }
+                Err(err) => Some(err),
+            };
+            let failed = error.is_some();
+            statements.push(ShadowStatement {
+                version: mig.version,
+                file: file.clone(),
+                index,
+                sql: stmt.sql,
+                duration: started.elapsed(),
+                error: error.map(|e| e.to_string()),
+            });
+            if failed {
+                return Ok(statements);
+            }
+        }
+    }
+    Ok(statements)
+}
+
+/// `ReplicatedReplacingMergeTree('/path', '{replica}', ver)` -> `ReplacingMergeTree(ver)`.
+pub(crate) fn unreplicate(query: &str) -> String {
+    let Some((_, name)) = engine_name(query) else {
+        return query.to_string();
+    };
+    let ident = &query[name.clone()];
+    if !ident.starts_with("Replicated") || !ident.ends_with("MergeTree") {
+        return query.to_string();
+    }
+    let engine = &ident["Replicated".len()..];
+    let engine_start = name.start;
+
+    let after = &query[name.end..];
+    let Some(args) = after.strip_prefix('(') else {
+        return format!("{}{}{}", &query[..engine_start], engine, after);
+    };
+    let Some(close) = matching_paren(args) else {
+        return query.to_string();
+    };
+    let mut parts = gendown::split_top_level(&args[..close]);
+    // The zookeeper path and replica name are optional when defaults are configured
+    if parts.len() >= 2 && parts[..2].iter().all(|p| p.starts_with('\'')) {
+        parts.drain(..2);
+    }
+    format!(
+        "{}{}({}){}",
+        &query[..engine_start],
+        engine,
+        parts.join(", "),
+        &args[close + 1..]
+    )
+}
+
+/// Engines that read from or write to something other than the table's own data: other
+/// tables (possibly in the source database or on other servers), message queues,
+/// external databases and object stores.
+const EXTERNAL_ENGINES: &[&str] = &[
+    "Distributed",
+    "Buffer",
+    "Merge",
+    "Kafka",
+    "RabbitMQ",
+    "NATS",
+    "KeeperMap",
+    "MySQL",
+    "PostgreSQL",
+    "MaterializedPostgreSQL",
+    "MongoDB",
+    "Redis",
+    "SQLite",
+    "JDBC",
+    "ODBC",
+    "URL",
+    "S3",
+    "S3Queue",
+    "AzureBlobStorage",
+    "AzureQueue",
+    "HDFS",
+    "ExternalDistributed",
+];
+
+/// `CREATE TABLE t (...) ENGINE = Kafka(...) SETTINGS ...` -> `CREATE TABLE t (...) ENGINE = Null`,
+/// other statements are returned as-is.
+pub(crate) fn stub_external_engine(query: &str) -> String {
+    let upper = query.to_ascii_uppercase();
+    if !upper.trim_start().starts_with("CREATE TABLE") {
+        return query.to_string();
+    }
+    let Some((start, name)) = engine_name(query) else {
+        return query.to_string();
+    };
+    if !EXTERNAL_ENGINES.contains(&&query[name]) {
+        return query.to_string();
+    }
+    format!("{}ENGINE = Null", &query[..start])
+}
+
+/// Position of the `ENGINE` keyword of `query`'s `ENGINE = Name` clause, and the byte
+/// range of the engine name.
+fn engine_name(query: &str) -> Option<(usize, Range<usize>)> {
+    let upper = query.to_ascii_uppercase();
+    let start = upper.match_indices("ENGINE").map(|(i, _)| i).find(|&i| {
+        query[..i].ends_with(char::is_whitespace)
+            && query[i + "ENGINE".len()..].trim_start().starts_with('=')
+    })?;
+    let after_eq = start + query[start..].find('=')? + 1;
+    let name_start = query.len() - query[after_eq..].trim_start().len();
+    let name_len = query[name_start..]
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(query.len() - name_start);
+    Some((start, name_start..name_start + name_len))
+}
+
+/// `ALTER TABLE t ON CLUSTER '{cluster}' ADD ...` -> `ALTER TABLE t ADD ...`. The shadow
+/// database only exists on the server it was created on.
+pub(crate) fn strip_on_cluster(query: &str) -> String {
+    let upper = query.to_ascii_uppercase();
+    let bytes = query.as_bytes();
+    let (mut out, mut copied, mut i) = (String::with_capacity(query.len()), 0, 0);
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' | b'`' => i += sql::quoted_len(&query[i..], bytes[i] as char),
+            b'O' | b'o'
+                if (i == 0 || bytes[i - 1].is_ascii_whitespace())
+                    && upper[i..].starts_with("ON")
+                    && upper[i + 2..].starts_with(char::is_whitespace)
+                    && upper[i + 2..].trim_start().starts_with("CLUSTER") =>
+            {
+                let cluster_kw = query.len() - query[i + 2..].trim_start().len();
+                let after_kw = cluster_kw + "CLUSTER".len();
+                let name_start = query.len() - query[after_kw..].trim_start().len();
+                if name_start == after_kw {
+                    i += 1;
+                    continue;
+                }
+                let name_len = match bytes.get(name_start) {
+                    Some(&quote @ (b'\'' | b'"' | b'`')) => {
+                        sql::quoted_len(&query[name_start..], quote as char)
+                    }
+                    _ => query[name_start..]
+                        .find(|c: char| !c.is_ascii_alphanumeric() && !"_-.{}".contains(c))
+                        .unwrap_or(query.len() - name_start),
+                };
+                out.push_str(query[copied..i].trim_end());
+                i = name_start + name_len;
+                copied = i;
+            }
+            _ => i += 1,
+        }
+    }
+    out.push_str(&query[copied..]);
+    out
+}
+
+/// Position of the `)` closing an already opened parenthesis.
+fn matching_paren(s: &str) -> Option<usize> {
+    let (mut depth, mut quote) = (0i32, None);
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '(') => depth += 1,
+            (None, ')') if depth == 0 => return Some(i),
+            (None, ')') => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unreplicate() {
+        assert_eq!(
+            unreplicate(
+                "CREATE TABLE t (id UInt64, v UInt32) ENGINE = ReplicatedReplacingMergeTree('/clickhouse/tables/{shard}/t', '{replica}', v) ORDER BY id"
+            ),
+            "CREATE TABLE t (id UInt64, v UInt32) ENGINE = ReplacingMergeTree(v) ORDER BY id"
+        );
+        assert_eq!(
+            unreplicate("CREATE TABLE t (id UInt64) ENGINE = ReplicatedMergeTree ORDER BY id"),
+            "CREATE TABLE t (id UInt64) ENGINE = MergeTree ORDER BY id"
+        );
+        assert_eq!(
+            unreplicate("CREATE TABLE t (id UInt64) ENGINE = ReplicatedMergeTree() ORDER BY id"),
+            "CREATE TABLE t (id UInt64) ENGINE = MergeTree() ORDER BY id"
+        );
+        let plain = "CREATE TABLE t (id UInt64) ENGINE = MergeTree ORDER BY id";
+        assert_eq!(unreplicate(plain), plain);
+    }
+
+    #[test]
+    fn test_stub_external_engine() {
+        assert_eq!(
+            stub_external_engine(
+                "CREATE TABLE events_all (id UInt64) ENGINE = Distributed('main', 'prod_db', 'events', rand())"
+            ),
+            "CREATE TABLE events_all (id UInt64) ENGINE = Null"
+        );
+        assert_eq!(
+            stub_external_engine(
+                "CREATE TABLE events_queue (id UInt64) ENGINE = Kafka SETTINGS kafka_broker_list = 'kafka:9092', kafka_topic_list = 'events', kafka_group_name = 'prod', kafka_format = 'JSONEachRow'"
+            ),
+            "CREATE TABLE events_queue (id UInt64) ENGINE = Null"
+        );
+        assert_eq!(
+            stub_external_engine(
+                "create table if not exists b (id UInt64) engine=Buffer('prod_db', 'events', 16, 10, 100, 10000, 1000000, 10000000, 100000000)"
+            ),
+            "create table if not exists b (id UInt64) ENGINE = Null"
+        );
+        let local = "CREATE TABLE t (id UInt64) ENGINE = MergeTree ORDER BY id";
+        assert_eq!(stub_external_engine(local), local);
+        let view = "CREATE MATERIALIZED VIEW mv TO t AS SELECT id FROM events_queue";
+        assert_eq!(stub_external_engine(view), view);
+    }
+
+    #[test]
+    fn test_unreplicate_unformatted_engine() {
+        assert_eq!(
+            unreplicate(
+                "create table t (id UInt64) engine=ReplicatedMergeTree('/t', '{replica}') order by id"
+            ),
+            "create table t (id UInt64) engine=MergeTree() order by id"
+        );
+    }
+
+    #[test]
+    fn test_strip_on_cluster() {
+        assert_eq!(
+            strip_on_cluster("ALTER TABLE t ON CLUSTER '{cluster}' ADD COLUMN v UInt32"),
+            "ALTER TABLE t ADD COLUMN v UInt32"
+        );
+        assert_eq!(
+            strip_on_cluster("CREATE TABLE t on cluster main\n(id UInt64) ENGINE = Memory"),
+            "CREATE TABLE t\n(id UInt64) ENGINE = Memory"
+        );
+        assert_eq!(
+            strip_on_cluster("DROP TABLE t ON CLUSTER `prod-1` SYNC"),
+            "DROP TABLE t SYNC"
+        );
+        let quoted = "INSERT INTO t VALUES ('runs ON CLUSTER x')";
+        assert_eq!(strip_on_cluster(quoted), quoted);
+        let plain = "ALTER TABLE one ADD COLUMN cluster String";
+        assert_eq!(strip_on_cluster(plain), plain);
+    }
+
+    #[tokio::test]
+    async fn test_shadow_run_rewrites_pending_statements() {
+        use ch::clickhouse::{self, test};
+
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(
+            temp_dir.path().join("0001_events.sql"),
+            "CREATE TABLE analytics.events ON CLUSTER '{cluster}' (id UInt64) \
+            ENGINE = ReplicatedMergeTree('/clickhouse/tables/{shard}/events', '{replica}') ORDER BY id;\n\
+            ALTER TABLE events ON CLUSTER main ADD COLUMN v UInt32;\n\
+            INSERT INTO billing.invoices SELECT * FROM events;",
+        )
+        .await
+        .unwrap();
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::provide(vec!["analytics".to_string()]));
+        mock.add(test::handlers::provide(vec!["analytics".to_string()]));
+        mock.add(test::handlers::provide(vec![
+            "analytics".to_string(),
+            "billing".to_string(),
+        ]));
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        let create = mock.add(test::handlers::record_ddl());
+        let alter = mock.add(test::handlers::record_ddl());
+        let drop = mock.add(test::handlers::record_ddl());
+
+        let report = migrator
+            .shadow_run(temp_dir.path().to_str().unwrap(), RunOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            create.query().await.trim(),
+            "CREATE TABLE events (id UInt64) ENGINE = MergeTree() ORDER BY id"
+        );
+        assert_eq!(
+            alter.query().await.trim(),
+            "ALTER TABLE events ADD COLUMN v UInt32"
+        );
+        assert!(drop.query().await.starts_with("DROP DATABASE"));
+
+        assert_eq!(report.statements.len(), 3);
+        assert!(report.statements[..2].iter().all(|s| s.error.is_none()));
+        assert!(
+            report.statements[2]
+                .error
+                .as_ref()
+                .unwrap()
+                .contains("billing")
+        );
+    }
+}
@@ -0,0 +1,131 @@
+/// A single statement of a migration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub sql: String,
+    /// 1-based line of the first non-comment line of the statement
+    pub line: usize,
+}
+
+/// Process content: remove full-line comments, then split by semicolon
+pub fn split_statements(content: &str) -> Vec<Statement> {
+    let mut statements = vec![];
+    let mut line = 1;
+
+    for chunk in content.split(';') {
+        let mut first_line = None;
+        // For each statement, filter out comment-only lines but keep inline comments
+        // (ClickHouse handles inline comments fine)
+        let kept: Vec<_> = chunk
+            .split('\n')
+            .enumerate()
+            .filter(|(i, l)| {
+                let trimmed = l.trim();
+                // Keep non-empty lines that don't start with --
+                let keep = !trimmed.is_empty() && !trimmed.starts_with("--");
+                if keep && first_line.is_none() {
+                    first_line = Some(line + i);
+                }
+                keep
+            })
+            .map(|(_, l)| l.strip_suffix('\r').unwrap_or(l))
+            .collect();
+
+        let sql = kept.join("\n").trim().to_string();
+        if let (false, Some(line)) = (sql.is_empty(), first_line) {
+            statements.push(Statement { sql, line });
+        }
+
+        line += chunk.matches('\n').count();
+    }
+
+    statements
+}
+
+/// Upper-cased statement with inline comments removed and whitespace collapsed,
+/// handy for keyword matching. `--` inside quotes is not a comment.
+pub fn normalize(sql: &str) -> String {
+    let mut code = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '\'' | '"' | '`' => {
+                let len = quoted_len(rest, c);
+                code.push_str(&rest[..len]);
+                len
+            }
+            '-' if rest.starts_with("--") => rest.find('\n').unwrap_or(rest.len()),
+            _ => {
+                code.push(c);
+                c.len_utf8()
+            }
+        };
+        rest = &rest[len..];
+    }
+    code.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_uppercase()
+}
+
+/// Length of the `quote`-delimited token `s` starts with, closing quote included.
+/// Backslash escapes and doubled quotes are skipped.
+pub(crate) fn quoted_len(s: &str, quote: char) -> usize {
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == quote {
+            if s[i + 1..].starts_with(quote) {
+                chars.next();
+            } else {
+                return i + 1;
+            }
+        }
+    }
+    s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_statements_tracks_lines() {
+        let content = "-- header\n\nCREATE TABLE a (id UInt32) ENGINE = Memory;\n\n-- second\nCREATE TABLE b\n(id UInt32)\nENGINE = Memory;\n";
+        let stmts = split_statements(content);
+
+        assert_eq!(stmts.len(), 2);
+        assert_eq!(stmts[0].line, 3);
+        assert_eq!(stmts[0].sql, "CREATE TABLE a (id UInt32) ENGINE = Memory");
+        assert_eq!(stmts[1].line, 6);
+        assert_eq!(stmts[1].sql, "CREATE TABLE b\n(id UInt32)\nENGINE = Memory");
+    }
+
+    #[test]
+    fn test_split_statements_skips_comment_only_chunks() {
+        let stmts = split_statements("-- only a comment\n;\n-- another\n");
+        assert!(stmts.is_empty());
+    }
+
+    #[test]
+    fn test_split_statements_keeps_inline_comments() {
+        let stmts = split_statements("SELECT 1 -- one\n;SELECT 2");
+        assert_eq!(stmts[0].sql, "SELECT 1 -- one");
+        assert_eq!(stmts[1].sql, "SELECT 2");
+        assert_eq!(stmts[1].line, 2);
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("create table  a -- note\n(id UInt32)"),
+            "CREATE TABLE A (ID UINT32)"
+        );
+        assert_eq!(
+            normalize(
+                "ALTER TABLE a UPDATE s = '--x' WHERE 1 -- note\nSETTINGS mutations_sync = 2"
+            ),
+            "ALTER TABLE A UPDATE S = '--X' WHERE 1 SETTINGS MUTATIONS_SYNC = 2"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+
+use crate::audit::new_scratch_run_id;
+use crate::scratch::ScratchDatabase;
+use crate::{Error, MigrationFileMode, MigrationInfo, Migrator, diff, fs};
+
+/// Name of the migration written by `Migrator::squash`.
+pub(crate) const BASELINE_NAME: &str = "baseline";
+
+/// Outcome of `Migrator::squash`.
+#[derive(Debug, Clone)]
+pub struct SquashReport {
+    /// Path of the written baseline migration
+    pub baseline: String,
+    /// `(old path, new path)` of every file moved to the archive directory
+    pub archived: Vec<(String, String)>,
+    /// First version covered by the baseline
+    pub from: u32,
+    /// Last version covered by the baseline, also its own version
+    pub to: u32,
+}
+
+impl Migrator {
+    /// Replace every migration up to `up_to` with a single `NNNN_baseline.sql` holding
+    /// the schema they produce, and move the old files to `archive_dir`
+    /// (`<src>/archive` by default).
+    ///
+    /// The schema is built by replaying the up files in a scratch database, so only
+    /// `CREATE` statements end up in the baseline. Data inserted by the squashed
+    /// migrations is not carried over. Statements qualified with the target database are
+    /// redirected to the scratch database, ones naming any other database are refused.
+    ///
+    /// Databases that already applied all the squashed versions report the baseline as
+    /// applied in `info`, fresh databases run it like any other migration.
+    pub async fn squash(
+        &self,
+        src: &str,
+        up_to: u32,
+        archive_dir: Option<&str>,
+    ) -> Result<SquashReport, Error> {
+        let src = src.strip_suffix('/').unwrap_or(src);
+        let migrations: Vec<MigrationInfo> = fs::list_migrations_strict(src)
+            .await?
+            .into_iter()
+            .filter(|mf| mf.seq_num <= up_to)
+            .map(|mf| (mf.seq_num, MigrationInfo::from(mf)))
+            .collect::<BTreeMap<_, _>>()
+            .into_values()
+            .collect();
+
+        if !migrations.iter().any(|m| m.version == up_to) {
+            return Err(Error::InvalidInput(format!(
+                "no migration with version {}",
+                up_to
+            )));
+        }
+        if migrations.len() < 2 {
+            return Err(Error::InvalidInput(format!(
+                "nothing to squash up to version {}",
+                up_to
+            )));
+        }
+
+        // Squashing again extends the previous baseline's range
+        let first = &migrations[0];
+        let from = match first.name == BASELINE_NAME && first.mode == MigrationFileMode::Simple {
+            true => fs::baseline_range(&first.file_path(true))
+                .await?
+                .map(|r| *r.start())
+                .unwrap_or(first.version),
+            false => first.version,
+        };
+
+        let scratch = ScratchDatabase::create(&self.inner, "squash").await?;
+        tracing::info!(database = scratch.name(), "Building baseline schema");
+        let result = build_baseline(&scratch, &migrations).await;
+        scratch.drop().await?;
+        let statements = result?;
+
+        let archive_dir = match archive_dir {
+            Some(dir) => dir.trim_end_matches('/').to_string(),
+            None => format!("{}/archive", src),
+        };
+        tokio::fs::create_dir_all(&archive_dir).await?;
+
+        let mut moves = vec![];
+        for mig in &migrations {
+            let mut paths = vec![mig.file_path(true)];
+            if mig.mode == MigrationFileMode::Reversible {
+                paths.push(mig.file_path(false));
+            }
+            for path in paths {
+                let file_name = path.rsplit('/').next().unwrap_or(&path);
+                let to = format!("{}/{}", archive_dir, file_name);
+                if tokio::fs::try_exists(&to).await? {
+                    return Err(Error::InvalidInput(format!(
+                        "cannot archive {} to {}: file exists",
+                        path, to
+                    )));
+                }
+                moves.push((path, to));
+            }
+        }
+
+        let baseline =
+            fs::build_file_path(src, up_to, BASELINE_NAME, MigrationFileMode::Simple, true);
+        if tokio::fs::try_exists(&baseline).await? {
+            return Err(Error::InvalidInput(format!(
+                "cannot write baseline {}: file exists",
+                baseline
+            )));
+        }
+
+        // The baseline is in place before any file moves, and a failed move puts the
+        // files back, so an interrupted squash never leaves the directory without them
+        let tmp = format!("{}.tmp", baseline);
+        tokio::fs::write(&tmp, render_baseline(from, up_to, &statements)).await?;
+        tokio::fs::rename(&tmp, &baseline).await?;
+
+        if let Err(err) = fs::rename_all(&moves).await {
+            if let Err(err) = tokio::fs::remove_file(&baseline).await {
+                tracing::error!(error = %err, path = %baseline, "Failed to remove baseline");
+            }
+            return Err(err);
+        }
+
+        Ok(SquashReport {
+            baseline,
+            archived: moves,
+            from,
+            to: up_to,
+        })
+    }
+}
+
+async fn build_baseline(
+    scratch: &ScratchDatabase,
+    migrations: &[MigrationInfo],
+) -> Result<Vec<String>, Error> {
+    let migrator = scratch.migrator();
+    let run_id = new_scratch_run_id();
+    for mig in migrations {
+        migrator.execute_migration(mig, true, &run_id).await?;
+    }
+
+    Ok(scratch
+        .creation_script()
+        .await?
+        .into_iter()
+        .map(|t| diff::strip_database(&t.create_table_query, scratch.name()))
+        .collect())
+}
+
+fn render_baseline(from: u32, to: u32, statements: &[String]) -> String {
+    let mut out = format!(
+        "{} from={} to={}\n-- Generated by chutils migrate squash, replaces versions {} to {}\n",
+        fs::BASELINE_MARKER,
+        from,
+        to,
+        from,
+        to
+    );
+    for stmt in statements {
+        out.push('\n');
+        out.push_str(stmt.trim_end());
+        out.push_str(";\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::TableDefinition;
+    use ch::clickhouse::{self, test};
+
+    #[tokio::test]
+    async fn test_render_baseline_roundtrips_marker() {
+        let content = render_baseline(
+            1,
+            12,
+            &["CREATE TABLE users (id UInt64) ENGINE = MergeTree ORDER BY id".to_string()],
+        );
+        assert!(content.ends_with("ORDER BY id;\n"));
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("0012_baseline.sql");
+        tokio::fs::write(&path, &content).await.unwrap();
+        assert_eq!(
+            fs::baseline_range(path.to_str().unwrap()).await.unwrap(),
+            Some(1..=12)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_squash_writes_baseline_and_archives_files() {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().to_str().unwrap();
+        for (name, sql) in [
+            (
+                "0001_users.up.sql",
+                "CREATE TABLE users (id UInt64) ENGINE = MergeTree ORDER BY id",
+            ),
+            ("0001_users.down.sql", "DROP TABLE users"),
+            (
+                "0002_email.sql",
+                "ALTER TABLE users ADD COLUMN email String",
+            ),
+            (
+                "0003_orders.sql",
+                "CREATE TABLE orders (id UInt64) ENGINE = MergeTree ORDER BY id",
+            ),
+        ] {
+            tokio::fs::write(temp_dir.path().join(name), sql)
+                .await
+                .unwrap();
+        }
+        let scratch = || {
+            mock.add(test::handlers::provide(vec!["default".to_string()]));
+            mock.add(test::handlers::provide(vec!["default".to_string()]));
+            mock.add(test::handlers::record_ddl());
+            mock.add(test::handlers::record_ddl());
+            mock.add(test::handlers::record_ddl());
+            mock.add(test::handlers::provide(vec![TableDefinition {
+                name: "users".to_string(),
+                create_table_query:
+                    "CREATE TABLE users (id UInt64, email String) ENGINE = MergeTree ORDER BY id"
+                        .to_string(),
+            }]));
+            mock.add(test::handlers::record_ddl());
+        };
+
+        // An archived file of the same name is never overwritten
+        let archive = temp_dir.path().join("archive");
+        tokio::fs::create_dir(&archive).await.unwrap();
+        tokio::fs::write(archive.join("0002_email.sql"), "")
+            .await
+            .unwrap();
+        scratch();
+        assert!(matches!(
+            migrator.squash(src, 2, None).await,
+            Err(Error::InvalidInput(msg)) if msg.contains("file exists")
+        ));
+        assert!(!temp_dir.path().join("0002_baseline.sql").exists());
+        assert!(temp_dir.path().join("0001_users.up.sql").exists());
+        assert!(!archive.join("0001_users.up.sql").exists());
+
+        tokio::fs::remove_file(archive.join("0002_email.sql"))
+            .await
+            .unwrap();
+        scratch();
+        let report = migrator.squash(src, 2, None).await.unwrap();
+        assert_eq!((report.from, report.to), (1, 2));
+        assert_eq!(report.baseline, format!("{}/0002_baseline.sql", src));
+        assert_eq!(report.archived.len(), 3);
+        for name in ["0001_users.up.sql", "0001_users.down.sql", "0002_email.sql"] {
+            assert!(archive.join(name).exists());
+            assert!(!temp_dir.path().join(name).exists());
+        }
+
+        let files = fs::list_migrations_strict(src).await.unwrap();
+        let mut versions: Vec<_> = files.iter().map(|f| (f.seq_num, f.name.as_str())).collect();
+        versions.sort();
+        assert_eq!(versions, vec![(2, BASELINE_NAME), (3, "orders")]);
+        assert_eq!(
+            fs::baseline_range(&report.baseline).await.unwrap(),
+            Some(1..=2)
+        );
+        let content = tokio::fs::read_to_string(&report.baseline).await.unwrap();
+        assert!(content.contains("CREATE TABLE users (id UInt64, email String)"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{Error, Migration, MigrationStatus, Migrator, fs};
+
+/// Where a database stands compared to the local migrations, see `Migrator::status`.
+#[derive(Debug)]
+pub enum SchemaState {
+    /// Every local migration is applied and the history has nothing else
+    UpToDate { version: Option<u32> },
+    /// Local migrations newer than the latest applied one are waiting
+    Pending {
+        latest_applied: Option<u32>,
+        pending: Vec<u32>,
+    },
+    /// The history has versions newer than the latest local file, e.g. a deploy of an
+    /// older build
+    Ahead {
+        latest_local: Option<u32>,
+        unknown: Vec<u32>,
+    },
+    /// History and local files disagree in a way `up` can't fix: a name mismatch, an
+    /// unknown version in the middle, a pending migration older than applied ones, ...
+    Corrupted(Error),
+}
+
+impl std::fmt::Display for SchemaState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let version = |v: &Option<u32>| match v {
+            Some(v) => format!("{:04}", v),
+            None => "none".to_string(),
+        };
+        match self {
+            Self::UpToDate { version: v } => write!(f, "up to date at {}", version(v)),
+            Self::Pending {
+                latest_applied,
+                pending,
+            } => write!(
+                f,
+                "{} pending migration(s) after {}: {:?}",
+                pending.len(),
+                version(latest_applied),
+                pending
+            ),
+            Self::Ahead {
+                latest_local,
+                unknown,
+            } => write!(
+                f,
+                "database is ahead of the latest local migration {}: {:?}",
+                version(latest_local),
+                unknown
+            ),
+            Self::Corrupted(err) => write!(f, "history is corrupted: {}", err),
+        }
+    }
+}
+
+impl Migrator {
+    /// Compare the history with the local migrations of `src`, for deploy gates.
+    ///
+    /// Disagreements are returned as `SchemaState::Corrupted`; only failures to read the
+    /// files or reach the server are errors.
+    pub async fn status(&self, src: &str) -> Result<SchemaState, Error> {
+        let migs = match self.info(src, false).await {
+            Ok(migs) => migs,
+            Err(Error::MissingLocal { .. }) => return self.unknown_versions(src).await,
+            Err(
+                err @ (Error::NameMismatch { .. }
+                | Error::PartialBaseline { .. }
+                | Error::InvalidMigrationSet(_)),
+            ) => return Ok(SchemaState::Corrupted(err)),
+            Err(err) => return Err(err),
+        };
+
+        let latest_applied = migs
+            .iter()
+            .filter(|m| m.status == MigrationStatus::Applied)
+            .map(|m| m.version)
+            .max();
+        let pending: Vec<_> = migs
+            .iter()
+            .filter(|m| m.status == MigrationStatus::Pending)
+            .map(|m| m.version)
+            .collect();
+
+        if let (Some(&first), Some(latest)) = (pending.first(), latest_applied) {
+            if first < latest {
+                return Ok(SchemaState::Corrupted(Error::OutOfOrder {
+                    pending: first,
+                    latest_applied: latest,
+                }));
+            }
+        }
+        if pending.is_empty() {
+            return Ok(SchemaState::UpToDate {
+                version: latest_applied,
+            });
+        }
+        Ok(SchemaState::Pending {
+            latest_applied,
+            pending,
+        })
+    }
+
+    /// The history has versions without local files: ahead if they all come after
+    /// the local ones and the known versions agree with the local files, corrupted
+    /// otherwise.
+    async fn unknown_versions(&self, src: &str) -> Result<SchemaState, Error> {
+        let local: BTreeMap<u32, String> = fs::list_migrations_strict(src)
+            .await?
+            .into_iter()
+            .map(|f| (f.seq_num, f.name))
+            .collect();
+        let latest_local = local.keys().max().copied();
+
+        let mut applied = self.history().load().await?;
+        applied.sort_by_key(|row| row.version);
+
+        for row in &applied {
+            if let Some(name) = local.get(&row.version) {
+                if *name != row.name {
+                    return Ok(SchemaState::Corrupted(Error::NameMismatch {
+                        version: row.version,
+                        db_name: row.name.clone(),
+                        local_name: name.clone(),
+                    }));
+                }
+            }
+        }
+        let applied_versions: HashSet<u32> = applied.iter().map(|row| row.version).collect();
+        let first_pending = local.keys().find(|v| !applied_versions.contains(v));
+        if let (Some(&pending), Some(latest_applied)) =
+            (first_pending, applied.last().map(|row| row.version))
+        {
+            if pending < latest_applied {
+                return Ok(SchemaState::Corrupted(Error::OutOfOrder {
+                    pending,
+                    latest_applied,
+                }));
+            }
+        }
+
+        let mut unknown = vec![];
+        for row in applied {
+            if local.contains_key(&row.version) {
+                continue;
+            }
+            if latest_local.is_some_and(|latest| row.version < latest) {
+                return Ok(SchemaState::Corrupted(Error::MissingLocal {
+                    version: row.version,
+                    name: row.name,
+                }));
+            }
+            unknown.push(row.version);
+        }
+
+        Ok(SchemaState::Ahead {
+            latest_local,
+            unknown,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MigrationFileMode::Simple;
+    use crate::tests::applied;
+    use ch::clickhouse::{self, test};
+
+    async fn setup() -> (test::Mock, Migrator, tempfile::TempDir) {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()));
+        let temp_dir = tempfile::tempdir().unwrap();
+        for name in ["0001_a.sql", "0002_b.sql"] {
+            tokio::fs::write(temp_dir.path().join(name), "SELECT 1")
+                .await
+                .unwrap();
+        }
+        (mock, migrator, temp_dir)
+    }
+
+    #[tokio::test]
+    async fn test_status_pending_and_up_to_date() {
+        let (mock, migrator, temp_dir) = setup().await;
+        let src = temp_dir.path().to_str().unwrap();
+
+        mock.add(test::handlers::provide(vec![applied(1, "a", Simple)]));
+        let state = migrator.status(src).await.unwrap();
+        assert!(
+            matches!(state, SchemaState::Pending { latest_applied: Some(1), ref pending } if pending == &[2])
+        );
+
+        mock.add(test::handlers::provide(vec![
+            applied(1, "a", Simple),
+            applied(2, "b", Simple),
+        ]));
+        let state = migrator.status(src).await.unwrap();
+        assert!(matches!(state, SchemaState::UpToDate { version: Some(2) }));
+    }
+
+    #[tokio::test]
+    async fn test_status_ahead_and_corrupted() {
+        let (mock, migrator, temp_dir) = setup().await;
+        let src = temp_dir.path().to_str().unwrap();
+
+        let rows = vec![
+            applied(1, "a", Simple),
+            applied(2, "b", Simple),
+            applied(3, "c", Simple),
+        ];
+        mock.add(test::handlers::provide(rows.clone()));
+        mock.add(test::handlers::provide(rows.clone()));
+        let state = migrator.status(src).await.unwrap();
+        assert!(
+            matches!(state, SchemaState::Ahead { latest_local: Some(2), ref unknown } if unknown == &[3])
+        );
+
+        // 2 is pending behind the unknown 3
+        let rows = vec![applied(1, "a", Simple), applied(3, "c", Simple)];
+        mock.add(test::handlers::provide(rows.clone()));
+        mock.add(test::handlers::provide(rows));
+        let state = migrator.status(src).await.unwrap();
+        assert!(matches!(
+            state,
+            SchemaState::Corrupted(Error::OutOfOrder {
+                pending: 2,
+                latest_applied: 3
+            })
+        ));
+
+        mock.add(test::handlers::provide(vec![applied(1, "renamed", Simple)]));
+        let state = migrator.status(src).await.unwrap();
+        assert!(matches!(
+            state,
+            SchemaState::Corrupted(Error::NameMismatch { version: 1, .. })
+        ));
+
+        mock.add(test::handlers::provide(vec![applied(2, "b", Simple)]));
+        let state = migrator.status(src).await.unwrap();
+        assert!(matches!(
+            state,
+            SchemaState::Corrupted(Error::OutOfOrder { pending: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,332 @@
+use std::sync::Arc;
+
+use crate::{Direction, Error, MigrationInfo, Migrator, sql};
+
+impl Migrator {
+    /// Run each migration and its history write in a server transaction (experimental).
+    ///
+    /// Needs a server with `allow_experimental_transactions` and MergeTree tables. When the
+    /// server refuses `BEGIN TRANSACTION`, or the history isn't kept in `_ch_migrations`,
+    /// migrations run without transactions as usual. So do migrations with statements
+    /// ClickHouse doesn't allow in a transaction (DDL), after a warning.
+    pub fn with_transactions(mut self, enabled: bool) -> Self {
+        self.transactions = enabled;
+        self
+    }
+
+    /// Whether this run can use transactions, checked once with a throwaway one.
+    pub(crate) async fn transactions_supported(&self, run_id: &str) -> bool {
+        if !self.transactions {
+            return false;
+        }
+        if self.history.is_some() {
+            tracing::warn!("Transactions need the history in _ch_migrations, running without");
+            return false;
+        }
+
+        let probe = self.session(format!("chutils-{}-probe", run_id));
+        match probe.inner.query("BEGIN TRANSACTION").execute().await {
+            Ok(()) => {
+                if let Err(err) = probe.inner.query("ROLLBACK").execute().await {
+                    tracing::debug!(error = %err, "Failed to roll back probe transaction");
+                }
+                true
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "Server doesn't support transactions, running without");
+                false
+            }
+        }
+    }
+
+    /// A copy of this migrator bound to a new transaction for `mig`, `None` when `enabled`
+    /// is unset or the migration has statements a transaction can't hold.
+    pub(crate) async fn begin_transaction(
+        &self,
+        enabled: bool,
+        run_id: &str,
+        mig: &MigrationInfo,
+        direction: Direction,
+    ) -> Result<Option<Migrator>, Error> {
+        if !enabled {
+            return Ok(None);
+        }
+        let file = mig.file_path(direction == Direction::Up);
+        let raw = tokio::fs::read(&file).await?;
+        let content = String::from_utf8_lossy(&raw);
+        if let Some(stmt) = sql::split_statements(&content)
+            .into_iter()
+            .find(|stmt| !allowed_in_transaction(&stmt.sql))
+        {
+            tracing::warn!(
+                version = mig.version,
+                line = stmt.line,
+                "Statement not allowed in a transaction, running the migration without"
+            );
+            return Ok(None);
+        }
+
+        let session = self.session(format!(
+            "chutils-{}-{:04}-{}",
+            run_id, mig.version, direction
+        ));
+        session.inner.query("BEGIN TRANSACTION").execute().await?;
+        Ok(Some(session))
+    }
+
+    /// Every query of the returned migrator, history writes included, shares one HTTP
+    /// session, which is what transactions are scoped to.
+    fn session(&self, session_id: String) -> Migrator {
+        Migrator {
+            inner: Arc::new((*self.inner).clone().with_option("session_id", session_id)),
+            ..self.clone()
+        }
+    }
+}
+
+/// Commit the transaction of `session` if `result` is ok, roll it back otherwise.
+pub(crate) async fn end_transaction(
+    session: Option<Migrator>,
+    result: Result<(), Error>,
+) -> Result<(), Error> {
+    let Some(session) = session else {
+        return result;
+    };
+    match result {
+        Ok(()) => match session.inner.query("COMMIT").execute().await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                rollback(&session).await;
+                Err(err.into())
+            }
+        },
+        Err(err) => {
+            rollback(&session).await;
+            Err(err)
+        }
+    }
+}
+
+async fn rollback(session: &Migrator) {
+    if let Err(err) = session.inner.query("ROLLBACK").execute().await {
+        tracing::warn!(error = %err, "Failed to roll back transaction");
+    }
+}
+
+/// Whether ClickHouse accepts `statement` inside a transaction: reads, inserts and
+/// mutations (`ALTER TABLE ... DELETE/UPDATE`, `DELETE FROM`). DDL is refused.
+fn allowed_in_transaction(statement: &str) -> bool {
+    let code = statement
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("--"))
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_ascii_uppercase();
+    let words: Vec<&str> = code.split_whitespace().collect();
+    match words.first().copied() {
+        Some("INSERT" | "SELECT" | "WITH" | "DELETE") => true,
+        Some("ALTER") => {
+            // ALTER TABLE t [ON CLUSTER c] DELETE/UPDATE ...
+            let mut rest = words.get(3..).unwrap_or_default();
+            if rest.starts_with(&["ON", "CLUSTER"]) {
+                rest = rest.get(3..).unwrap_or_default();
+            }
+            matches!(rest.first(), Some(&("DELETE" | "UPDATE")))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::allowed_in_transaction;
+    use crate::{Migration, MigrationFileMode, MigrationInfo, Migrator, RevertOptions, RunOptions};
+    use ch::clickhouse::{self, test};
+
+    async fn setup() -> (test::Mock, Migrator, tempfile::TempDir) {
+        let mock = test::Mock::new();
+        let migrator = Migrator::from_client(clickhouse::Client::default().with_url(mock.url()))
+            .with_transactions(true);
+        let temp_dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(
+            temp_dir.path().join("0001_backfill.sql"),
+            "INSERT INTO a SELECT * FROM b",
+        )
+        .await
+        .unwrap();
+        (mock, migrator, temp_dir)
+    }
+
+    #[tokio::test]
+    async fn test_run_commits_transaction() {
+        let (mock, migrator, temp_dir) = setup().await;
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        let begin = mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        let history = mock.add(test::handlers::record());
+        let commit = mock.add(test::handlers::record_ddl());
+
+        let applied = migrator
+            .run(temp_dir.path().to_str().unwrap(), RunOptions::new())
+            .await
+            .unwrap();
+        assert!(applied[0].transactional);
+
+        assert_eq!(begin.query().await.trim(), "BEGIN TRANSACTION");
+        let rows: Vec<MigrationInfo> = history.collect().await;
+        assert!(rows[0].transactional);
+        assert_eq!(commit.query().await.trim(), "COMMIT");
+    }
+
+    #[tokio::test]
+    async fn test_run_rolls_back_failed_migration() {
+        let (mock, migrator, temp_dir) = setup().await;
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::failure(test::status::BAD_REQUEST));
+        let rollback = mock.add(test::handlers::record_ddl());
+
+        let result = migrator
+            .run(temp_dir.path().to_str().unwrap(), RunOptions::new())
+            .await;
+        assert!(result.is_err());
+        assert_eq!(rollback.query().await.trim(), "ROLLBACK");
+    }
+
+    #[tokio::test]
+    async fn test_run_falls_back_without_transactions() {
+        let (mock, migrator, temp_dir) = setup().await;
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::failure(test::status::BAD_REQUEST));
+        let statement = mock.add(test::handlers::record_ddl());
+        let history = mock.add(test::handlers::record());
+
+        let applied = migrator
+            .run(temp_dir.path().to_str().unwrap(), RunOptions::new())
+            .await
+            .unwrap();
+        assert!(!applied[0].transactional);
+
+        assert!(statement.query().await.starts_with("INSERT INTO a"));
+        let rows: Vec<MigrationInfo> = history.collect().await;
+        assert!(!rows[0].transactional);
+    }
+
+    #[tokio::test]
+    async fn test_run_rolls_back_failed_commit() {
+        let (mock, migrator, temp_dir) = setup().await;
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record::<MigrationInfo>());
+        mock.add(test::handlers::failure(test::status::BAD_REQUEST));
+        let rollback = mock.add(test::handlers::record_ddl());
+
+        let result = migrator
+            .run(temp_dir.path().to_str().unwrap(), RunOptions::new())
+            .await;
+        assert!(result.is_err());
+        assert_eq!(rollback.query().await.trim(), "ROLLBACK");
+    }
+
+    #[tokio::test]
+    async fn test_revert_commits_transaction() {
+        let (mock, migrator, temp_dir) = setup().await;
+        for (name, sql) in [
+            (
+                "0002_users.up.sql",
+                "INSERT INTO users SELECT * FROM staging",
+            ),
+            ("0002_users.down.sql", "DELETE FROM users WHERE imported"),
+        ] {
+            tokio::fs::write(temp_dir.path().join(name), sql)
+                .await
+                .unwrap();
+        }
+
+        mock.add(test::handlers::provide(vec![
+            crate::tests::applied(1, "backfill", MigrationFileMode::Simple),
+            crate::tests::applied(2, "users", MigrationFileMode::Reversible),
+        ]));
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        let begin = mock.add(test::handlers::record_ddl());
+        let statement = mock.add(test::handlers::record_ddl());
+        let history = mock.add(test::handlers::record_ddl());
+        let commit = mock.add(test::handlers::record_ddl());
+
+        let reverted = migrator
+            .revert(temp_dir.path().to_str().unwrap(), RevertOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(reverted[0].version, 2);
+
+        assert_eq!(begin.query().await.trim(), "BEGIN TRANSACTION");
+        assert!(statement.query().await.starts_with("DELETE FROM users"));
+        assert!(
+            history
+                .query()
+                .await
+                .starts_with("DELETE FROM _ch_migrations")
+        );
+        assert_eq!(commit.query().await.trim(), "COMMIT");
+    }
+
+    #[tokio::test]
+    async fn test_run_skips_transaction_for_ddl() {
+        let (mock, migrator, temp_dir) = setup().await;
+        tokio::fs::write(
+            temp_dir.path().join("0001_backfill.sql"),
+            "CREATE TABLE a (id UInt64) ENGINE = MergeTree ORDER BY id;\nINSERT INTO a SELECT * FROM b;",
+        )
+        .await
+        .unwrap();
+
+        mock.add(test::handlers::provide(Vec::<MigrationInfo>::new()));
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        let create = mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        let history = mock.add(test::handlers::record());
+
+        let applied = migrator
+            .run(temp_dir.path().to_str().unwrap(), RunOptions::new())
+            .await
+            .unwrap();
+        assert!(!applied[0].transactional);
+
+        assert!(create.query().await.starts_with("CREATE TABLE a"));
+        let rows: Vec<MigrationInfo> = history.collect().await;
+        assert!(!rows[0].transactional);
+    }
+
+    #[test]
+    fn test_allowed_in_transaction() {
+        assert!(allowed_in_transaction(
+            "-- copy\nINSERT INTO a SELECT * FROM b"
+        ));
+        assert!(allowed_in_transaction("delete from a where id = 1"));
+        assert!(allowed_in_transaction(
+            "ALTER TABLE a UPDATE v = 1 WHERE id = 1"
+        ));
+        assert!(allowed_in_transaction(
+            "ALTER TABLE a ON CLUSTER main DELETE WHERE id = 1"
+        ));
+        assert!(!allowed_in_transaction("ALTER TABLE a ADD COLUMN v UInt8"));
+        assert!(!allowed_in_transaction(
+            "CREATE TABLE a (id UInt8) ENGINE = Memory"
+        ));
+        assert!(!allowed_in_transaction("DROP TABLE a"));
+    }
+}
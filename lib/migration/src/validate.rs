@@ -0,0 +1,103 @@
+use crate::{Error, Migration, MigrationStatus, Migrator, sql};
+
+/// A statement of a pending migration the server refused to parse.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ValidationError {
+    pub version: u32,
+    pub name: String,
+    pub file: String,
+    pub line: usize,
+    pub statement: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// Server error codes of statements that don't parse: `SYNTAX_ERROR`, `TOO_DEEP_AST`
+/// and `TOO_BIG_AST`.
+const PARSE_ERROR_CODES: &[u32] = &[62, 167, 168];
+
+/// Server error code of `err`, read from its `Code: 62. DB::Exception: ...` message.
+fn error_code(err: &ch::ClickhouseError) -> Option<u32> {
+    let ch::ClickhouseError::BadResponse(message) = err else {
+        return None;
+    };
+    let code = message.split_once("Code: ")?.1;
+    let len = code
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(code.len());
+    code[..len].parse().ok()
+}
+
+impl Migrator {
+    /// Parse every statement of every pending migration on the server with
+    /// `EXPLAIN AST`, without executing anything.
+    /// All parse failures are collected instead of stopping at the first one; any other
+    /// server or connection error is returned as is.
+    pub async fn validate(
+        &self,
+        src: &str,
+        ignore_missing: bool,
+    ) -> Result<Vec<ValidationError>, Error> {
+        let pending = self
+            .info(src, ignore_missing)
+            .await?
+            .into_iter()
+            .filter(|m| m.status == MigrationStatus::Pending);
+
+        let mut errors = vec![];
+        for mig in pending {
+            let file = mig.file_path(true);
+            let raw = tokio::fs::read(&file).await?;
+            let content = String::from_utf8_lossy(&raw);
+
+            for stmt in sql::split_statements(&content) {
+                let explain = format!("EXPLAIN AST {}", stmt.sql);
+                let Err(err) = self.inner.query(&explain).execute().await else {
+                    continue;
+                };
+                if !error_code(&err).is_some_and(|code| PARSE_ERROR_CODES.contains(&code)) {
+                    return Err(err.into());
+                }
+                tracing::debug!(error=?err, file, line = stmt.line, "Statement failed validation");
+                errors.push(ValidationError {
+                    version: mig.version,
+                    name: mig.name.clone(),
+                    file: file.clone(),
+                    line: stmt.line,
+                    statement: stmt.sql,
+                    message: err.to_string(),
+                });
+            }
+        }
+
+        Ok(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        let syntax = ch::ClickhouseError::BadResponse(
+            "Code: 62. DB::Exception: Syntax error: failed at position 8 ('TABLEE'). (SYNTAX_ERROR)"
+                .to_string(),
+        );
+        assert_eq!(error_code(&syntax), Some(62));
+        let unknown_db = ch::ClickhouseError::BadResponse(
+            "Code: 81. DB::Exception: Database app doesn't exist. (UNKNOWN_DATABASE)".to_string(),
+        );
+        assert_eq!(error_code(&unknown_db), Some(81));
+        assert_eq!(
+            error_code(&ch::ClickhouseError::BadResponse("Bad Request".to_string())),
+            None
+        );
+        assert_eq!(error_code(&ch::ClickhouseError::TimedOut), None);
+    }
+}
@@ -1,6 +1,6 @@
 use ch::Builder;
 use ch::clickhouse;
-use migration::{Migration, MigrationFileMode, MigrationStatus};
+use migration::{Migration, MigrationFileMode, MigrationStatus, RevertOptions, RunOptions};
 use serial_test::serial;
 use std::sync::atomic::{AtomicU32, Ordering};
 
@@ -222,7 +222,7 @@ async fn test_run_dry_run() {
         .unwrap();
 
     // Dry run should return pending migrations without applying
-    let result = migrator.run(src, true, false, None).await;
+    let result = migrator.run(src, RunOptions::new().dry_run(true)).await;
     assert!(result.is_ok());
 
     let pending = result.unwrap();
@@ -260,7 +260,7 @@ async fn test_run_applies_migrations() {
     .unwrap();
 
     // Run migration
-    let result = migrator.run(src, false, false, None).await;
+    let result = migrator.run(src, RunOptions::new()).await;
     assert!(result.is_ok(), "run failed: {:?}", result.err());
 
     let applied = result.unwrap();
@@ -302,7 +302,9 @@ async fn test_run_with_target_version() {
         .unwrap();
 
     // Run only up to version 2 (dry run to avoid side effects)
-    let result = migrator.run(src, true, false, Some(2)).await;
+    let result = migrator
+        .run(src, RunOptions::new().dry_run(true).target_version(Some(2)))
+        .await;
     assert!(result.is_ok());
 
     let pending = result.unwrap();
@@ -321,7 +323,7 @@ async fn test_run_no_pending_migrations() {
     let src = temp_dir.path().to_str().unwrap();
 
     // Empty directory - no migrations to run
-    let result = migrator.run(src, false, false, None).await;
+    let result = migrator.run(src, RunOptions::new()).await;
     assert!(result.is_ok());
     assert!(result.unwrap().is_empty());
 }
@@ -354,7 +356,7 @@ async fn test_run_multiple_queries_in_single_file() {
     .unwrap();
 
     // Run migration
-    let result = migrator.run(src, false, false, None).await;
+    let result = migrator.run(src, RunOptions::new()).await;
     assert!(result.is_ok(), "run failed: {:?}", result.err());
 
     // Cleanup
@@ -407,10 +409,12 @@ async fn test_revert_dry_run() {
     .unwrap();
 
     // Apply migration first
-    migrator.run(src, false, false, None).await.unwrap();
+    migrator.run(src, RunOptions::new()).await.unwrap();
 
     // Dry run revert
-    let result = migrator.revert(src, true, false, None).await;
+    let result = migrator
+        .revert(src, RevertOptions::new().dry_run(true))
+        .await;
     assert!(result.is_ok());
 
     let targets = result.unwrap();
@@ -447,10 +451,12 @@ async fn test_revert_simple_migration_not_revertable() {
         .unwrap();
 
     // Apply migration
-    migrator.run(src, false, false, None).await.unwrap();
+    migrator.run(src, RunOptions::new()).await.unwrap();
 
     // Try to revert - should return empty (no revertable migrations)
-    let result = migrator.revert(src, true, false, None).await;
+    let result = migrator
+        .revert(src, RevertOptions::new().dry_run(true))
+        .await;
     assert!(result.is_ok());
     assert!(result.unwrap().is_empty());
 }
@@ -483,7 +489,7 @@ async fn test_revert_executes_down_migration() {
         .unwrap();
 
     // Apply migration
-    migrator.run(src, false, false, None).await.unwrap();
+    migrator.run(src, RunOptions::new()).await.unwrap();
 
     // Verify table exists
     let client = build_raw_client();
@@ -495,7 +501,7 @@ async fn test_revert_executes_down_migration() {
     assert_eq!(exists_result.unwrap(), 1);
 
     // Revert migration
-    let result = migrator.revert(src, false, false, None).await;
+    let result = migrator.revert(src, RevertOptions::new()).await;
     assert!(result.is_ok(), "revert failed: {:?}", result.err());
 
     let reverted = result.unwrap();
@@ -536,10 +542,15 @@ async fn test_revert_with_target_version() {
     }
 
     // Apply all migrations
-    migrator.run(src, false, false, None).await.unwrap();
+    migrator.run(src, RunOptions::new()).await.unwrap();
 
     // Revert down to version 1 (keep 1, revert 2 and 3) - dry run
-    let result = migrator.revert(src, true, false, Some(1)).await;
+    let result = migrator
+        .revert(
+            src,
+            RevertOptions::new().dry_run(true).target_version(Some(1)),
+        )
+        .await;
     assert!(result.is_ok());
 
     let targets = result.unwrap();
@@ -568,10 +579,12 @@ async fn test_revert_latest_only() {
     }
 
     // Apply all migrations
-    migrator.run(src, false, false, None).await.unwrap();
+    migrator.run(src, RunOptions::new()).await.unwrap();
 
     // Revert without target_version should only revert the latest
-    let result = migrator.revert(src, true, false, None).await;
+    let result = migrator
+        .revert(src, RevertOptions::new().dry_run(true))
+        .await;
     assert!(result.is_ok());
 
     let targets = result.unwrap();
@@ -621,7 +634,7 @@ async fn test_full_migration_workflow() {
     assert_eq!(info[0].status, MigrationStatus::Pending);
 
     // 4. Run migration
-    let applied = migrator.run(src, false, false, None).await.unwrap();
+    let applied = migrator.run(src, RunOptions::new()).await.unwrap();
     assert_eq!(applied.len(), 1);
     assert_eq!(applied[0].status, MigrationStatus::Applied);
 
@@ -630,11 +643,11 @@ async fn test_full_migration_workflow() {
     assert_eq!(info[0].status, MigrationStatus::Applied);
 
     // 6. Run again - should be no-op
-    let applied = migrator.run(src, false, false, None).await.unwrap();
+    let applied = migrator.run(src, RunOptions::new()).await.unwrap();
     assert!(applied.is_empty());
 
     // 7. Revert migration
-    let reverted = migrator.revert(src, false, false, None).await.unwrap();
+    let reverted = migrator.revert(src, RevertOptions::new()).await.unwrap();
     assert_eq!(reverted.len(), 1);
     assert_eq!(reverted[0].status, MigrationStatus::Pending);
 
@@ -643,7 +656,7 @@ async fn test_full_migration_workflow() {
     assert_eq!(info[0].status, MigrationStatus::Pending);
 
     // 9. Run migration again
-    let applied = migrator.run(src, false, false, None).await.unwrap();
+    let applied = migrator.run(src, RunOptions::new()).await.unwrap();
     assert_eq!(applied.len(), 1);
 
     // Cleanup
@@ -676,7 +689,7 @@ async fn test_run_invalid_sql_fails() {
     .unwrap();
 
     // Run should fail
-    let result = migrator.run(src, false, false, None).await;
+    let result = migrator.run(src, RunOptions::new()).await;
     assert!(result.is_err());
 }
 
@@ -733,7 +746,7 @@ INSERT INTO {} (id, name) VALUES (1, 'test'); -- trailing comment
     .unwrap();
 
     // Run migration
-    let result = migrator.run(src, false, false, None).await;
+    let result = migrator.run(src, RunOptions::new()).await;
     assert!(result.is_ok(), "run failed: {:?}", result.err());
 
     // Verify table was created and data inserted
@@ -806,7 +819,7 @@ DROP TABLE IF EXISTS {};
         .unwrap();
 
     // Run up migration
-    let result = migrator.run(src, false, false, None).await;
+    let result = migrator.run(src, RunOptions::new()).await;
     assert!(result.is_ok(), "run failed: {:?}", result.err());
 
     // Verify table exists
@@ -820,7 +833,7 @@ DROP TABLE IF EXISTS {};
     assert_eq!(exists, 1, "Table should exist after up migration");
 
     // Run down migration (revert)
-    let result = migrator.revert(src, false, false, None).await;
+    let result = migrator.revert(src, RevertOptions::new()).await;
     assert!(result.is_ok(), "revert failed: {:?}", result.err());
 
     // Verify table no longer exists
@@ -858,7 +871,7 @@ async fn test_migration_with_only_comments_skipped() {
     .unwrap();
 
     // Run migration - should succeed (no queries to execute)
-    let result = migrator.run(src, false, false, None).await;
+    let result = migrator.run(src, RunOptions::new()).await;
     assert!(result.is_ok(), "run failed: {:?}", result.err());
 
     // Migration should be recorded as applied
@@ -866,3 +879,149 @@ async fn test_migration_with_only_comments_skipped() {
     assert_eq!(info.len(), 1);
     assert_eq!(info[0].status, MigrationStatus::Applied);
 }
+
+// ==================== Round-trip Verification Tests ====================
+
+#[tokio::test]
+#[serial(clickhouse)]
+async fn test_verify_roundtrip_restores_schema() {
+    let migrator = require_clickhouse!();
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let src = temp_dir.path().to_str().unwrap();
+
+    tokio::fs::write(
+        format!("{}/0001_create_users.up.sql", src),
+        b"CREATE TABLE users (id UInt32) ENGINE = MergeTree ORDER BY id",
+    )
+    .await
+    .unwrap();
+    tokio::fs::write(
+        format!("{}/0001_create_users.down.sql", src),
+        b"DROP TABLE users",
+    )
+    .await
+    .unwrap();
+    tokio::fs::write(
+        format!("{}/0002_add_email.up.sql", src),
+        b"ALTER TABLE users ADD COLUMN email String",
+    )
+    .await
+    .unwrap();
+    tokio::fs::write(
+        format!("{}/0002_add_email.down.sql", src),
+        b"ALTER TABLE users DROP COLUMN email",
+    )
+    .await
+    .unwrap();
+
+    let reports = migrator.verify_roundtrip(src).await.unwrap();
+    assert_eq!(reports.len(), 2);
+    assert!(reports.iter().all(|r| r.is_ok()), "{:?}", reports);
+}
+
+#[tokio::test]
+#[serial(clickhouse)]
+async fn test_verify_roundtrip_detects_incomplete_down() {
+    let migrator = require_clickhouse!();
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let src = temp_dir.path().to_str().unwrap();
+
+    tokio::fs::write(
+        format!("{}/0001_create_tables.up.sql", src),
+        b"CREATE TABLE a (id UInt32) ENGINE = Memory;\nCREATE TABLE b (id UInt32) ENGINE = Memory;",
+    )
+    .await
+    .unwrap();
+    // Forgets to drop `b`
+    tokio::fs::write(
+        format!("{}/0001_create_tables.down.sql", src),
+        b"DROP TABLE a",
+    )
+    .await
+    .unwrap();
+
+    let reports = migrator.verify_roundtrip(src).await.unwrap();
+    assert_eq!(reports.len(), 1);
+    match &reports[0].outcome {
+        migration::RoundtripOutcome::SchemaMismatch(diff) => {
+            assert_eq!(diff.added, vec!["b".to_string()]);
+        }
+        other => panic!("unexpected outcome: {:?}", other),
+    }
+    assert!(
+        reports[0]
+            .down_file
+            .ends_with("0001_create_tables.down.sql")
+    );
+}
+
+// ==================== Schema Diff Tests ====================
+
+#[tokio::test]
+#[serial(clickhouse)]
+async fn test_diff_schema_generates_alter_statements() {
+    let migrator = require_clickhouse!();
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let src = temp_dir.path().to_str().unwrap();
+    let schema_dir = tempfile::tempdir().unwrap();
+    let schema = schema_dir.path().to_str().unwrap();
+
+    tokio::fs::write(
+        format!("{}/0001_create_users.sql", src),
+        b"CREATE TABLE users (id UInt64, legacy String) ENGINE = MergeTree ORDER BY id",
+    )
+    .await
+    .unwrap();
+    tokio::fs::write(
+        format!("{}/users.sql", schema),
+        b"CREATE TABLE users (id UInt64, email String) ENGINE = MergeTree ORDER BY id",
+    )
+    .await
+    .unwrap();
+
+    let changes = migrator
+        .diff_schema(src, schema, migration::DiffBase::Migrations)
+        .await
+        .unwrap();
+    assert_eq!(
+        changes.up,
+        vec![
+            "ALTER TABLE `users` ADD COLUMN `email` String AFTER `id`",
+            "ALTER TABLE `users` DROP COLUMN `legacy`",
+        ]
+    );
+
+    let files = changes
+        .write_migration(src, "replace_legacy")
+        .await
+        .unwrap();
+    assert!(files[0].ends_with("0002_replace_legacy.up.sql"));
+}
+
+#[tokio::test]
+#[serial(clickhouse)]
+async fn test_validate_reports_every_syntax_error() {
+    let migrator = require_clickhouse!();
+    migrator.ensure_migrations_table().await.unwrap();
+    clear_migrations_table().await;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let src = temp_dir.path().to_str().unwrap();
+
+    tokio::fs::write(
+        format!("{}/0001_broken.sql", src),
+        b"-- broken\nCREATE TABLEE users;\nSELECT 2;\nSELEC 3",
+    )
+    .await
+    .unwrap();
+
+    let errors = migrator.validate(src, false).await.unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].version, 1);
+    assert_eq!(errors[0].line, 2);
+    assert_eq!(errors[1].line, 4);
+    assert!(errors[0].message.contains("Code: 62"));
+}